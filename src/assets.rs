@@ -0,0 +1,136 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use image;
+use image::hdr::HDRDecoder;
+use na::{Point2, Point3, Vector3};
+use tobj;
+
+use math::Scalar;
+use mesh::TriangleMesh;
+use spectrum::Spectrum;
+
+/// Load every model in a Wavefront .obj file as a separate
+/// triangle mesh, paired with the name the model was given
+/// in the file. Models without any triangles are skipped.
+pub fn load_obj(filename: &Path) -> Result<Vec<(String, TriangleMesh)>, tobj::LoadError> {
+    let (models, _materials) = try!(tobj::load_obj(filename));
+    let mut meshes = Vec::new();
+
+    for model in models {
+        let mesh = &model.mesh;
+        if mesh.indices.len() < 3 {
+            continue;
+        }
+
+        let indices: Vec<[usize; 3]> = (0..mesh.indices.len() / 3)
+            .map(|i| {
+                [mesh.indices[i * 3] as usize,
+                 mesh.indices[i * 3 + 1] as usize,
+                 mesh.indices[i * 3 + 2] as usize]
+            })
+            .collect();
+
+        let vertices: Vec<Point3<Scalar>> = (0..mesh.positions.len() / 3)
+            .map(|v| {
+                Point3::new(mesh.positions[v * 3] as Scalar,
+                            mesh.positions[v * 3 + 1] as Scalar,
+                            mesh.positions[v * 3 + 2] as Scalar)
+            })
+            .collect();
+
+        let uvs: Vec<Point2<Scalar>> = (0..mesh.texcoords.len() / 2)
+            .map(|t| {
+                Point2::new(mesh.texcoords[t * 2] as Scalar,
+                            mesh.texcoords[t * 2 + 1] as Scalar)
+            })
+            .collect();
+
+        // normals are interpolated across each triangle when the
+        // mesh is intersected so only keep them if the file provides
+        // one for every vertex, otherwise the flat triangle normal
+        // is used
+        let normals = if mesh.normals.len() != mesh.positions.len() {
+            None
+        } else {
+            Some((0..mesh.normals.len() / 3)
+                .map(|n| {
+                    Vector3::new(mesh.normals[n * 3] as Scalar,
+                                 mesh.normals[n * 3 + 1] as Scalar,
+                                 mesh.normals[n * 3 + 2] as Scalar)
+                })
+                .collect())
+        };
+
+        let uvs = if uvs.len() != vertices.len() { None } else { Some(uvs) };
+
+        meshes.push((model.name.clone(), TriangleMesh::new(vertices, indices, normals, uvs)))
+    }
+    Ok(meshes)
+}
+
+/// Load an image as linear radiance values returning its
+/// width, height and pixels in row-major order.
+///
+/// Radiance .hdr files keep their full range, any other
+/// image format is mapped into [0, 1].
+pub fn load_radiance_image(filename: &Path) -> image::ImageResult<(u32, u32, Vec<Spectrum>)> {
+    let is_hdr = filename.extension()
+        .and_then(|ext| ext.to_str())
+        .map_or(false, |ext| ext.eq_ignore_ascii_case("hdr"));
+
+    if is_hdr {
+        let f = try!(File::open(filename));
+        let decoder = try!(HDRDecoder::new(BufReader::new(f)));
+        let metadata = decoder.metadata();
+        let pixels = try!(decoder.read_image_hdr());
+        let pixels = pixels.iter()
+            .map(|p| Spectrum::new(p[0] as Scalar, p[1] as Scalar, p[2] as Scalar))
+            .collect();
+        Ok((metadata.width, metadata.height, pixels))
+    } else {
+        let img = try!(image::open(filename)).to_rgb();
+        let (width, height) = img.dimensions();
+        let pixels = img.pixels()
+            .map(|p| {
+                Spectrum::new(p[0] as Scalar / 255.0,
+                              p[1] as Scalar / 255.0,
+                              p[2] as Scalar / 255.0)
+            })
+            .collect();
+        Ok((width, height, pixels))
+    }
+}
+
+/// Combine several meshes into a single mesh.
+///
+/// Texture coordinates and normals are only kept
+/// if every one of the meshes has them, there must
+/// be at least one mesh.
+pub fn merge_meshes(meshes: &[TriangleMesh]) -> TriangleMesh {
+    let mut vertices = Vec::new();
+    let mut indices = Vec::new();
+    let mut uvs = Vec::new();
+    let mut normals = Vec::new();
+    let has_uvs = meshes.iter().all(|m| m.uvs().is_some());
+    let has_normals = meshes.iter().all(|m| m.normals().is_some());
+
+    for mesh in meshes {
+        let offset = vertices.len();
+        vertices.extend(mesh.vertices().iter().cloned());
+        indices.extend(mesh.indices()
+            .iter()
+            .map(|idx| [idx[0] + offset, idx[1] + offset, idx[2] + offset]));
+        if has_uvs {
+            uvs.extend(mesh.uvs().unwrap().iter().cloned());
+        }
+        if has_normals {
+            normals.extend(mesh.normals().unwrap().iter().cloned());
+        }
+    }
+
+    let uvs = if has_uvs { Some(uvs) } else { None };
+    let normals = if has_normals { Some(normals) } else { None };
+    TriangleMesh::new(vertices, indices, normals, uvs)
+}
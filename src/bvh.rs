@@ -0,0 +1,363 @@
+use na;
+use ncollide::bounding_volume::{AABB3, BoundingVolume};
+use ncollide::query::Ray3;
+
+use math::{Point, Scalar, Vector};
+
+/// The number of buckets item centroids are sorted
+/// into when looking for the best split of a node.
+const SAH_BUCKETS: usize = 12;
+
+/// Nodes with this many items or fewer are never split.
+const MAX_LEAF_ITEMS: usize = 4;
+
+/// The cost of visiting a node relative
+/// to the cost of intersecting an item.
+const TRAVERSAL_COST: Scalar = 0.125;
+
+/// A bounding volume hierarchy over items with bounding boxes.
+///
+/// The tree is built using the surface area heuristic and stored
+/// flattened in depth first order, so the first child of a node
+/// always follows it and only the second child needs an index.
+pub struct BVH<T> {
+    items: Vec<T>,
+    nodes: Vec<Node>,
+}
+
+#[derive(Clone, Debug)]
+struct Node {
+    bounds: AABB3<Scalar>,
+    /// The index of the first item of a leaf or the
+    /// index of the second child of an interior node.
+    offset: usize,
+    /// The number of items in a leaf, zero for an interior node.
+    count: usize,
+    /// The axis an interior node's children were split along.
+    axis: usize,
+}
+
+struct BuildItem {
+    index: usize,
+    bounds: AABB3<Scalar>,
+    centroid: Point,
+}
+
+impl<T> BVH<T> {
+    pub fn new(items: Vec<(T, AABB3<Scalar>)>) -> BVH<T> {
+        let mut build: Vec<BuildItem> = items.iter()
+            .enumerate()
+            .map(|(index, &(_, ref bounds))| {
+                BuildItem {
+                    index: index,
+                    bounds: bounds.clone(),
+                    centroid: centre(bounds),
+                }
+            })
+            .collect();
+
+        let mut nodes = Vec::new();
+        let mut order = Vec::with_capacity(items.len());
+        if !build.is_empty() {
+            build_node(&mut build[..], &mut nodes, &mut order);
+        }
+
+        // store the items in the order the leaves reference them
+        let mut items: Vec<Option<T>> = items.into_iter().map(|(item, _)| Some(item)).collect();
+        let items = order.iter().map(|&index| items[index].take().unwrap()).collect();
+        BVH {
+            items: items,
+            nodes: nodes,
+        }
+    }
+
+    /// Find the closest item hit by the ray before t_max.
+    ///
+    /// Nodes are visited front to back and the intersect function
+    /// is given each item whose bounds the ray hits along with the
+    /// distance to the closest hit so far, returning the distance
+    /// and details of the hit if it hit the item any closer.
+    pub fn closest_hit<'a, H, F>(&'a self, ray: &Ray3<Scalar>, t_max: Scalar, mut intersect: F)
+                                 -> Option<H>
+        where F: FnMut(&'a T, Scalar) -> Option<(Scalar, H)>
+    {
+        let mut nearest = None;
+        self.traverse(ray, t_max, |item, t_max| {
+            if let Some((toi, hit)) = intersect(item, *t_max) {
+                if toi < *t_max {
+                    *t_max = toi;
+                    nearest = Some(hit);
+                }
+            }
+            false
+        });
+        nearest
+    }
+
+    /// Does the ray hit any item before t_max? Stops as soon
+    /// as the intersect function finds an item that is hit.
+    pub fn any_hit<'a, F>(&'a self, ray: &Ray3<Scalar>, t_max: Scalar, mut intersect: F) -> bool
+        where F: FnMut(&'a T, Scalar) -> bool
+    {
+        self.traverse(ray, t_max, |item, t_max| intersect(item, *t_max))
+    }
+
+    /// Visit the items in the leaves the ray passes through before
+    /// t_max, which the visitor may shrink, until it returns true.
+    fn traverse<'a, F>(&'a self, ray: &Ray3<Scalar>, t_max: Scalar, mut visit: F) -> bool
+        where F: FnMut(&'a T, &mut Scalar) -> bool
+    {
+        if self.nodes.is_empty() {
+            return false;
+        }
+        let inv_dir = Vector::new(1.0 / ray.dir.x, 1.0 / ray.dir.y, 1.0 / ray.dir.z);
+        let dir_is_neg = [inv_dir.x < 0.0, inv_dir.y < 0.0, inv_dir.z < 0.0];
+
+        let mut t_max = t_max;
+        let mut stack = Vec::with_capacity(64);
+        let mut current = 0;
+        loop {
+            let node = &self.nodes[current];
+            if hits_aabb(&node.bounds, &ray.origin, &inv_dir, t_max) {
+                if node.count > 0 {
+                    for item in &self.items[node.offset..node.offset + node.count] {
+                        if visit(item, &mut t_max) {
+                            return true;
+                        }
+                    }
+                } else if dir_is_neg[node.axis] {
+                    // visit the child nearest the ray's
+                    // origin first so that later nodes
+                    // can be culled by a closer t_max
+                    stack.push(current + 1);
+                    current = node.offset;
+                    continue;
+                } else {
+                    stack.push(node.offset);
+                    current = current + 1;
+                    continue;
+                }
+            }
+            match stack.pop() {
+                Some(next) => current = next,
+                None => return false,
+            }
+        }
+    }
+}
+
+/// Build the node over the items and its children, returning
+/// its index. The indices of the items are added to order as
+/// they are placed in leaves.
+fn build_node(items: &mut [BuildItem], nodes: &mut Vec<Node>, order: &mut Vec<usize>) -> usize {
+    let mut bounds = items[0].bounds.clone();
+    for item in &items[1..] {
+        bounds.merge(&item.bounds);
+    }
+    let index = nodes.len();
+    nodes.push(Node {
+        bounds: bounds.clone(),
+        offset: order.len(),
+        count: items.len(),
+        axis: 0,
+    });
+
+    if items.len() <= MAX_LEAF_ITEMS {
+        order.extend(items.iter().map(|item| item.index));
+        return index;
+    }
+
+    // split along the axis the centroids are most spread out on
+    let mut centroid_mins = items[0].centroid;
+    let mut centroid_maxs = items[0].centroid;
+    for item in &items[1..] {
+        let c = &item.centroid;
+        centroid_mins = Point::new(centroid_mins.x.min(c.x),
+                                   centroid_mins.y.min(c.y),
+                                   centroid_mins.z.min(c.z));
+        centroid_maxs = Point::new(centroid_maxs.x.max(c.x),
+                                   centroid_maxs.y.max(c.y),
+                                   centroid_maxs.z.max(c.z));
+    }
+    let extent = centroid_maxs - centroid_mins;
+    let axis = if extent.x > extent.y && extent.x > extent.z {
+        0
+    } else if extent.y > extent.z {
+        1
+    } else {
+        2
+    };
+    if extent[axis] == 0.0 {
+        // every centroid is in the same place
+        order.extend(items.iter().map(|item| item.index));
+        return index;
+    }
+
+    let bucket = |item: &BuildItem| {
+        let b = ((item.centroid[axis] - centroid_mins[axis]) / extent[axis] *
+                 SAH_BUCKETS as Scalar) as usize;
+        usize::min(b, SAH_BUCKETS - 1)
+    };
+    let mut counts = [0; SAH_BUCKETS];
+    let mut bucket_bounds: Vec<Option<AABB3<Scalar>>> = vec![None; SAH_BUCKETS];
+    for item in items.iter() {
+        let b = bucket(item);
+        counts[b] += 1;
+        bucket_bounds[b] = Some(match bucket_bounds[b].take() {
+            Some(bounds) => bounds.merged(&item.bounds),
+            None => item.bounds.clone(),
+        });
+    }
+
+    // find the split between buckets with the lowest expected cost,
+    // costs are scaled by the surface area of the node's bounds
+    let mut best_split = 0;
+    let mut best_cost = ::std::f64::INFINITY;
+    for split in 0..SAH_BUCKETS - 1 {
+        let (below, above) = bucket_bounds.split_at(split + 1);
+        let (count_below, count_above) = counts.split_at(split + 1);
+        let cost = TRAVERSAL_COST * surface_area(&bounds) +
+                   side_cost(below, count_below) + side_cost(above, count_above);
+        if cost < best_cost {
+            best_cost = cost;
+            best_split = split;
+        }
+    }
+    let leaf_cost = items.len() as Scalar * surface_area(&bounds);
+    if best_cost >= leaf_cost {
+        order.extend(items.iter().map(|item| item.index));
+        return index;
+    }
+
+    let mut mid = 0;
+    for i in 0..items.len() {
+        if bucket(&items[i]) <= best_split {
+            items.swap(i, mid);
+            mid += 1;
+        }
+    }
+
+    let (below, above) = items.split_at_mut(mid);
+    build_node(below, nodes, order);
+    let second = build_node(above, nodes, order);
+    nodes[index].offset = second;
+    nodes[index].count = 0;
+    nodes[index].axis = axis;
+    index
+}
+
+/// The cost of intersecting the items in the buckets
+/// on one side of a split, scaled by surface area.
+fn side_cost(bounds: &[Option<AABB3<Scalar>>], counts: &[usize]) -> Scalar {
+    let mut merged: Option<AABB3<Scalar>> = None;
+    for b in bounds.iter().filter_map(|b| b.as_ref()) {
+        merged = Some(match merged {
+            Some(merged) => merged.merged(b),
+            None => b.clone(),
+        });
+    }
+    let count: usize = counts.iter().sum();
+    match merged {
+        Some(merged) => count as Scalar * surface_area(&merged),
+        None => 0.0,
+    }
+}
+
+#[inline]
+fn centre(aabb: &AABB3<Scalar>) -> Point {
+    Point::from_coordinates((aabb.mins().coords + aabb.maxs().coords) * 0.5)
+}
+
+#[inline]
+fn surface_area(aabb: &AABB3<Scalar>) -> Scalar {
+    let d = *aabb.maxs() - *aabb.mins();
+    2.0 * (d.x * d.y + d.y * d.z + d.z * d.x)
+}
+
+/// Does a ray hit the box between its origin and t_max?
+#[inline]
+fn hits_aabb(aabb: &AABB3<Scalar>, origin: &Point, inv_dir: &Vector, t_max: Scalar) -> bool {
+    let mut t0 = 0.0;
+    let mut t1 = t_max;
+    for i in 0..3 {
+        let mut near = (aabb.mins()[i] - origin[i]) * inv_dir[i];
+        let mut far = (aabb.maxs()[i] - origin[i]) * inv_dir[i];
+        if near > far {
+            ::std::mem::swap(&mut near, &mut far);
+        }
+        // widen the slab slightly so rounding errors don't
+        // miss items lying flat against the box's faces,
+        // a NaN from a ray in the plane of a face is ignored
+        far = far * (1.0 + 1e-9);
+        if near > t0 {
+            t0 = near;
+        }
+        if far < t1 {
+            t1 = far;
+        }
+        if t0 > t1 {
+            return false;
+        }
+    }
+    true
+}
+
+#[test]
+fn test_closest_hit_matches_brute_force() {
+    // a grid of balls with rays fired through it from the
+    // side, every ray must find the same ball as testing
+    // each ball in turn
+    let mut balls = Vec::new();
+    for x in 0..6 {
+        for y in 0..6 {
+            for z in 0..6 {
+                let centre = Point::new(x as Scalar * 3.0, y as Scalar * 2.5, z as Scalar * 2.0);
+                let radius = 0.5 + ((x + y + z) % 3) as Scalar * 0.25;
+                balls.push((centre, radius));
+            }
+        }
+    }
+    let ball_toi = |&(centre, radius): &(Point, Scalar), ray: &Ray3<Scalar>| {
+        let oc = ray.origin - centre;
+        let b = na::dot(&oc, &ray.dir);
+        let c = na::dot(&oc, &oc) - radius * radius;
+        let disc = b * b - c;
+        if disc < 0.0 {
+            return None;
+        }
+        let t = -b - disc.sqrt();
+        if t > 0.0 { Some(t) } else { None }
+    };
+
+    let items = balls.iter()
+        .enumerate()
+        .map(|(i, &(centre, radius))| {
+            let r = Vector::new(radius, radius, radius);
+            (i, AABB3::new(centre - r, centre + r))
+        })
+        .collect();
+    let bvh = BVH::new(items);
+
+    for i in 0..50 {
+        let origin = Point::new(-5.0, i as Scalar * 0.3, i as Scalar * 0.2);
+        let dir = na::normalize(&Vector::new(1.0, (i % 7) as Scalar * 0.05, -0.1));
+        let ray = Ray3::new(origin, dir);
+
+        let expected = balls.iter()
+            .enumerate()
+            .filter_map(|(i, ball)| ball_toi(ball, &ray).map(|t| (t, i)))
+            .fold(None, |nearest: Option<(Scalar, usize)>, (t, i)| match nearest {
+                Some((nt, _)) if nt <= t => nearest,
+                _ => Some((t, i)),
+            })
+            .map(|(_, i)| i);
+        let found = bvh.closest_hit(&ray, ::std::f64::INFINITY, |&i, _| {
+            ball_toi(&balls[i], &ray).map(|t| (t, i))
+        });
+        assert_eq!(found, expected);
+        assert_eq!(bvh.any_hit(&ray, ::std::f64::INFINITY, |&i, _| {
+                       ball_toi(&balls[i], &ray).is_some()
+                   }),
+                   expected.is_some());
+    }
+}
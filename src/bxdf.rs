@@ -8,8 +8,10 @@ use na;
 use na::{Matrix3, Rotation3, Transform};
 
 use math::{Clamp, Scalar, Vector};
+use microfacet::MicrofacetDistribution;
 use montecarlo::cosine_sample_hemisphere;
-use rand::Rng;
+use sampler::Sampler;
+use surface::SurfaceGeometry;
 
 pub type Pdf = Scalar;
 
@@ -35,22 +37,59 @@ bitflags! {
 /// Return Cos Theta for a normalized vector
 /// in normal space.
 #[inline]
-fn cos_theta(v: &Vector) -> Scalar {
+pub fn cos_theta(v: &Vector) -> Scalar {
     v.z
 }
 
 #[inline]
-fn sin_theta2(v: &Vector) -> Scalar {
+pub fn cos_theta2(v: &Vector) -> Scalar {
+    v.z * v.z
+}
+
+#[inline]
+pub fn sin_theta2(v: &Vector) -> Scalar {
     Scalar::max(0.0, 1.0 - cos_theta(v) * cos_theta(v))
 }
 
 #[inline]
-fn sin_theta(v: &Vector) -> Scalar {
+pub fn sin_theta(v: &Vector) -> Scalar {
     sin_theta2(v).sqrt()
 }
 
 #[inline]
-fn same_hemisphere(w: &Vector, wp: &Vector) -> bool {
+pub fn tan_theta(v: &Vector) -> Scalar {
+    sin_theta(v) / cos_theta(v)
+}
+
+#[inline]
+pub fn tan_theta2(v: &Vector) -> Scalar {
+    sin_theta2(v) / cos_theta2(v)
+}
+
+/// Return Cos Phi, the cosine of the angle of a vector
+/// in normal space around the normal from the tangent.
+#[inline]
+pub fn cos_phi(v: &Vector) -> Scalar {
+    let sin_theta = sin_theta(v);
+    if sin_theta == 0.0 {
+        1.0
+    } else {
+        (v.x / sin_theta).clamp(-1.0, 1.0)
+    }
+}
+
+#[inline]
+pub fn sin_phi(v: &Vector) -> Scalar {
+    let sin_theta = sin_theta(v);
+    if sin_theta == 0.0 {
+        0.0
+    } else {
+        (v.y / sin_theta).clamp(-1.0, 1.0)
+    }
+}
+
+#[inline]
+pub fn same_hemisphere(w: &Vector, wp: &Vector) -> bool {
     w.z * wp.z > 0.0
 }
 
@@ -109,6 +148,53 @@ impl BxDF for Lambertian {
     }
 }
 
+/// A BRDF for rough diffuse surfaces such as clay or
+/// concrete, made of Lambertian microfacets whose angles
+/// have a Gaussian distribution, which reflect more light
+/// back towards the light than a Lambertian surface.
+pub struct OrenNayar {
+    colour: Spectrum,
+    a: Scalar,
+    b: Scalar,
+}
+
+impl OrenNayar {
+    /// Create the BRDF where sigma is the standard deviation
+    /// of the angle of the facets in radians.
+    pub fn new(colour: Spectrum, sigma: Scalar) -> OrenNayar {
+        let sigma2 = sigma * sigma;
+        OrenNayar {
+            colour: colour,
+            a: 1.0 - sigma2 / (2.0 * (sigma2 + 0.33)),
+            b: 0.45 * sigma2 / (sigma2 + 0.09),
+        }
+    }
+}
+
+impl BxDF for OrenNayar {
+    fn f(&self, wo: &Vector, wi: &Vector) -> Spectrum {
+        let sin_theta_i = sin_theta(wi);
+        let sin_theta_o = sin_theta(wo);
+        // cosine of the difference in azimuth
+        let max_cos = if sin_theta_i > 1e-4 && sin_theta_o > 1e-4 {
+            Scalar::max(0.0, cos_phi(wi) * cos_phi(wo) + sin_phi(wi) * sin_phi(wo))
+        } else {
+            0.0
+        };
+        let (sin_alpha, tan_beta) = if cos_theta(wi).abs() > cos_theta(wo).abs() {
+            (sin_theta_o, sin_theta_i / cos_theta(wi).abs())
+        } else {
+            (sin_theta_i, sin_theta_o / cos_theta(wo).abs())
+        };
+        self.colour * (consts::FRAC_1_PI * (self.a + self.b * max_cos * sin_alpha * tan_beta))
+    }
+
+    #[inline]
+    fn bxdf_type(&self) -> BxDFType {
+        BSDF_DIFFUSE | BSDF_REFLECTION
+    }
+}
+
 /// A structure representing a Bidirectional Reflectance
 /// Distribution Function for Specular Reflection.
 /// This models the amount of incident
@@ -205,9 +291,9 @@ impl BxDF for SpecularTransmission {
         }
 
         let cost = if entering {
-            -Scalar::max(0.0, 1.0 - sint2)
+            -Scalar::max(0.0, 1.0 - sint2).sqrt()
         } else {
-            Scalar::max(0.0, 1.0 - sint2)
+            Scalar::max(0.0, 1.0 - sint2).sqrt()
         };
 
         let sint_over_sini = eta;
@@ -231,27 +317,359 @@ impl BxDF for SpecularTransmission {
     }
 }
 
+/// A BRDF for glossy reflection from a rough surface made
+/// of microscopic mirrors (microfacets) with normals following
+/// a distribution, each reflecting by the Fresnel equations.
+pub struct MicrofacetReflection {
+    r: Spectrum,
+    distribution: Box<MicrofacetDistribution>,
+    fresnel: Box<Fresnel>,
+}
+
+impl MicrofacetReflection {
+    pub fn new<D: 'static + MicrofacetDistribution, F: 'static + Fresnel>
+        (r: Spectrum,
+         distribution: Box<D>,
+         fresnel: Box<F>)
+         -> MicrofacetReflection {
+        MicrofacetReflection {
+            r: r,
+            distribution: distribution as Box<MicrofacetDistribution>,
+            fresnel: fresnel as Box<Fresnel>,
+        }
+    }
+}
+
+impl BxDF for MicrofacetReflection {
+    fn pdf(&self, wo: &Vector, wi: &Vector) -> Pdf {
+        if !same_hemisphere(wo, wi) {
+            return 0.0;
+        }
+        let wh = *wo + *wi;
+        if wh == na::zero() {
+            return 0.0;
+        }
+        let wh = na::normalize(&wh);
+        self.distribution.pdf(wo, &wh) / (4.0 * na::dot(wo, &wh).abs())
+    }
+
+    /// Sample the facet normal from those visible
+    /// from wo and reflect wo about it.
+    fn sample_f(&self, wo: &Vector, u1: Scalar, u2: Scalar) -> (Spectrum, Vector, Pdf) {
+        if wo.z == 0.0 {
+            return (na::zero(), na::zero(), 0.0);
+        }
+        let wh = self.distribution.sample_wh(wo, u1, u2);
+        if na::dot(wo, &wh) < 0.0 {
+            return (na::zero(), na::zero(), 0.0);
+        }
+        let wi = -*wo + wh * (2.0 * na::dot(wo, &wh));
+        if !same_hemisphere(wo, &wi) {
+            return (na::zero(), na::zero(), 0.0);
+        }
+        (self.f(wo, &wi), wi, self.pdf(wo, &wi))
+    }
+
+    fn f(&self, wo: &Vector, wi: &Vector) -> Spectrum {
+        let cos_o = cos_theta(wo).abs();
+        let cos_i = cos_theta(wi).abs();
+        let wh = *wo + *wi;
+        if cos_o == 0.0 || cos_i == 0.0 || wh == na::zero() {
+            return na::zero();
+        }
+        let wh = na::normalize(&wh);
+        // evaluate the Fresnel term from the side of the
+        // surface the directions are on
+        let wh_outside = if wh.z < 0.0 { -wh } else { wh };
+        let f = self.fresnel.evaluate(na::dot(wi, &wh_outside));
+        let d = self.distribution.d(&wh);
+        let g = self.distribution.g(wo, wi);
+        self.r.component_mul(&f) * (d * g / (4.0 * cos_i * cos_o))
+    }
+
+    #[inline]
+    fn bxdf_type(&self) -> BxDFType {
+        BSDF_REFLECTION | BSDF_GLOSSY
+    }
+}
+
+/// A BTDF for glossy transmission through a rough dielectric
+/// surface, such as frosted glass, made of microfacets that
+/// each refract by Snell's law.
+///
+/// Like `SpecularTransmission` the radiance isn't scaled by
+/// the change in solid angle as light crosses the boundary.
+pub struct MicrofacetTransmission {
+    t: Spectrum,
+    distribution: Box<MicrofacetDistribution>,
+    etai: Scalar,
+    etat: Scalar,
+    fresnel: FresnelDielectric,
+}
+
+impl MicrofacetTransmission {
+    pub fn new<D: 'static + MicrofacetDistribution>(t: Spectrum,
+                                                    distribution: Box<D>,
+                                                    etai: Scalar,
+                                                    etat: Scalar)
+                                                    -> MicrofacetTransmission {
+        MicrofacetTransmission {
+            t: t,
+            distribution: distribution as Box<MicrofacetDistribution>,
+            etai: etai,
+            etat: etat,
+            fresnel: FresnelDielectric::new(etai, etat),
+        }
+    }
+
+    /// The ratio of the refractive index on the side of the
+    /// surface wi is on to the index on the side of wo.
+    #[inline]
+    fn eta(&self, wo: &Vector) -> Scalar {
+        if cos_theta(wo) > 0.0 {
+            self.etat / self.etai
+        } else {
+            self.etai / self.etat
+        }
+    }
+
+    /// The facet normal that refracts wo into wi, facing out
+    /// of the surface, and the factor relating the change in
+    /// wi to the change in the normal.
+    fn half_vector(&self, wo: &Vector, wi: &Vector) -> Option<(Vector, Scalar)> {
+        let eta = self.eta(wo);
+        let wh = *wo + *wi * eta;
+        if wh == na::zero() {
+            return None;
+        }
+        let wh = na::normalize(&wh);
+        let wh = if wh.z < 0.0 { -wh } else { wh };
+        // both directions must be on opposite sides of the facet
+        if na::dot(wo, &wh) * na::dot(wi, &wh) > 0.0 {
+            return None;
+        }
+        let denom = na::dot(wo, &wh) + eta * na::dot(wi, &wh);
+        Some((wh, eta * eta / (denom * denom)))
+    }
+}
+
+impl BxDF for MicrofacetTransmission {
+    fn pdf(&self, wo: &Vector, wi: &Vector) -> Pdf {
+        if same_hemisphere(wo, wi) {
+            return 0.0;
+        }
+        match self.half_vector(wo, wi) {
+            Some((wh, jacobian)) => {
+                self.distribution.pdf(wo, &wh) * na::dot(wi, &wh).abs() * jacobian
+            }
+            None => 0.0,
+        }
+    }
+
+    /// Sample the facet normal from those visible
+    /// from wo and refract wo through it.
+    fn sample_f(&self, wo: &Vector, u1: Scalar, u2: Scalar) -> (Spectrum, Vector, Pdf) {
+        if wo.z == 0.0 {
+            return (na::zero(), na::zero(), 0.0);
+        }
+        let wh = self.distribution.sample_wh(wo, u1, u2);
+        if na::dot(wo, &wh) < 0.0 {
+            return (na::zero(), na::zero(), 0.0);
+        }
+        match math::refract(wo, &wh, 1.0 / self.eta(wo)) {
+            Some(wi) => (self.f(wo, &wi), wi, self.pdf(wo, &wi)),
+            // total internal reflection
+            None => (na::zero(), na::zero(), 0.0),
+        }
+    }
+
+    fn f(&self, wo: &Vector, wi: &Vector) -> Spectrum {
+        if same_hemisphere(wo, wi) {
+            return na::zero();
+        }
+        let cos_o = cos_theta(wo);
+        let cos_i = cos_theta(wi);
+        if cos_o == 0.0 || cos_i == 0.0 {
+            return na::zero();
+        }
+        let (wh, jacobian) = match self.half_vector(wo, wi) {
+            Some(half_vector) => half_vector,
+            None => return na::zero(),
+        };
+        let f = self.fresnel.evaluate(na::dot(wo, &wh));
+        let d = self.distribution.d(&wh);
+        let g = self.distribution.g(wo, wi);
+        (Vector::new(1.0, 1.0, 1.0) - f).component_mul(&self.t) *
+        (d * g * jacobian * na::dot(wi, &wh).abs() * na::dot(wo, &wh).abs() /
+         (cos_i * cos_o))
+            .abs()
+    }
+
+    #[inline]
+    fn bxdf_type(&self) -> BxDFType {
+        BSDF_TRANSMISSION | BSDF_GLOSSY
+    }
+}
+
+/// The Fresnel reflectance of a thin dielectric slab, such
+/// as a window pane, including light reflected back and forth
+/// any number of times between its two surfaces.
+pub struct FresnelThinDielectric {
+    fresnel: FresnelDielectric,
+}
+
+impl FresnelThinDielectric {
+    pub fn new(etai: Scalar, etat: Scalar) -> FresnelThinDielectric {
+        FresnelThinDielectric { fresnel: FresnelDielectric::new(etai, etat) }
+    }
+}
+
+impl Fresnel for FresnelThinDielectric {
+    fn evaluate(&self, cosi: Scalar) -> Spectrum {
+        // light enters the slab from the same side whichever
+        // face it hits as both surfaces are parallel
+        let r = self.fresnel.evaluate(cosi.abs());
+        let one = Spectrum::new(1.0, 1.0, 1.0);
+        if r == one {
+            // total internal reflection
+            return r;
+        }
+        let t = one - r;
+        r + t.component_mul(&t).component_mul(&r).component_div(&(one - r.component_mul(&r)))
+    }
+}
+
+/// A BTDF for transmission through a thin dielectric slab,
+/// where the light is refracted into the slab and straight
+/// back out so it passes through without changing direction.
+pub struct ThinSpecularTransmission {
+    t: Spectrum,
+    fresnel: FresnelThinDielectric,
+}
+
+impl ThinSpecularTransmission {
+    pub fn new(t: Spectrum, etai: Scalar, etat: Scalar) -> ThinSpecularTransmission {
+        ThinSpecularTransmission {
+            t: t,
+            fresnel: FresnelThinDielectric::new(etai, etat),
+        }
+    }
+}
+
+impl BxDF for ThinSpecularTransmission {
+    #[inline]
+    fn pdf(&self, _: &Vector, _: &Vector) -> Pdf {
+        0.0
+    }
+
+    fn sample_f(&self, wo: &Vector, _: Scalar, _: Scalar) -> (Spectrum, Vector, Pdf) {
+        let wi = -*wo;
+        let f = self.fresnel.evaluate(cos_theta(wo));
+        let transmitted = (Vector::new(1.0, 1.0, 1.0) - f)
+            .component_mul(&(self.t / cos_theta(&wi).abs()));
+        (transmitted, wi, 1.0)
+    }
+
+    /// Specular transmission only produces light in a single direction
+    /// given by the sample_f method.
+    #[inline]
+    fn f(&self, _: &Vector, _: &Vector) -> Spectrum {
+        na::zero()
+    }
+
+    #[inline]
+    fn bxdf_type(&self) -> BxDFType {
+        BSDF_TRANSMISSION | BSDF_SPECULAR
+    }
+}
+
+/// A BxDF seen through a smooth dielectric coating, such as the
+/// varnish on wood or the clear coat on car paint, which passes
+/// on the light the coating doesn't reflect on the way in and
+/// on the way out.
+///
+/// Light reflected back and forth between the coating and the
+/// base, and the refraction of directions through the coating,
+/// are ignored.
+pub struct Coated {
+    bxdf: Box<BxDF>,
+    fresnel: FresnelDielectric,
+}
+
+impl Coated {
+    pub fn new(bxdf: Box<BxDF>, etai: Scalar, etat: Scalar) -> Coated {
+        Coated {
+            bxdf: bxdf,
+            fresnel: FresnelDielectric::new(etai, etat),
+        }
+    }
+
+    /// The fraction of light passing through the coating
+    /// towards the base along wi and back out along wo.
+    fn transmittance(&self, wo: &Vector, wi: &Vector) -> Spectrum {
+        let one = Spectrum::new(1.0, 1.0, 1.0);
+        (one - self.fresnel.evaluate(cos_theta(wo)))
+            .component_mul(&(one - self.fresnel.evaluate(cos_theta(wi))))
+    }
+}
+
+impl BxDF for Coated {
+    #[inline]
+    fn pdf(&self, wo: &Vector, wi: &Vector) -> Pdf {
+        self.bxdf.pdf(wo, wi)
+    }
+
+    fn sample_f(&self, wo: &Vector, u1: Scalar, u2: Scalar) -> (Spectrum, Vector, Pdf) {
+        let (f, wi, pdf) = self.bxdf.sample_f(wo, u1, u2);
+        (f.component_mul(&self.transmittance(wo, &wi)), wi, pdf)
+    }
+
+    fn f(&self, wo: &Vector, wi: &Vector) -> Spectrum {
+        self.bxdf.f(wo, wi).component_mul(&self.transmittance(wo, wi))
+    }
+
+    #[inline]
+    fn bxdf_type(&self) -> BxDFType {
+        self.bxdf.bxdf_type()
+    }
+}
+
 pub struct BSDF {
+    /// The true normal of the surface, which decides whether
+    /// directions are reflected or transmitted.
     normal: Vector,
+    /// Rotates into the shading frame, with the shading
+    /// normal along Z and dp/du projected onto X.
     world_to_local: Rotation3<Scalar>,
     bxdfs: Vec<Box<BxDF>>,
+    /// Scales all light scattered, such as by the
+    /// absorption of the volume it crossed to get here.
+    scale: Spectrum,
 }
 
 impl BSDF {
-    pub fn new(normal: Vector) -> BSDF {
-        Self::new_with_bxdfs(normal, Vec::new())
+    pub fn new(geometry: &SurfaceGeometry) -> BSDF {
+        Self::new_with_bxdfs(geometry, Vec::new())
     }
 
-    pub fn new_with_bxdfs(normal: Vector, bxdfs: Vec<Box<BxDF>>) -> BSDF {
+    pub fn new_with_bxdfs(geometry: &SurfaceGeometry, bxdfs: Vec<Box<BxDF>>) -> BSDF {
         BSDF {
-            normal: normal,
-            world_to_local: BSDF::world_to_local_from_normal(&normal),
+            normal: geometry.normal,
+            world_to_local: BSDF::world_to_local_from_shading(&geometry.shading_normal,
+                                                              &geometry.dpdu),
             bxdfs: bxdfs,
+            scale: Spectrum::new(1.0, 1.0, 1.0),
         }
     }
 
-    fn world_to_local_from_normal(normal: &Vector) -> Rotation3<Scalar> {
-        let (tangent, binormal) = math::coordinate_system(normal);
+    fn world_to_local_from_shading(normal: &Vector, dpdu: &Vector) -> Rotation3<Scalar> {
+        let tangent = *dpdu - *normal * na::dot(dpdu, normal);
+        let (tangent, binormal) = if na::norm_squared(&tangent) == 0.0 {
+            math::coordinate_system(normal)
+        } else {
+            let tangent = na::normalize(&tangent);
+            (tangent, normal.cross(&tangent))
+        };
         Rotation3::from_matrix_unchecked(Matrix3::new(tangent.x,
                                                       tangent.y,
                                                       tangent.z,
@@ -263,6 +681,18 @@ impl BSDF {
                                                       normal.z))
     }
 
+    /// Scale the light scattered by the BSDF.
+    #[inline]
+    pub fn scale(&mut self, scale: &Spectrum) {
+        self.scale = self.scale.component_mul(scale);
+    }
+
+    /// Replace each of the BxDFs with the result of `f`,
+    /// such as to wrap them in another BxDF.
+    pub fn map_bxdfs<F: FnMut(Box<BxDF>) -> Box<BxDF>>(&mut self, f: F) {
+        self.bxdfs = self.bxdfs.drain(..).map(f).collect();
+    }
+
     #[inline]
     pub fn add_bxdf<T: 'static + BxDF>(&mut self, x: Box<T>) {
         self.bxdfs.push(x as Box<BxDF>);
@@ -278,22 +708,25 @@ impl BSDF {
         self.world_to_local.inverse_transform_vector(v)
     }
 
-    pub fn sample_f<R>(&self,
-                       wo_world: &Vector,
-                       rng: &mut R,
-                       flags: BxDFType)
-                       -> (Spectrum, Vector, Pdf, Option<BxDFType>)
-        where R: Rng
-    {
+    pub fn sample_f(&self,
+                    wo_world: &Vector,
+                    sampler: &mut Sampler,
+                    flags: BxDFType)
+                    -> (Spectrum, Vector, Pdf, Option<BxDFType>) {
         let wo = self.world_to_local(wo_world);
 
         let bxdfs: Vec<&Box<BxDF>> = self.bxdfs.iter().filter(|x| x.matches_flags(flags)).collect();
         // choose a random bxdf from the matching ones
-        let bxdf = rng.choose(&bxdfs);
+        let u = sampler.get_1d();
+        let bxdf = if bxdfs.is_empty() {
+            None
+        } else {
+            bxdfs.get(usize::min((u * bxdfs.len() as Scalar) as usize, bxdfs.len() - 1))
+        };
 
         match bxdf {
             Some(bxdf) => {
-                let (u1, u2) = rng.gen::<(Scalar, Scalar)>();
+                let (u1, u2) = sampler.get_2d();
                 let (mut colour, wi, mut pdf) = bxdf.sample_f(&wo, u1, u2);
                 let bxdf_type = bxdf.bxdf_type();
 
@@ -329,12 +762,26 @@ impl BSDF {
                         colour = colour + bxdf.f(&wo, &wi);
                     }
                 }
-                (colour, wi_world, pdf, Some(bxdf_type))
+                (colour.component_mul(&self.scale), wi_world, pdf, Some(bxdf_type))
             }
             None => (na::zero(), na::zero(), 0.0, None),
         }
     }
 
+    /// The pdf of sampling the direction `wi_world` given
+    /// `wo_world` using `sample_f` with the same flags.
+    pub fn pdf(&self, wo_world: &Vector, wi_world: &Vector, flags: BxDFType) -> Pdf {
+        let wo = self.world_to_local(wo_world);
+        let wi = self.world_to_local(wi_world);
+
+        let bxdfs: Vec<&Box<BxDF>> = self.bxdfs.iter().filter(|x| x.matches_flags(flags)).collect();
+        if bxdfs.is_empty() {
+            return 0.0;
+        }
+        let pdf: Pdf = bxdfs.iter().map(|bxdf| bxdf.pdf(&wo, &wi)).sum();
+        pdf / bxdfs.len() as Scalar
+    }
+
     pub fn f(&self, wo_world: &Vector, wi_world: &Vector, flags: BxDFType) -> Spectrum {
         // incident and outgoing directions in local space
         let wi = self.world_to_local(wi_world);
@@ -352,8 +799,9 @@ impl BSDF {
         self.bxdfs
             .iter()
             .filter(|x| x.matches_flags(flags))
-            .map(|bxdf| bxdf.f(&wi, &wo))
-            .fold(na::zero(), |acc, f| acc + f)
+            .map(|bxdf| bxdf.f(&wo, &wi))
+            .fold(na::zero::<Spectrum>(), |acc, f| acc + f)
+            .component_mul(&self.scale)
     }
 }
 
@@ -441,3 +889,90 @@ impl Fresnel for FresnelDielectric {
         }
     }
 }
+
+#[test]
+fn test_oren_nayar_without_roughness_is_lambertian() {
+    let colour = Spectrum::new(0.2, 0.5, 0.8);
+    let smooth = OrenNayar::new(colour, 0.0);
+    let lambertian = Lambertian::new(colour);
+    let wo = na::normalize(&Vector::new(0.3, 0.2, 0.9));
+    let wi = na::normalize(&Vector::new(-0.6, 0.1, 0.4));
+    assert_approx_eq!(smooth.f(&wo, &wi), lambertian.f(&wo, &wi));
+
+    // rough surfaces are brighter looking back towards the light
+    let rough = OrenNayar::new(colour, 0.5);
+    let back = na::normalize(&Vector::new(-0.3, 0.05, 0.9));
+    assert!(rough.f(&back, &wi).x > rough.f(&wo, &wi).x);
+}
+
+#[test]
+fn test_thin_dielectric_passes_straight_through() {
+    let white = Spectrum::new(1.0, 1.0, 1.0);
+    let thin = ThinSpecularTransmission::new(white, 1.0, 1.5);
+    let wo = na::normalize(&Vector::new(0.3, 0.2, 0.9));
+    let (t, wi, _) = thin.sample_f(&wo, 0.5, 0.5);
+    assert_approx_eq!(wi, -wo);
+    // light is either reflected or transmitted, with more
+    // reflected than from a single surface
+    let r = FresnelThinDielectric::new(1.0, 1.5).evaluate(cos_theta(&wo));
+    assert_approx_eq!(t * cos_theta(&wi).abs() + r, white);
+    assert!(r.x > FresnelDielectric::new(1.0, 1.5).evaluate(cos_theta(&wo)).x);
+}
+
+#[test]
+fn test_coating_conserves_energy() {
+    let white = Spectrum::new(1.0, 1.0, 1.0);
+    let coated = Coated::new(Box::new(Lambertian::new(white)), 1.0, 1.5);
+    let wo = na::normalize(&Vector::new(0.3, 0.2, 0.9));
+    let wi = na::normalize(&Vector::new(-0.6, 0.1, 0.4));
+    let f = coated.f(&wo, &wi);
+    assert!(f.x < Lambertian::new(white).f(&wo, &wi).x);
+
+    // the base can't get back the light the coating reflects
+    let fresnel = FresnelDielectric::new(1.0, 1.5);
+    let reflected = fresnel.evaluate(cos_theta(&wo)).x;
+    let (f, wi, pdf) = coated.sample_f(&wo, 0.3, 0.6);
+    assert!(f.x * cos_theta(&wi) / pdf <= 1.0 - reflected);
+    assert_approx_eq!(coated.pdf(&wo, &wi), pdf);
+}
+
+#[test]
+fn test_microfacet_samples_are_consistent() {
+    use microfacet::{Beckmann, TrowbridgeReitz};
+
+    let conductor = || Box::new(FresnelConductor::new(na::zero(), Spectrum::new(1.0, 1.0, 1.0)));
+    let white = Spectrum::new(1.0, 1.0, 1.0);
+    let bxdfs: Vec<Box<BxDF>> =
+        vec![Box::new(MicrofacetReflection::new(white,
+                                                Box::new(TrowbridgeReitz::new(0.3, 0.1)),
+                                                conductor())),
+             Box::new(MicrofacetReflection::new(white,
+                                                Box::new(Beckmann::new(0.2, 0.4)),
+                                                conductor())),
+             Box::new(MicrofacetTransmission::new(white,
+                                                  Box::new(TrowbridgeReitz::new(0.3, 0.3)),
+                                                  1.0,
+                                                  1.5)),
+             Box::new(MicrofacetTransmission::new(white,
+                                                  Box::new(Beckmann::new(0.3, 0.3)),
+                                                  1.0,
+                                                  1.5))];
+    let wos = [na::normalize(&Vector::new(0.3, 0.2, 0.9)),
+               na::normalize(&Vector::new(-0.5, 0.1, -0.6))];
+    for bxdf in bxdfs.iter() {
+        for wo in wos.iter() {
+            for &(u1, u2) in &[(0.1, 0.7), (0.5, 0.5), (0.8, 0.2)] {
+                let (f, wi, pdf) = bxdf.sample_f(wo, u1, u2);
+                if pdf == 0.0 {
+                    continue;
+                }
+                assert_approx_eq!(na::norm(&wi), 1.0);
+                assert!((bxdf.pdf(wo, &wi) - pdf).abs() < 1e-6 * pdf.max(1.0));
+                assert_approx_eq!(bxdf.f(wo, &wi), f);
+                // the sampled direction must be on the side the lobe covers
+                assert_eq!(same_hemisphere(wo, &wi),
+                           bxdf.bxdf_type().contains(BSDF_REFLECTION));
+            }
+        }
+    }
+}
@@ -1,100 +1,453 @@
 extern crate nalgebra as na;
 
+use std::f64::consts;
+
 use alga::general::Inverse;
-use na::{Isometry3, Matrix4, Orthographic3, Perspective3, Point3, Translation, Vector4};
+use na::{Matrix4, Orthographic3, Perspective3, Point3, Vector4};
 
-use math::{Point, Scalar, Vector};
+use math::{Point, Scalar, Vector, look_at};
+use montecarlo::concentric_sample_disc;
 use ray::Ray;
+use sampler::Sampler;
+use transform::{AnimatedTransform, Transform};
 
+/// Cameras follow the same right-handed convention as every
+/// other transform in the scene: a camera with no rotation
+/// looks down the -Z axis with +X to the right of the image
+/// and +Y to the top.
+///
+/// Each ray is given a time within the camera's shutter
+/// interval, the camera itself may move during that time.
 pub trait Camera {
-    fn look_at_z(&mut self, at: &Point, up: &Vector);
-    fn width(&self) -> u32;
-    fn height(&self) -> u32;
-    fn view(&self) -> Matrix4<Scalar>;
-    fn proj(&self) -> &Matrix4<Scalar>;
+    /// The placement, shutter and image size of the camera.
+    fn base(&self) -> &CameraBase;
+    fn base_mut(&mut self) -> &mut CameraBase;
+
+    /// Turn the camera to look at a point. A moving camera
+    /// keeps its keyframes and is turned to look at the
+    /// point from where it is at each of them.
+    fn look_at_z(&mut self, at: &Point, up: &Vector) {
+        let base = self.base_mut();
+        base.transform = base.transform.map(|transform| {
+            let eye = Point::from_coordinates(transform.translation());
+            Transform::from_isometry(look_at(&eye, at, up))
+        });
+    }
+
+    #[inline]
+    fn width(&self) -> u32 {
+        self.base().width
+    }
+
+    #[inline]
+    fn height(&self) -> u32 {
+        self.base().height
+    }
 
-    fn position(&self) -> Point3<Scalar>;
+    #[inline]
+    fn position(&self) -> Point3<Scalar> {
+        self.base().position()
+    }
 
-    /// Unproject a point from 2D Screen Space to 3D World Space.
+    /// Generate a ray through the position (x, y) on the film,
+    /// where the pixel (x, y) covers the area up to (x + 1, y + 1).
+    /// Cameras that need more random values to generate a ray
+    /// take them from the sampler.
+    ///
+    /// Returns None if no ray passes through the position,
+    /// such as outside the image circle of a fisheye lens.
+    fn generate_ray(&self, x: Scalar, y: Scalar, sampler: &mut Sampler) -> Option<Ray>;
+}
+
+/// A camera whose rays are found by unprojecting
+/// points on the film with a projection matrix.
+pub trait ProjectiveCamera: Camera {
+    fn proj(&self) -> &Matrix4<Scalar>;
+
+    /// Unproject a point from 2D Screen Space to 3D Camera Space.
     fn unproject(&self, x: Scalar, y: Scalar) -> Point3<Scalar> {
-        let viewproj = self.view() * self.proj().inverse();
         // Device coordinates are normalised [-1, 1].
         let device_x = ((x / self.width() as Scalar) - 0.5) * 2.0;
         let device_y = -((y / self.height() as Scalar) - 0.5) * 2.0;
         let point = Vector4::new(device_x, device_y, -1.0, 1.0);
-        let h_eye = viewproj * point;
+        let h_eye = self.proj().inverse() * point;
         Point3::from_homogeneous(h_eye).expect("Could not convert from homogeneous Vector.")
     }
 
+    /// The ray in Camera Space through the point (x, y) on the film.
     fn ray_from(&self, x: Scalar, y: Scalar) -> Ray {
         let eye = self.unproject(x, y);
-        let origin = self.position();
-        let direction = na::normalize(&(eye - origin));
-        Ray::new(origin, direction)
+        let direction = na::normalize(&eye.coords);
+        Ray::new(Point3::origin(), direction)
     }
 }
 
-// TODO: cache view and projection matrices for optimisation?
-pub struct PerspectiveCamera {
+/// What every kind of camera has: its transform, which
+/// may change while the shutter is open, the shutter
+/// interval and the size of the image in pixels.
+pub struct CameraBase {
+    transform: AnimatedTransform,
+    shutter: (Scalar, Scalar),
     width: u32,
     height: u32,
-    transform: Isometry3<Scalar>,
+}
+
+impl CameraBase {
+    pub fn new(transform: AnimatedTransform,
+               shutter: (Scalar, Scalar),
+               width: u32,
+               height: u32)
+               -> CameraBase {
+        CameraBase {
+            transform: transform,
+            shutter: shutter,
+            width: width,
+            height: height,
+        }
+    }
+
+    #[inline]
+    fn position(&self) -> Point3<Scalar> {
+        Point3::from_coordinates(self.transform.start().translation())
+    }
+}
+
+/// Choose the time a ray is sent at within the shutter interval.
+#[inline]
+fn sample_time(shutter: (Scalar, Scalar), sampler: &mut Sampler) -> Scalar {
+    let (open, close) = shutter;
+    open + (close - open) * sampler.get_1d()
+}
+
+/// Move a ray from Camera Space into World Space
+/// using the camera's transform at the given time.
+#[inline]
+fn camera_to_world(ray: &Ray, transform: &AnimatedTransform, time: Scalar) -> Ray {
+    let mut ray = transform.at(time).transform_ray(ray);
+    ray.ray.dir = na::normalize(ray.dir());
+    ray.time = time;
+    ray
+}
+
+/// A perspective camera modelled as a thin lens.
+///
+/// With a lens radius of zero this is a pinhole camera and
+/// everything is in focus, otherwise only points at the
+/// focal distance in front of the camera are in focus.
+pub struct PerspectiveCamera {
+    base: CameraBase,
     proj: Perspective3<Scalar>,
+    lens_radius: Scalar,
+    focal_distance: Scalar,
 }
 
 impl PerspectiveCamera {
-    pub fn new(transform: Isometry3<Scalar>,
+    pub fn new(transform: AnimatedTransform,
+               shutter: (Scalar, Scalar),
                width: u32,
                height: u32,
                fov: Scalar,
                znear: Scalar,
-               zfar: Scalar)
+               zfar: Scalar,
+               lens_radius: Scalar,
+               focal_distance: Scalar)
                -> PerspectiveCamera {
         PerspectiveCamera {
-            width: width,
-            height: height,
-            transform: transform,
+            base: CameraBase::new(transform, shutter, width, height),
             proj: Perspective3::new((width as Scalar) / (height as Scalar), fov, znear, zfar),
+            lens_radius: lens_radius,
+            focal_distance: focal_distance,
         }
     }
 }
 
 impl Camera for PerspectiveCamera {
     #[inline]
-    fn look_at_z(&mut self, at: &Point, up: &Vector) {
-        // FIXME: this may need to be look_at_rh instead.
-        self.transform = Isometry3::look_at_lh(&self.position(), at, up);
+    fn base(&self) -> &CameraBase {
+        &self.base
     }
 
     #[inline]
-    fn position(&self) -> Point3<Scalar> {
-        Point3::from_coordinates(self.transform.translation.vector)
+    fn base_mut(&mut self) -> &mut CameraBase {
+        &mut self.base
     }
 
+    fn generate_ray(&self, x: Scalar, y: Scalar, sampler: &mut Sampler) -> Option<Ray> {
+        let time = sample_time(self.base.shutter, sampler);
+        let ray = self.ray_from(x, y);
+        if self.lens_radius <= 0.0 {
+            return Some(camera_to_world(&ray, &self.base.transform, time));
+        }
+
+        // find where the pinhole ray meets the plane of focus,
+        // the camera looks down its negative z axis
+        let t = self.focal_distance / -ray.dir().z;
+        let focus = *ray.orig() + *ray.dir() * t;
+
+        // then shoot a ray through it from a point on the lens
+        let (u1, u2) = sampler.get_2d();
+        let (lens_x, lens_y) = concentric_sample_disc(u1, u2);
+        let origin = Point3::new(lens_x * self.lens_radius, lens_y * self.lens_radius, 0.0);
+        let ray = Ray::new(origin, na::normalize(&(focus - origin)));
+        Some(camera_to_world(&ray, &self.base.transform, time))
+    }
+}
+
+impl ProjectiveCamera for PerspectiveCamera {
     #[inline]
-    fn width(&self) -> u32 {
-        self.width
+    fn proj(&self) -> &Matrix4<Scalar> {
+        self.proj.as_matrix()
+    }
+}
+
+/// A camera with parallel rays, objects keep the same
+/// size however far they are from the camera.
+pub struct OrthographicCamera {
+    base: CameraBase,
+    proj: Orthographic3<Scalar>,
+}
+
+impl OrthographicCamera {
+    /// Create a camera that sees a region `size` units high
+    /// and as wide as the aspect ratio of the image gives.
+    pub fn new(transform: AnimatedTransform,
+               shutter: (Scalar, Scalar),
+               width: u32,
+               height: u32,
+               size: Scalar,
+               znear: Scalar,
+               zfar: Scalar)
+               -> OrthographicCamera {
+        let half_height = size * 0.5;
+        let half_width = half_height * (width as Scalar) / (height as Scalar);
+        OrthographicCamera {
+            base: CameraBase::new(transform, shutter, width, height),
+            proj: Orthographic3::new(-half_width,
+                                     half_width,
+                                     -half_height,
+                                     half_height,
+                                     znear,
+                                     zfar),
+        }
+    }
+}
+
+impl Camera for OrthographicCamera {
+    #[inline]
+    fn base(&self) -> &CameraBase {
+        &self.base
     }
 
     #[inline]
-    fn height(&self) -> u32 {
-        self.height
+    fn base_mut(&mut self) -> &mut CameraBase {
+        &mut self.base
     }
 
     #[inline]
-    fn view(&self) -> Matrix4<Scalar> {
-        self.transform.to_homogeneous()
+    fn generate_ray(&self, x: Scalar, y: Scalar, sampler: &mut Sampler) -> Option<Ray> {
+        let time = sample_time(self.base.shutter, sampler);
+        Some(camera_to_world(&self.ray_from(x, y), &self.base.transform, time))
     }
+}
 
+impl ProjectiveCamera for OrthographicCamera {
     #[inline]
     fn proj(&self) -> &Matrix4<Scalar> {
         self.proj.as_matrix()
     }
+
+    fn ray_from(&self, x: Scalar, y: Scalar) -> Ray {
+        // every ray starts on the near plane
+        // and points along the view direction
+        let origin = self.unproject(x, y);
+        Ray::new(origin, Vector::new(0.0, 0.0, -1.0))
+    }
+}
+
+/// A camera that sees in every direction, the film is
+/// mapped to the sphere with an equirectangular projection
+/// with longitude across and latitude down the image.
+///
+/// The centre of the image is in front of the camera.
+pub struct SphericalCamera {
+    base: CameraBase,
+}
+
+impl SphericalCamera {
+    pub fn new(transform: AnimatedTransform,
+               shutter: (Scalar, Scalar),
+               width: u32,
+               height: u32)
+               -> SphericalCamera {
+        SphericalCamera {
+            base: CameraBase::new(transform, shutter, width, height),
+        }
+    }
+}
+
+impl Camera for SphericalCamera {
+    #[inline]
+    fn base(&self) -> &CameraBase {
+        &self.base
+    }
+
+    #[inline]
+    fn base_mut(&mut self) -> &mut CameraBase {
+        &mut self.base
+    }
+
+    fn generate_ray(&self, x: Scalar, y: Scalar, sampler: &mut Sampler) -> Option<Ray> {
+        let time = sample_time(self.base.shutter, sampler);
+        let phi = (x / self.base.width as Scalar - 0.5) * 2.0 * consts::PI;
+        let theta = (y / self.base.height as Scalar) * consts::PI;
+        let dir = Vector::new(theta.sin() * phi.sin(),
+                              theta.cos(),
+                              -theta.sin() * phi.cos());
+        Some(camera_to_world(&Ray::new(Point3::origin(), dir), &self.base.transform, time))
+    }
+}
+
+/// A camera with an equidistant fisheye lens, the
+/// angle from the view direction grows linearly with
+/// distance from the centre of the image.
+///
+/// The image circle fits the shorter side of the image
+/// and covers the given field of view, which can be
+/// more than 180 degrees.
+pub struct FisheyeCamera {
+    base: CameraBase,
+    fov: Scalar,
+}
+
+impl FisheyeCamera {
+    pub fn new(transform: AnimatedTransform,
+               shutter: (Scalar, Scalar),
+               width: u32,
+               height: u32,
+               fov: Scalar)
+               -> FisheyeCamera {
+        FisheyeCamera {
+            base: CameraBase::new(transform, shutter, width, height),
+            fov: fov,
+        }
+    }
+}
+
+impl Camera for FisheyeCamera {
+    #[inline]
+    fn base(&self) -> &CameraBase {
+        &self.base
+    }
+
+    #[inline]
+    fn base_mut(&mut self) -> &mut CameraBase {
+        &mut self.base
+    }
+
+    fn generate_ray(&self, x: Scalar, y: Scalar, sampler: &mut Sampler) -> Option<Ray> {
+        let time = sample_time(self.base.shutter, sampler);
+        let radius = u32::min(self.base.width, self.base.height) as Scalar * 0.5;
+        let px = (x - self.base.width as Scalar * 0.5) / radius;
+        let py = -(y - self.base.height as Scalar * 0.5) / radius;
+        let r = (px * px + py * py).sqrt();
+        if r > 1.0 {
+            return None;
+        }
+        let theta = r * self.fov * 0.5;
+        let phi = py.atan2(px);
+        let dir = Vector::new(theta.sin() * phi.cos(),
+                              theta.sin() * phi.sin(),
+                              -theta.cos());
+        Some(camera_to_world(&Ray::new(Point3::origin(), dir), &self.base.transform, time))
+    }
 }
 
-// pub struct OrthographicCamera {
-//     width: u32,
-//     height: u32,
-//     iso: Isometry3<Scalar>,
-//     proj: Orthographic3<Scalar>
-// }
+#[cfg(test)]
+fn assert_direction(ray: &Ray, expected: Vector) {
+    let expected = na::normalize(&expected);
+    assert_approx_eq!(ray.dir().x, expected.x);
+    assert_approx_eq!(ray.dir().y, expected.y);
+    assert_approx_eq!(ray.dir().z, expected.z);
+}
+
+#[cfg(test)]
+fn test_perspective_camera() -> PerspectiveCamera {
+    use na::Isometry3;
+
+    let transform = AnimatedTransform::fixed(Transform::from_isometry(Isometry3::identity()));
+    PerspectiveCamera::new(transform,
+                           (0.0, 0.0),
+                           100,
+                           100,
+                           consts::FRAC_PI_2,
+                           0.01,
+                           100.0,
+                           0.0,
+                           1.0)
+}
+
+#[test]
+fn test_perspective_ray_from() {
+    let camera = test_perspective_camera();
+    // the centre of the image is straight ahead and with a 90 degree
+    // field of view the edges of the image are 45 degrees off
+    assert_direction(&camera.ray_from(50.0, 50.0), Vector::new(0.0, 0.0, -1.0));
+    assert_direction(&camera.ray_from(100.0, 50.0), Vector::new(1.0, 0.0, -1.0));
+    assert_direction(&camera.ray_from(0.0, 50.0), Vector::new(-1.0, 0.0, -1.0));
+    assert_direction(&camera.ray_from(50.0, 0.0), Vector::new(0.0, 1.0, -1.0));
+    assert_direction(&camera.ray_from(50.0, 100.0), Vector::new(0.0, -1.0, -1.0));
+}
+
+#[test]
+fn test_perspective_generate_ray_look_at() {
+    use sampler::IndependentSampler;
+
+    let mut camera = test_perspective_camera();
+    camera.look_at_z(&Point::new(1.0, 0.0, 0.0), &Vector::new(0.0, 1.0, 0.0));
+    let mut sampler = IndependentSampler::new(1, 0);
+    sampler.start_pixel(0, 0);
+    // looking down +X the right of the image is towards +Z
+    let mut ray_at = |x, y| camera.generate_ray(x, y, &mut sampler).unwrap();
+    assert_direction(&ray_at(50.0, 50.0), Vector::new(1.0, 0.0, 0.0));
+    assert_direction(&ray_at(100.0, 50.0), Vector::new(1.0, 0.0, 1.0));
+    assert_direction(&ray_at(50.0, 0.0), Vector::new(1.0, 1.0, 0.0));
+}
+
+#[test]
+fn test_orthographic_ray_from() {
+    use na::Isometry3;
+
+    let transform = AnimatedTransform::fixed(Transform::from_isometry(Isometry3::identity()));
+    let camera = OrthographicCamera::new(transform,
+                                         (0.0, 0.0),
+                                         200,
+                                         100,
+                                         2.0,
+                                         0.01,
+                                         100.0);
+    let ray = camera.ray_from(200.0, 0.0);
+    assert_direction(&ray, Vector::new(0.0, 0.0, -1.0));
+    // the view is 2 units high and twice as wide
+    assert_approx_eq!(ray.orig().x, 2.0);
+    assert_approx_eq!(ray.orig().y, 1.0);
+}
+
+#[test]
+fn test_look_at_keeps_keyframes() {
+    use na::{Isometry3, Vector3};
+
+    let start = Isometry3::new(Vector3::new(0.0, 0.0, 0.0), na::zero());
+    let end = Isometry3::new(Vector3::new(0.0, 0.0, 2.0), na::zero());
+    let transform = AnimatedTransform::new(vec![(0.0, Transform::from_isometry(start)),
+                                                (1.0, Transform::from_isometry(end))]);
+    let mut camera = SphericalCamera::new(transform, (0.0, 1.0), 100, 50);
+    camera.look_at_z(&Point::new(1.0, 0.0, 1.0), &Vector::new(0.0, 1.0, 0.0));
+    assert!(camera.base.transform.is_animated());
+    // the camera still moves and looks at the point from both ends
+    for &(time, z) in &[(0.0, 0.0), (1.0, 2.0)] {
+        let at = camera.base.transform.at(time);
+        assert_approx_eq!(at.translation().z, z);
+        let forward = at.transform_vector(&Vector::new(0.0, 0.0, -1.0));
+        assert_direction(&Ray::new(Point::origin(), forward), Vector::new(1.0, 0.0, 1.0 - z));
+    }
+}
@@ -0,0 +1,225 @@
+use std::sync::Arc;
+
+use na;
+
+use filter::Filter;
+use math::Scalar;
+use spectrum::Spectrum;
+use tile::Tile;
+
+/// The running weighted sum of the samples for a pixel.
+#[derive(Clone, Copy)]
+struct Pixel {
+    sum: Spectrum,
+    weight: Scalar,
+}
+
+impl Pixel {
+    fn new() -> Pixel {
+        Pixel {
+            sum: na::zero(),
+            weight: 0.0,
+        }
+    }
+}
+
+/// The image being rendered.
+///
+/// Film coordinates are continuous, the pixel (x, y)
+/// covers the area from (x, y) to (x + 1, y + 1).
+/// Samples are taken a tile at a time into a `FilmTile`
+/// and merged into the film when the tile is finished.
+pub struct Film {
+    width: u32,
+    height: u32,
+    filter: Arc<Filter + Sync + Send>,
+    pixels: Vec<Pixel>,
+    splats: Vec<Spectrum>,
+}
+
+impl Film {
+    pub fn new(width: u32, height: u32, filter: Arc<Filter + Sync + Send>) -> Film {
+        Film {
+            width: width,
+            height: height,
+            filter: filter,
+            pixels: vec![Pixel::new(); (width * height) as usize],
+            splats: vec![na::zero(); (width * height) as usize],
+        }
+    }
+
+    #[inline]
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    #[inline]
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    #[inline]
+    pub fn filter(&self) -> &Arc<Filter + Sync + Send> {
+        &self.filter
+    }
+
+    /// Add the samples taken in a tile to the film.
+    pub fn merge_tile(&mut self, tile: FilmTile) {
+        let tile_width = tile.x1 - tile.x0;
+        for y in tile.y0..tile.y1 {
+            for x in tile.x0..tile.x1 {
+                let src = &tile.pixels[((y - tile.y0) * tile_width + (x - tile.x0)) as usize];
+                let dst = &mut self.pixels[(y * self.width + x) as usize];
+                dst.sum = dst.sum + src.sum;
+                dst.weight += src.weight;
+            }
+        }
+    }
+
+    /// Add a contribution to the pixel at the film position
+    /// (x, y) without filtering or weighting it, as done by
+    /// integrators that trace paths from the lights and so
+    /// can't choose which pixels they reach.
+    pub fn add_splat(&mut self, x: Scalar, y: Scalar, l: &Spectrum) {
+        if x < 0.0 || y < 0.0 {
+            return;
+        }
+        let (x, y) = (x as u32, y as u32);
+        if x < self.width && y < self.height {
+            let splat = &mut self.splats[(y * self.width + x) as usize];
+            *splat = *splat + *l;
+        }
+    }
+
+    /// The final linear radiance of every pixel in row-major order.
+    ///
+    /// Splatted contributions are scaled by `splat_scale`,
+    /// usually one over the number of samples per pixel.
+    pub fn image(&self, splat_scale: Scalar) -> Vec<Spectrum> {
+        self.pixels
+            .iter()
+            .zip(self.splats.iter())
+            .map(|(pixel, splat)| {
+                let c = if pixel.weight != 0.0 {
+                    pixel.sum / pixel.weight
+                } else {
+                    na::zero()
+                };
+                // negative filter lobes can leave
+                // negative values near sharp edges
+                let c = Spectrum::new(Scalar::max(0.0, c.x),
+                                      Scalar::max(0.0, c.y),
+                                      Scalar::max(0.0, c.z));
+                c + *splat * splat_scale
+            })
+            .collect()
+    }
+}
+
+/// The samples taken for a region of the film.
+pub struct FilmTile {
+    x0: u32,
+    y0: u32,
+    x1: u32,
+    y1: u32,
+    filter: Arc<Filter + Sync + Send>,
+    pixels: Vec<Pixel>,
+}
+
+impl FilmTile {
+    /// Create a tile for the given region of a film with the
+    /// given size. The tile extends past the region by the
+    /// filter radius since samples near the edge also
+    /// contribute to the pixels just outside it.
+    pub fn new(region: &Tile,
+               width: u32,
+               height: u32,
+               filter: Arc<Filter + Sync + Send>)
+               -> FilmTile {
+        let (rx, ry) = filter.radius();
+        let x0 = Scalar::max(0.0, (region.x0 as Scalar - 0.5 - rx).ceil()) as u32;
+        let y0 = Scalar::max(0.0, (region.y0 as Scalar - 0.5 - ry).ceil()) as u32;
+        let x1 = u32::min(width, (region.x1 as Scalar - 0.5 + rx).floor() as u32 + 1);
+        let y1 = u32::min(height, (region.y1 as Scalar - 0.5 + ry).floor() as u32 + 1);
+        FilmTile {
+            x0: x0,
+            y0: y0,
+            x1: x1,
+            y1: y1,
+            filter: filter,
+            pixels: vec![Pixel::new(); ((x1 - x0) * (y1 - y0)) as usize],
+        }
+    }
+
+    /// Add the radiance of a sample taken at the film position (x, y)
+    /// to every pixel within the filter radius of it.
+    pub fn add_sample(&mut self, x: Scalar, y: Scalar, l: &Spectrum) {
+        let (rx, ry) = self.filter.radius();
+        // the position of the sample relative to the pixel centres
+        let (dx, dy) = (x - 0.5, y - 0.5);
+        let px0 = i64::max(self.x0 as i64, (dx - rx).ceil() as i64);
+        let py0 = i64::max(self.y0 as i64, (dy - ry).ceil() as i64);
+        let px1 = i64::min(self.x1 as i64, (dx + rx).floor() as i64 + 1);
+        let py1 = i64::min(self.y1 as i64, (dy + ry).floor() as i64 + 1);
+        let tile_width = (self.x1 - self.x0) as i64;
+        for py in py0..py1 {
+            for px in px0..px1 {
+                let w = self.filter.evaluate(px as Scalar - dx, py as Scalar - dy);
+                let pixel = &mut self.pixels[((py - self.y0 as i64) * tile_width +
+                                              (px - self.x0 as i64)) as
+                                             usize];
+                pixel.sum = pixel.sum + *l * w;
+                pixel.weight += w;
+            }
+        }
+    }
+}
+
+#[test]
+fn test_box_filtered_film() {
+    use filter::BoxFilter;
+
+    let mut film = Film::new(4, 4, Arc::new(BoxFilter::new((0.5, 0.5))));
+    let region = Tile {
+        x0: 0,
+        y0: 0,
+        x1: 2,
+        y1: 2,
+    };
+    let mut tile = FilmTile::new(&region, 4, 4, film.filter().clone());
+    tile.add_sample(1.25, 1.25, &Spectrum::new(1.0, 2.0, 3.0));
+    tile.add_sample(1.75, 1.75, &Spectrum::new(3.0, 2.0, 1.0));
+    film.merge_tile(tile);
+    let image = film.image(1.0);
+    assert_eq!(image[1 * 4 + 1], Spectrum::new(2.0, 2.0, 2.0));
+    assert_eq!(image[0], na::zero());
+}
+
+#[test]
+fn test_splats_are_scaled_and_added() {
+    use filter::BoxFilter;
+
+    let mut film = Film::new(4, 4, Arc::new(BoxFilter::new((0.5, 0.5))));
+    let region = Tile {
+        x0: 0,
+        y0: 0,
+        x1: 4,
+        y1: 4,
+    };
+    let mut tile = FilmTile::new(&region, 4, 4, film.filter().clone());
+    tile.add_sample(2.5, 1.5, &Spectrum::new(1.0, 1.0, 1.0));
+    film.merge_tile(tile);
+    film.add_splat(2.9, 1.1, &Spectrum::new(4.0, 8.0, 2.0));
+    film.add_splat(2.2, 1.7, &Spectrum::new(4.0, 0.0, 2.0));
+    // splats outside the film are dropped
+    film.add_splat(-0.5, 1.0, &Spectrum::new(1.0, 1.0, 1.0));
+    film.add_splat(4.0, 1.0, &Spectrum::new(1.0, 1.0, 1.0));
+
+    let image = film.image(0.25);
+    assert_eq!(image[1 * 4 + 2], Spectrum::new(3.0, 3.0, 2.0));
+    // a pixel with only splats and no samples
+    film.add_splat(0.5, 3.5, &Spectrum::new(2.0, 2.0, 2.0));
+    let image = film.image(0.5);
+    assert_eq!(image[3 * 4], Spectrum::new(1.0, 1.0, 1.0));
+    assert_eq!(image[0], na::zero());
+}
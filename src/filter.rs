@@ -0,0 +1,212 @@
+use std::f64::consts;
+
+use math::Scalar;
+
+/// A pixel reconstruction filter.
+///
+/// Each sample on the film contributes to every pixel
+/// within the filter's radius of it, weighted by the
+/// filter evaluated at the offset of the pixel's
+/// centre from the sample.
+pub trait Filter {
+    /// The extent of the filter in x and y, it
+    /// is zero everywhere outside of this.
+    fn radius(&self) -> (Scalar, Scalar);
+
+    /// Evaluate the filter at the offset (x, y) from its centre.
+    fn evaluate(&self, x: Scalar, y: Scalar) -> Scalar;
+}
+
+/// Weights every sample within the radius equally.
+pub struct BoxFilter {
+    radius: (Scalar, Scalar),
+}
+
+impl BoxFilter {
+    pub fn new(radius: (Scalar, Scalar)) -> BoxFilter {
+        BoxFilter { radius: radius }
+    }
+}
+
+impl Filter for BoxFilter {
+    #[inline]
+    fn radius(&self) -> (Scalar, Scalar) {
+        self.radius
+    }
+
+    #[inline]
+    fn evaluate(&self, x: Scalar, y: Scalar) -> Scalar {
+        if x.abs() <= self.radius.0 && y.abs() <= self.radius.1 {
+            1.0
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Weights samples falling off linearly from the centre.
+pub struct TriangleFilter {
+    radius: (Scalar, Scalar),
+}
+
+impl TriangleFilter {
+    pub fn new(radius: (Scalar, Scalar)) -> TriangleFilter {
+        TriangleFilter { radius: radius }
+    }
+}
+
+impl Filter for TriangleFilter {
+    #[inline]
+    fn radius(&self) -> (Scalar, Scalar) {
+        self.radius
+    }
+
+    #[inline]
+    fn evaluate(&self, x: Scalar, y: Scalar) -> Scalar {
+        Scalar::max(0.0, self.radius.0 - x.abs()) * Scalar::max(0.0, self.radius.1 - y.abs())
+    }
+}
+
+/// A Gaussian falloff with the given alpha, shifted
+/// down so that it reaches zero at the radius.
+pub struct GaussianFilter {
+    radius: (Scalar, Scalar),
+    alpha: Scalar,
+    edge: (Scalar, Scalar),
+}
+
+impl GaussianFilter {
+    pub fn new(radius: (Scalar, Scalar), alpha: Scalar) -> GaussianFilter {
+        GaussianFilter {
+            radius: radius,
+            alpha: alpha,
+            edge: ((-alpha * radius.0 * radius.0).exp(), (-alpha * radius.1 * radius.1).exp()),
+        }
+    }
+
+    #[inline]
+    fn gaussian(&self, d: Scalar, edge: Scalar) -> Scalar {
+        Scalar::max(0.0, (-self.alpha * d * d).exp() - edge)
+    }
+}
+
+impl Filter for GaussianFilter {
+    #[inline]
+    fn radius(&self) -> (Scalar, Scalar) {
+        self.radius
+    }
+
+    #[inline]
+    fn evaluate(&self, x: Scalar, y: Scalar) -> Scalar {
+        self.gaussian(x, self.edge.0) * self.gaussian(y, self.edge.1)
+    }
+}
+
+/// The Mitchell-Netravali cubic filter, the parameters B and C
+/// trade off blurring against ringing. B = C = 1/3 is the
+/// recommended balance between the two.
+pub struct MitchellFilter {
+    radius: (Scalar, Scalar),
+    b: Scalar,
+    c: Scalar,
+}
+
+impl MitchellFilter {
+    pub fn new(radius: (Scalar, Scalar), b: Scalar, c: Scalar) -> MitchellFilter {
+        MitchellFilter {
+            radius: radius,
+            b: b,
+            c: c,
+        }
+    }
+
+    /// The 1D filter over [-2, 2].
+    fn mitchell_1d(&self, x: Scalar) -> Scalar {
+        let (b, c) = (self.b, self.c);
+        let x = x.abs();
+        if x > 2.0 {
+            0.0
+        } else if x > 1.0 {
+            ((-b - 6.0 * c) * x * x * x + (6.0 * b + 30.0 * c) * x * x +
+             (-12.0 * b - 48.0 * c) * x + (8.0 * b + 24.0 * c)) / 6.0
+        } else {
+            ((12.0 - 9.0 * b - 6.0 * c) * x * x * x + (-18.0 + 12.0 * b + 6.0 * c) * x * x +
+             (6.0 - 2.0 * b)) / 6.0
+        }
+    }
+}
+
+impl Filter for MitchellFilter {
+    #[inline]
+    fn radius(&self) -> (Scalar, Scalar) {
+        self.radius
+    }
+
+    #[inline]
+    fn evaluate(&self, x: Scalar, y: Scalar) -> Scalar {
+        self.mitchell_1d(2.0 * x / self.radius.0) * self.mitchell_1d(2.0 * y / self.radius.1)
+    }
+}
+
+/// A sinc filter windowed by a wider sinc, tau
+/// is the number of cycles the filter has.
+pub struct LanczosFilter {
+    radius: (Scalar, Scalar),
+    tau: Scalar,
+}
+
+impl LanczosFilter {
+    pub fn new(radius: (Scalar, Scalar), tau: Scalar) -> LanczosFilter {
+        LanczosFilter {
+            radius: radius,
+            tau: tau,
+        }
+    }
+
+    fn windowed_sinc(&self, x: Scalar, radius: Scalar) -> Scalar {
+        let x = x.abs();
+        if x > radius {
+            0.0
+        } else {
+            sinc(x) * sinc(x / self.tau)
+        }
+    }
+}
+
+impl Filter for LanczosFilter {
+    #[inline]
+    fn radius(&self) -> (Scalar, Scalar) {
+        self.radius
+    }
+
+    #[inline]
+    fn evaluate(&self, x: Scalar, y: Scalar) -> Scalar {
+        self.windowed_sinc(x, self.radius.0) * self.windowed_sinc(y, self.radius.1)
+    }
+}
+
+#[inline]
+fn sinc(x: Scalar) -> Scalar {
+    let x = x.abs();
+    if x < 1e-5 {
+        1.0
+    } else {
+        (consts::PI * x).sin() / (consts::PI * x)
+    }
+}
+
+#[test]
+fn test_filters_vanish_at_radius() {
+    let radius = (2.0, 2.0);
+    let filters: Vec<Box<Filter>> = vec![Box::new(TriangleFilter::new(radius)),
+                                         Box::new(GaussianFilter::new(radius, 2.0)),
+                                         Box::new(MitchellFilter::new(radius,
+                                                                      1.0 / 3.0,
+                                                                      1.0 / 3.0)),
+                                         Box::new(LanczosFilter::new(radius, 3.0))];
+    for filter in &filters {
+        assert!(filter.evaluate(0.0, 0.0) > 0.0);
+        assert_approx_eq!(filter.evaluate(2.0, 0.0), 0.0);
+        assert_approx_eq!(filter.evaluate(0.0, 2.0), 0.0);
+    }
+}
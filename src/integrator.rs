@@ -1,63 +1,124 @@
 
+use std::sync::Arc;
+
 use na;
 
-use bxdf::{BxDFType, BSDF_ALL, BSDF_REFLECTION, BSDF_SPECULAR, BSDF_TRANSMISSION};
+use bxdf::{BxDFType, Pdf, BSDF_ALL, BSDF_REFLECTION, BSDF_SPECULAR, BSDF_TRANSMISSION};
 use light::Light;
-use math::{Scalar, Vector};
-use rand::{Rng, StdRng};
+use math::{Point, Scalar, Vector};
+use montecarlo::power_heuristic;
 use ray::Ray;
 use renderer::Renderer;
+use sampler::Sampler;
 use scene::{Intersection, Scene};
-use spectrum::Spectrum;
+use spectrum::{Spectrum, luminance};
 
 // maximum depth to perform actual
 // sampling techniques in path tracing
 const SAMPLE_DEPTH: i32 = 3;
 
-fn luminance(c: &Spectrum) -> Scalar {
-    c.x * 0.2126 + c.y * 0.7152 + c.z * 0.0722
-}
-
+/// Sample the direct lighting from a single light.
+///
+/// If `select_pdf` is given (the probability of the light
+/// having been chosen) the sample is weighted using multiple
+/// importance sampling against the BSDF sampling the same
+/// direction, in which case the rest of the light's
+/// contribution must be found by sampling the BSDF.
 #[inline]
-fn sample_light(light: &Box<Light + Send + Sync>,
+fn sample_light(light: &Arc<Light + Send + Sync>,
                 wo: &Vector,
                 isect: &Intersection,
                 scene: &Scene,
-                flags: BxDFType)
+                sampler: &mut Sampler,
+                flags: BxDFType,
+                select_pdf: Option<Pdf>)
                 -> Spectrum {
-    let (li, wi) = light.sample(&isect.point);
-    if li == na::zero() {
+    let (u1, u2) = sampler.get_2d();
+    let (li, wi, pdf, dist) = light.sample(&isect.point, isect.time, u1, u2);
+    if li == na::zero() || pdf == 0.0 {
         return na::zero();
     }
     let bsdf = &isect.bsdf;
     let f = bsdf.f(wo, &wi, flags);
-    if f == na::zero() || light.shadow(&isect.point, scene) {
-        na::zero()
-    } else {
-        f.component_mul(&li) * na::dot(&isect.normal, &wi)
+    if f == na::zero() || light.shadow(&isect.point, &wi, dist, isect.time, scene) {
+        return na::zero();
+    }
+    let ld = f.component_mul(&li) * na::dot(&isect.shading_normal, &wi).abs() / pdf;
+    match select_pdf {
+        Some(select_pdf) if !light.is_delta() => {
+            // the BSDF is sampled with all flags when
+            // continuing the path so weight against that
+            let bsdf_pdf = bsdf.pdf(wo, &wi, BSDF_ALL);
+            ld * power_heuristic(1, pdf * select_pdf, 1, bsdf_pdf)
+        }
+        _ => ld,
     }
 }
 
+/// Sample the direct lighting from one light in the
+/// scene chosen at random, optionally weighting the
+/// sample using multiple importance sampling.
 pub fn sample_one_light(wo: &Vector,
                         isect: &Intersection,
                         scene: &Scene,
-                        rng: &mut StdRng)
+                        sampler: &mut Sampler,
+                        mis: bool)
                         -> Spectrum {
     let nlights = scene.lights.len();
     if nlights == 0 {
         return na::zero();
     }
-    let light = rng.choose(&scene.lights).expect("Light could not be chosen");
-    sample_light(light, wo, isect, scene, BSDF_ALL - BSDF_SPECULAR) * nlights as f64
+    let index = (sampler.get_1d() * nlights as Scalar) as usize;
+    let light = &scene.lights[usize::min(index, nlights - 1)];
+    let select_pdf = if mis {
+        Some(1.0 / nlights as Scalar)
+    } else {
+        None
+    };
+    sample_light(light,
+                 wo,
+                 isect,
+                 scene,
+                 sampler,
+                 BSDF_ALL - BSDF_SPECULAR,
+                 select_pdf) * nlights as f64
 }
 
 /// Integrate over all lights computing
 /// direct lighting at a surface point
 /// and sampling the BSDF at the intersection.
-pub fn sample_all_lights(wo: &Vector, isect: &Intersection, scene: &Scene) -> Spectrum {
+pub fn sample_all_lights(wo: &Vector,
+                         isect: &Intersection,
+                         scene: &Scene,
+                         sampler: &mut Sampler)
+                         -> Spectrum {
     scene.lights
         .iter()
-        .map(|l| sample_light(l, wo, isect, scene, BSDF_ALL - BSDF_SPECULAR))
+        .map(|l| sample_light(l, wo, isect, scene, sampler, BSDF_ALL - BSDF_SPECULAR, None))
+        .fold(na::zero(), |acc, c| acc + c)
+}
+
+/// Sum the radiance emitted by all lights in the
+/// direction of a ray that escaped the scene.
+pub fn escaped_radiance(ray: &Ray, scene: &Scene) -> Spectrum {
+    scene.lights
+        .iter()
+        .map(|l| l.emitted(ray.dir()))
+        .fold(na::zero(), |acc, c| acc + c)
+}
+
+/// Sum the radiance emitted by all lights in the
+/// direction of a ray that escaped the scene after
+/// sampling the BSDF at the point p with the given
+/// pdf, weighted against sampling the lights directly.
+fn escaped_radiance_mis(p: &Point, ray: &Ray, bsdf_pdf: Pdf, scene: &Scene) -> Spectrum {
+    let select_pdf = 1.0 / scene.lights.len() as Scalar;
+    scene.lights
+        .iter()
+        .map(|l| {
+            let light_pdf = l.pdf(p, ray.dir()) * select_pdf;
+            l.emitted(ray.dir()) * power_heuristic(1, bsdf_pdf, 1, light_pdf)
+        })
         .fold(na::zero(), |acc, c| acc + c)
 }
 
@@ -66,17 +127,20 @@ pub fn specular_reflect(ray: &Ray,
                         isect: &Intersection,
                         scene: &Scene,
                         renderer: &Renderer,
-                        rng: &mut StdRng)
+                        sampler: &mut Sampler)
                         -> Spectrum {
     let wo = -(*ray.dir());
-    let n = &isect.normal;
+    let n = &isect.shading_normal;
     let bsdf = &isect.bsdf;
-    let (f, wi, pdf, _) = bsdf.sample_f(&wo, rng, BSDF_REFLECTION | BSDF_SPECULAR);
+    let (f, wi, pdf, _) = bsdf.sample_f(&wo, sampler, BSDF_REFLECTION | BSDF_SPECULAR);
     if pdf > 0.0 && f != na::zero() && na::dot(&wi, n) != 0.0 {
         // move the ray origin forward by a small amount in its direction
         // to avoid intersection with the surface we just came from
-        let ray = Ray::new_with_depth(isect.point + wi * 0.000000000001, wi, ray.depth + 1);
-        let li = renderer.render(&ray, scene, rng);
+        let ray = Ray::new_with_time(isect.point + wi * 0.000000000001,
+                                     wi,
+                                     ray.depth + 1,
+                                     isect.time);
+        let li = renderer.render(&ray, scene, sampler);
         f.component_mul(&li) * (na::dot(&wi, n).abs() / pdf)
     } else {
         na::zero()
@@ -88,17 +152,20 @@ pub fn specular_transmit(ray: &Ray,
                          isect: &Intersection,
                          scene: &Scene,
                          renderer: &Renderer,
-                         rng: &mut StdRng)
+                         sampler: &mut Sampler)
                          -> Spectrum {
     let wo = -(*ray.dir());
-    let n = &isect.normal;
+    let n = &isect.shading_normal;
     let bsdf = &isect.bsdf;
-    let (f, wi, pdf, _) = bsdf.sample_f(&wo, rng, BSDF_TRANSMISSION | BSDF_SPECULAR);
+    let (f, wi, pdf, _) = bsdf.sample_f(&wo, sampler, BSDF_TRANSMISSION | BSDF_SPECULAR);
     if pdf > 0.0 && f != na::zero() && na::dot(&wi, n) != 0.0 {
         // move the ray origin forward by a small amount in its direction
         // to avoid intersection with the surface we just came from
-        let ray = Ray::new_with_depth(isect.point + wi * 0.000000000001, wi, ray.depth + 1);
-        let li = renderer.render(&ray, scene, rng);
+        let ray = Ray::new_with_time(isect.point + wi * 0.000000000001,
+                                     wi,
+                                     ray.depth + 1,
+                                     isect.time);
+        let li = renderer.render(&ray, scene, sampler);
         f.component_mul(&li) * (na::dot(&wi, n).abs() / pdf)
     } else {
         na::zero()
@@ -111,7 +178,7 @@ pub trait Integrator {
                  isect: &Intersection,
                  scene: &Scene,
                  renderer: &Renderer,
-                 rng: &mut StdRng)
+                 sampler: &mut Sampler)
                  -> Spectrum;
 }
 
@@ -131,14 +198,14 @@ impl Integrator for Whitted {
                  isect: &Intersection,
                  scene: &Scene,
                  renderer: &Renderer,
-                 rng: &mut StdRng)
+                 sampler: &mut Sampler)
                  -> Spectrum {
         let wo = -(*ray.dir());
-        let mut l = sample_all_lights(&wo, isect, scene);
+        let mut l = isect.le + sample_all_lights(&wo, isect, scene, sampler);
 
         if ray.depth < self.depth {
-            l = l + specular_reflect(ray, isect, scene, renderer, rng);
-            l = l + specular_transmit(ray, isect, scene, renderer, rng);
+            l = l + specular_reflect(ray, isect, scene, renderer, sampler);
+            l = l + specular_transmit(ray, isect, scene, renderer, sampler);
         }
         l
     }
@@ -163,7 +230,7 @@ fn path_bounce(tracer: &PathTraced,
                isect: &Intersection,
                scene: &Scene,
                renderer: &Renderer,
-               rng: &mut StdRng,
+               sampler: &mut Sampler,
                bounce: i32,
                throughput: Spectrum,
                specular_bounce: bool)
@@ -171,33 +238,62 @@ fn path_bounce(tracer: &PathTraced,
     let mut l = na::zero();
     let bsdf = &isect.bsdf;
     let wo = -(*ray.dir());
-    // TODO: add emitted light at path vertex
-    // if bounce == 0 || specular_bounce {
-    //     l = l + throughput *
-    // }
-    if bounce < SAMPLE_DEPTH {
-        // TODO: this should perform proper sampling
-        // using Monte Carlo techniques, currently it's
-        // exactly the same as the other branch
-        l = l + throughput.component_mul(&sample_one_light(&wo, isect, scene, rng));
-    } else {
-        l = l + throughput.component_mul(&sample_one_light(&wo, isect, scene, rng));
+    // emitted light at other path vertices is already accounted
+    // for by sampling the lights directly at the previous vertex
+    if bounce == 0 || specular_bounce {
+        l = l + throughput.component_mul(&isect.le);
     }
+    // combine light and BSDF sampling for direct lighting at
+    // the first few vertices, past that only sample the lights
+    let mis = bounce < SAMPLE_DEPTH;
+    l = l + throughput.component_mul(&sample_one_light(&wo, isect, scene, sampler, mis));
 
     // sample BSDF to get next direction for path
-    let (f, wi, pdf, flags) = bsdf.sample_f(&wo, rng, BSDF_ALL);
+    let (f, wi, pdf, flags) = bsdf.sample_f(&wo, sampler, BSDF_ALL);
     if f == na::zero() || pdf == 0.0 {
         return l;
     }
     let flags = flags.unwrap();
     let specular_bounce = flags.intersects(BSDF_SPECULAR);
-    let mut throughput = throughput.component_mul(&f) * na::dot(&wi, &isect.normal).abs() / pdf;
-    let ray = Ray::new(isect.point + wi * 0.000000000001, wi);
+    let mut throughput = throughput.component_mul(&f) *
+                         na::dot(&wi, &isect.shading_normal).abs() / pdf;
+    let ray = Ray::new_with_time(isect.point + wi * 0.000000000001, wi, 0, isect.time);
+    let next_isect = match scene.trace(&ray) {
+        Some(next) => next,
+        None => {
+            // the path escaped the scene so add the
+            // light arriving from infinitely far away
+            if specular_bounce {
+                l = l + throughput.component_mul(&escaped_radiance(&ray, scene));
+            } else if mis {
+                l = l + throughput.component_mul(&escaped_radiance_mis(&isect.point,
+                                                                          &ray,
+                                                                          pdf,
+                                                                          scene));
+            }
+            return l;
+        }
+    };
+
+    // add the light emitted from the surface found by sampling the
+    // BSDF, weighted against the light sample taken at this vertex
+    if mis && !specular_bounce {
+        if let Some(ref light) = next_isect.light {
+            let select_pdf = 1.0 / scene.lights.len() as Scalar;
+            let light_pdf = light.pdf_from(&isect.point,
+                                           &next_isect.point,
+                                           &next_isect.normal,
+                                           isect.time) *
+                            select_pdf;
+            let weight = power_heuristic(1, pdf, 1, light_pdf);
+            l = l + throughput.component_mul(&next_isect.le) * weight;
+        }
+    }
 
     // possibly terminate the path using russian roulette
     if bounce > 3 {
         let continue_probability = f64::min(0.5, luminance(&throughput));
-        if rng.next_f64() > continue_probability {
+        if sampler.get_1d() > continue_probability {
             return l;
         }
         throughput = throughput / continue_probability;
@@ -208,29 +304,17 @@ fn path_bounce(tracer: &PathTraced,
         return l;
     }
 
+    // TODO: take transmittance into account
     l +
-    match scene.trace(&ray) {
-        Some(isect) => {
-            // TODO: take transmittance into account
-            path_bounce(tracer,
-                        &ray,
-                        &isect,
-                        scene,
-                        renderer,
-                        rng,
-                        bounce + 1,
-                        throughput,
-                        specular_bounce)
-        }
-        None => {
-            if specular_bounce {
-                // TODO: get light from all lights
-                // emitted in the incident direction
-                // given by wi
-            }
-            na::zero()
-        }
-    }
+    path_bounce(tracer,
+                &ray,
+                &next_isect,
+                scene,
+                renderer,
+                sampler,
+                bounce + 1,
+                throughput,
+                specular_bounce)
 }
 
 impl Integrator for PathTraced {
@@ -239,14 +323,14 @@ impl Integrator for PathTraced {
                  isect: &Intersection,
                  scene: &Scene,
                  renderer: &Renderer,
-                 rng: &mut StdRng)
+                 sampler: &mut Sampler)
                  -> Spectrum {
         path_bounce(self,
                     ray,
                     isect,
                     scene,
                     renderer,
-                    rng,
+                    sampler,
                     0,
                     Vector::new(1.0, 1.0, 1.0),
                     false)
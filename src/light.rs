@@ -1,16 +1,27 @@
-
 use std::f64::consts;
+use std::sync::Arc;
 
+use alga::linear::ProjectiveTransformation;
 use na;
-use na::{Point3, Vector3};
+use na::{Isometry3, Point3, Vector3};
 
 use ncollide::utils::triangle_area;
-use ncollide::shape::{Ball3, Cuboid3, Triangle3, TriMesh3};
+use ncollide::shape::{Ball3, Cuboid3, Triangle3};
 
+use bxdf::Pdf;
 use math::{Normal, Point, Scalar, Vector, uniform_sample_sphere};
+use mesh::TriangleMesh;
+use montecarlo::{Distribution2D, uniform_sample_triangle};
 use ray::Ray;
 use scene::Scene;
-use spectrum::Spectrum;
+use spectrum::{Spectrum, luminance};
+use transform::AnimatedTransform;
+
+// fraction of the distance to a sampled point on a light
+// that a shadow ray must travel before it is considered
+// to be occluded, this stops the light's own surface from
+// casting a shadow
+const SHADOW_EPSILON: Scalar = 1e-4;
 
 pub trait Light {
     fn colour(&self) -> &Spectrum;
@@ -18,18 +29,38 @@ pub trait Light {
     // fn power(&self) -> Scalar;
     fn is_delta(&self) -> bool;
 
-    /// Sample the light given a point and its shading
-    /// normal in world space, returning a Spectrum and
-    /// a normalized vector indicating the
-    /// incident light direction.
-    fn sample(&self, p: &Point) -> (Spectrum, Vector);
-
+    /// Sample the light from a point in world space at the
+    /// given time using the uniform random values `u1` and
+    /// `u2`, returning the incident radiance, the normalized
+    /// incident light direction, the pdf (with respect to
+    /// solid angle) of sampling that direction and the
+    /// distance to the sampled point on the light.
+    fn sample(&self,
+              p: &Point,
+              time: Scalar,
+              u1: Scalar,
+              u2: Scalar)
+              -> (Spectrum, Vector, Pdf, Scalar);
+
+    /// The radiance arriving from this light along a ray
+    /// that escaped the scene travelling in direction wi.
     #[inline]
-    fn emitted(&self, wi: &Vector) -> Spectrum {
+    fn emitted(&self, _: &Vector) -> Spectrum {
         na::zero()
     }
 
-    fn shadow(&self, p: &Point, scene: &Scene) -> bool;
+    /// The pdf of `sample` choosing the direction wi from
+    /// the point p, for lights that can be found by rays
+    /// escaping the scene.
+    #[inline]
+    fn pdf(&self, _: &Point, _: &Vector) -> Pdf {
+        0.0
+    }
+
+    /// Is the point p in shadow cast by this light when it
+    /// is sampled in the direction wi at distance dist at
+    /// the given time?
+    fn shadow(&self, p: &Point, wi: &Vector, dist: Scalar, time: Scalar, scene: &Scene) -> bool;
 }
 
 pub struct PointLight {
@@ -61,28 +92,28 @@ impl Light for PointLight {
 
     /// Give the amount of incident light at a particular
     /// point in the scene.
-    fn sample(&self, p: &Point) -> (Spectrum, Vector) {
+    fn sample(&self,
+              p: &Point,
+              _: Scalar,
+              _: Scalar,
+              _: Scalar)
+              -> (Spectrum, Vector, Pdf, Scalar) {
         let mut wi = self.position - *p;
         let dist = wi.norm_squared();
         wi.normalize_mut();
         if dist > 0.0 && dist <= self.radius * self.radius {
             let attenuation = (1.0 / dist) * self.radius;
             let li = self.colour * self.intensity * attenuation;
-            (li, wi)
+            (li, wi, 1.0, dist.sqrt())
         } else {
-            (na::zero(), wi)
+            (na::zero(), wi, 1.0, dist.sqrt())
         }
     }
 
     /// Is the point p in shadow cast by this light?
-    fn shadow(&self, p: &Point, scene: &Scene) -> bool {
-        let dist = na::distance(&self.position, p);
-        let mut dir = self.position - *p;
-        dir.normalize_mut();
-        let ray = Ray::new(*p, dir);
-        scene.intersections(&ray)
-            .iter()
-            .any(|&x| x < dist)
+    fn shadow(&self, p: &Point, wi: &Vector, dist: Scalar, time: Scalar, scene: &Scene) -> bool {
+        let ray = Ray::new_with_time(*p, *wi, 0, time);
+        scene.occluded(&ray, dist)
     }
 }
 
@@ -112,17 +143,158 @@ impl Light for DirectionalLight {
     }
 
     #[inline]
-    fn sample(&self, _: &Point) -> (Spectrum, Vector) {
-        (self.colour, -self.direction)
+    fn sample(&self,
+              _: &Point,
+              _: Scalar,
+              _: Scalar,
+              _: Scalar)
+              -> (Spectrum, Vector, Pdf, Scalar) {
+        (self.colour, -self.direction, 1.0, ::std::f64::INFINITY)
     }
 
     #[inline]
-    fn shadow(&self, _: &Point, _: &Scene) -> bool {
+    fn shadow(&self, _: &Point, _: &Vector, _: Scalar, _: Scalar, _: &Scene) -> bool {
         // No point can be in shadow from a global directional light
         false
     }
 }
 
+/// A light infinitely far away that surrounds the whole
+/// scene, with the radiance arriving from each direction
+/// given by an equirectangular (latitude-longitude) map.
+pub struct InfiniteAreaLight {
+    colour: Spectrum,
+    transform: Isometry3<Scalar>,
+    width: u32,
+    height: u32,
+    texels: Vec<Spectrum>,
+    distribution: Distribution2D,
+}
+
+impl InfiniteAreaLight {
+    /// Create a light from an equirectangular map whose texels
+    /// are stored in row-major order, scaled by colour. The
+    /// top row of the map is in the direction of the light's
+    /// local +Y axis.
+    pub fn new(colour: Spectrum,
+               transform: Isometry3<Scalar>,
+               width: u32,
+               height: u32,
+               texels: Vec<Spectrum>)
+               -> InfiniteAreaLight {
+        assert_eq!(texels.len(), (width * height) as usize);
+        // sample the map in proportion to its luminance, the
+        // sin(theta) term compensates for the distortion of
+        // rows near the poles of the sphere
+        let func: Vec<Scalar> = (0..height)
+            .flat_map(|y| {
+                let sin_theta = ((y as Scalar + 0.5) / height as Scalar * consts::PI).sin();
+                let texels = &texels;
+                (0..width).map(move |x| {
+                    let c = texels[(y * width + x) as usize];
+                    luminance(&c) * sin_theta
+                })
+            })
+            .collect();
+        let distribution = Distribution2D::new(&func, width as usize, height as usize);
+
+        InfiniteAreaLight {
+            colour: colour,
+            transform: transform,
+            width: width,
+            height: height,
+            texels: texels,
+            distribution: distribution,
+        }
+    }
+
+    /// Create a light with the same radiance in every direction.
+    pub fn constant(colour: Spectrum) -> InfiniteAreaLight {
+        Self::new(colour,
+                  Isometry3::identity(),
+                  1,
+                  1,
+                  vec![Spectrum::new(1.0, 1.0, 1.0)])
+    }
+
+    /// Map a direction in the light's local space
+    /// to coordinates in [0, 1]^2 on the map.
+    fn direction_to_uv(w: &Vector) -> (Scalar, Scalar) {
+        let theta = w.y.max(-1.0).min(1.0).acos();
+        let mut phi = w.z.atan2(w.x);
+        if phi < 0.0 {
+            phi = phi + 2.0 * consts::PI;
+        }
+        (phi / (2.0 * consts::PI), theta / consts::PI)
+    }
+
+    fn lookup(&self, u: Scalar, v: Scalar) -> Spectrum {
+        let x = u32::min((u * self.width as Scalar) as u32, self.width - 1);
+        let y = u32::min((v * self.height as Scalar) as u32, self.height - 1);
+        self.texels[(y * self.width + x) as usize].component_mul(&self.colour)
+    }
+}
+
+impl Light for InfiniteAreaLight {
+    #[inline]
+    fn colour(&self) -> &Spectrum {
+        &self.colour
+    }
+
+    #[inline]
+    fn is_delta(&self) -> bool {
+        false
+    }
+
+    fn sample(&self,
+              _: &Point,
+              _: Scalar,
+              u1: Scalar,
+              u2: Scalar)
+              -> (Spectrum, Vector, Pdf, Scalar) {
+        let ((u, v), map_pdf) = self.distribution.sample_continuous(u1, u2);
+        if map_pdf == 0.0 {
+            return (na::zero(), na::zero(), 0.0, 0.0);
+        }
+
+        let theta = v * consts::PI;
+        let phi = u * 2.0 * consts::PI;
+        let sin_theta = theta.sin();
+        if sin_theta == 0.0 {
+            return (na::zero(), na::zero(), 0.0, 0.0);
+        }
+        let w = Vector::new(sin_theta * phi.cos(), theta.cos(), sin_theta * phi.sin());
+        let wi = self.transform * w;
+
+        // convert the pdf from being with respect
+        // to the map to being with respect to solid angle
+        let pdf = map_pdf / (2.0 * consts::PI * consts::PI * sin_theta);
+        (self.lookup(u, v), wi, pdf, ::std::f64::INFINITY)
+    }
+
+    fn emitted(&self, wi: &Vector) -> Spectrum {
+        let w = self.transform.inverse_transform_vector(wi);
+        let (u, v) = Self::direction_to_uv(&na::normalize(&w));
+        self.lookup(u, v)
+    }
+
+    fn pdf(&self, _: &Point, wi: &Vector) -> Pdf {
+        let w = na::normalize(&self.transform.inverse_transform_vector(wi));
+        let (u, v) = Self::direction_to_uv(&w);
+        let sin_theta = (v * consts::PI).sin();
+        if sin_theta == 0.0 {
+            0.0
+        } else {
+            self.distribution.pdf(u, v) / (2.0 * consts::PI * consts::PI * sin_theta)
+        }
+    }
+
+    fn shadow(&self, p: &Point, wi: &Vector, dist: Scalar, time: Scalar, scene: &Scene) -> bool {
+        let ray = Ray::new_with_time(*p, *wi, 0, time);
+        scene.occluded(&ray, dist)
+    }
+}
+
 // pub struct SpotLight {
 //     colour: Spectrum,
 //     direction: Vector,
@@ -137,109 +309,218 @@ impl Light for DirectionalLight {
 //     fn is_delta(&self) -> bool { true }
 // }
 
-// pub trait AreaLight : Light {
-//     fn radiance(&self, p: &Point, n: &Normal, w: &Vector) -> Spectrum;
-// }
+/// A light that emits radiance from the surface
+/// of a shape in the scene.
+pub trait AreaLight: Light {
+    /// The radiance emitted from the point p on the
+    /// surface of the light with normal n in the
+    /// direction w.
+    fn radiance(&self, p: &Point, n: &Normal, w: &Vector) -> Spectrum;
+
+    /// The pdf (with respect to solid angle) of `sample`
+    /// choosing the point ps with normal ns on the surface
+    /// of the light when sampled from the point p at the
+    /// given time.
+    fn pdf_from(&self, p: &Point, ps: &Point, ns: &Normal, time: Scalar) -> Pdf;
+}
 
-// pub struct DiffuseLight {
-//     emit: Spectrum,
-//     area: Scalar
-// }
+/// An area light that emits the same amount of
+/// radiance in every direction on the outward
+/// side of its surface.
+pub struct DiffuseLight {
+    emit: Spectrum,
+    transform: AnimatedTransform,
+    shape: Arc<ShapeEmitter + Sync + Send>,
+    area: Scalar,
+}
 
-// impl Light for DiffuseLight {
-//     #[inline]
-//     fn colour(&self) -> &Spectrum { &self.emit }
+impl DiffuseLight {
+    pub fn new(emit: Spectrum,
+               transform: AnimatedTransform,
+               shape: Arc<ShapeEmitter + Sync + Send>)
+               -> DiffuseLight {
+        let area = shape.area();
+        DiffuseLight {
+            emit: emit,
+            transform: transform,
+            shape: shape,
+            area: area,
+        }
+    }
+}
 
-//     #[inline]
-//     fn sample(&self, p: &Point) -> (Spectrum, Vector) {
-//         (na::zero(), na::zero())
-//     }
-//     #[inline]
-//     fn is_delta(&self) -> bool { false }
+impl Light for DiffuseLight {
+    #[inline]
+    fn colour(&self) -> &Spectrum {
+        &self.emit
+    }
 
-//     #[inline]
-//     fn shadow(&self, p: &Point, scene: &Scene) -> bool {
-//         true
-//     }
-// }
+    #[inline]
+    fn is_delta(&self) -> bool {
+        false
+    }
 
-// impl AreaLight for DiffuseLight {
-//     #[inline]
-//     fn radiance(&self, p: &Point, n: &Normal, w: &Vector) -> Spectrum {
-//         if na::dot(w, n) > 0.0 { self.emit } else { na::zero() }
-//     }
-// }
+    fn sample(&self,
+              p: &Point,
+              time: Scalar,
+              u1: Scalar,
+              u2: Scalar)
+              -> (Spectrum, Vector, Pdf, Scalar) {
+        // sample the light where it is at the given time
+        let transform = self.transform.at(time);
+        let (ps, ns) = self.shape.sample(u1, u2);
+        let ps = transform.transform_point(&ps);
+        let ns = transform.transform_normal(&ns);
+
+        let mut wi = ps - *p;
+        let dist2 = wi.norm_squared();
+        if dist2 == 0.0 {
+            return (na::zero(), na::zero(), 0.0, 0.0);
+        }
+        let dist = dist2.sqrt();
+        wi = wi / dist;
 
-// /// A trait for designating a Shape as being an
-// /// emitter for radiance.
-// pub trait ShapeEmitter {
-//     fn area(&self) -> Scalar;
-//     fn sample(&self, u1: Scalar, u2: Scalar) -> (Point, Normal);
+        let pdf = self.pdf_from(p, &ps, &ns, time);
+        if pdf == 0.0 {
+            return (na::zero(), wi, 0.0, dist);
+        }
+        (self.radiance(&ps, &ns, &-wi), wi, pdf, dist)
+    }
 
-//     fn sample_at_point(&self, p: &Point, u1: Scalar, u2: Scalar) -> (Point, Normal) {
-//         self.sample(u1, u2)
-//     }
-// }
+    fn shadow(&self, p: &Point, wi: &Vector, dist: Scalar, time: Scalar, scene: &Scene) -> bool {
+        let ray = Ray::new_with_time(*p, *wi, 0, time);
+        scene.occluded(&ray, dist * (1.0 - SHADOW_EPSILON))
+    }
+}
 
-// impl ShapeEmitter for Triangle3<Scalar> {
-//     #[inline]
-//     fn area(&self) -> Scalar {
-//         triangle_area(self.a(), self.b(), self.c())
-//     }
+impl AreaLight for DiffuseLight {
+    #[inline]
+    fn radiance(&self, _: &Point, n: &Normal, w: &Vector) -> Spectrum {
+        if na::dot(w, n) > 0.0 {
+            self.emit
+        } else {
+            na::zero()
+        }
+    }
 
-//     #[inline]
-//     fn sample(&self, u1: Scalar, u2: Scalar) -> (Point, Normal) {
-//         (na::zero(), na::zero())
-//     }
-// }
+    fn pdf_from(&self, p: &Point, ps: &Point, ns: &Normal, time: Scalar) -> Pdf {
+        let wi = *ps - *p;
+        let dist2 = wi.norm_squared();
+        if dist2 == 0.0 {
+            return 0.0;
+        }
+        // points are sampled uniformly over the shape's own
+        // surface, a stretching transform changes the area
+        // around each point by a different amount
+        let transform = self.transform.at(time);
+        let area = self.area * transform.area_scale(&transform.inverse_transform_normal(ns));
+        // convert the pdf from being with respect to
+        // surface area to being with respect to solid angle
+        let cos_light = na::dot(ns, &wi).abs() / dist2.sqrt();
+        if cos_light == 0.0 {
+            0.0
+        } else {
+            dist2 / (cos_light * area)
+        }
+    }
+}
 
-// impl ShapeEmitter for TriMesh3<Scalar> {
-//     #[inline]
-//     fn area(&self) -> Scalar {
-//         let mut area = 0.0;
-//         for idx in self.indices().iter() {
-//             let p1 = self.vertices()[idx.x];
-//             let p2 = self.vertices()[idx.y];
-//             let p3 = self.vertices()[idx.z];
-//             area = area + triangle_area(&p1, &p2, &p3);
-//         }
-//         area
-//     }
+/// A trait for designating a Shape as being an
+/// emitter for radiance.
+pub trait ShapeEmitter {
+    fn area(&self) -> Scalar;
 
-//     #[inline]
-//     fn sample(&self, u1: Scalar, u2: Scalar) -> (Point, Normal) {
-//         (na::zero(), na::zero())
-//     }
-// }
+    /// Uniformly sample a point on the surface of the shape
+    /// returning the point and the surface normal there,
+    /// both in the shape's local space.
+    fn sample(&self, u1: Scalar, u2: Scalar) -> (Point, Normal);
+}
 
-// impl ShapeEmitter for Ball3<Scalar> {
-//     #[inline]
-//     fn area(&self) -> Scalar {
-//         4.0 * consts::PI * self.radius() * self.radius()
-//     }
+impl ShapeEmitter for Triangle3<Scalar> {
+    #[inline]
+    fn area(&self) -> Scalar {
+        triangle_area(self.a(), self.b(), self.c())
+    }
 
-//     #[inline]
-//     fn sample(&self, u1: Scalar, u2: Scalar) -> (Point, Normal) {
-//         let p = na::zero() + self.radius() * uniform_sample_sphere(u1, u2);
-//         // TODO: need some way to transform a point into world space
-//         // from the object space
-//         // let n =
-//         (na::zero(), na::zero())
-//     }
-// }
+    #[inline]
+    fn sample(&self, u1: Scalar, u2: Scalar) -> (Point, Normal) {
+        sample_triangle(self.a(), self.b(), self.c(), u1, u2)
+    }
+}
 
-// impl ShapeEmitter for Cuboid3<Scalar> {
-//     #[inline]
-//     fn area(&self) -> Scalar {
-//         let he = self.half_extents();
-//         2.0 * he.x * he.y * he.z
-//     }
+impl ShapeEmitter for TriangleMesh {
+    #[inline]
+    fn area(&self) -> Scalar {
+        self.surface_area()
+    }
 
-//     #[inline]
-//     fn sample(&self, u1: Scalar, u2: Scalar) -> (Point, Normal) {
-//         (na::zero(), na::zero())
-//     }
-// }
+    fn sample(&self, u1: Scalar, u2: Scalar) -> (Point, Normal) {
+        // choose a triangle with probability proportional to its
+        // area then reuse the remapped u1 to sample the triangle
+        let (i, u1) = self.choose_triangle(u1);
+        let (a, b, c) = self.triangle(i);
+        sample_triangle(a, b, c, u1, u2)
+    }
+}
+
+impl ShapeEmitter for Ball3<Scalar> {
+    #[inline]
+    fn area(&self) -> Scalar {
+        4.0 * consts::PI * self.radius() * self.radius()
+    }
+
+    #[inline]
+    fn sample(&self, u1: Scalar, u2: Scalar) -> (Point, Normal) {
+        let n = uniform_sample_sphere(u1, u2);
+        (Point::from_coordinates(n * self.radius()), n)
+    }
+}
+
+impl ShapeEmitter for Cuboid3<Scalar> {
+    #[inline]
+    fn area(&self) -> Scalar {
+        let he = self.half_extents();
+        8.0 * (he.y * he.z + he.x * he.z + he.x * he.y)
+    }
+
+    fn sample(&self, u1: Scalar, u2: Scalar) -> (Point, Normal) {
+        let he = self.half_extents();
+        // areas of the pairs of faces perpendicular to each axis
+        let areas = [he.y * he.z, he.x * he.z, he.x * he.y];
+        let total = areas[0] + areas[1] + areas[2];
+
+        // choose an axis with probability proportional to the
+        // area of its faces then one of the two faces on it
+        let mut u = u1 * total;
+        let mut axis = 0;
+        while axis < 2 && u >= areas[axis] {
+            u = u - areas[axis];
+            axis += 1;
+        }
+        let u = Scalar::min(u / areas[axis], 1.0);
+        let (side, u) = if u < 0.5 {
+            (1.0, u * 2.0)
+        } else {
+            (-1.0, (u - 0.5) * 2.0)
+        };
+
+        let (a, b) = ((axis + 1) % 3, (axis + 2) % 3);
+        let mut p: Vector = na::zero();
+        let mut n: Normal = na::zero();
+        p[axis] = side * he[axis];
+        p[a] = (u * 2.0 - 1.0) * he[a];
+        p[b] = (u2 * 2.0 - 1.0) * he[b];
+        n[axis] = side;
+        (Point::from_coordinates(p), n)
+    }
+}
+
+fn sample_triangle(a: &Point, b: &Point, c: &Point, u1: Scalar, u2: Scalar) -> (Point, Normal) {
+    let (b0, b1) = uniform_sample_triangle(u1, u2);
+    let p = Point::from_coordinates(a.coords * b0 + b.coords * b1 + c.coords * (1.0 - b0 - b1));
+    let n = na::normalize(&(*b - *a).cross(&(*c - *a)));
+    (p, n)
+}
 
 // #[test]
 // fn test_DirectionalLight_sample() {
@@ -260,18 +541,40 @@ impl Light for DirectionalLight {
 //     assert_approx_eq!(value, na::one());
 // }
 
-// #[test]
-// fn test_area_ball() {
-//     let ball = Ball3::new(1.0);
-//     let area = ball.area();
-//     let expected = 4.0 * consts::PI;
-//     assert_approx_eq!(area, expected);
-// }
+#[test]
+fn test_area_ball() {
+    let ball = Ball3::new(1.0);
+    let area = ball.area();
+    let expected = 4.0 * consts::PI;
+    assert_approx_eq!(area, expected);
+}
 
-// #[test]
-// fn test_area_cuboid() {
-//     let cuboid = Cuboid3::new(1.0, 1.0, 1.0);
-//     let area = cuboid.area();
-//     let expected = 1.0;
-//     assert_approx_eq!(area, expected);
-// }
+#[test]
+fn test_area_cuboid() {
+    let cuboid = Cuboid3::new(Vector3::new(0.5, 0.5, 0.5));
+    let area = cuboid.area();
+    let expected = 6.0;
+    assert_approx_eq!(area, expected);
+}
+
+#[test]
+fn test_sample_cuboid_on_surface() {
+    let cuboid = Cuboid3::new(Vector3::new(1.0, 2.0, 3.0));
+    for &(u1, u2) in &[(0.0, 0.0), (0.3, 0.7), (0.6, 0.2), (0.99, 0.5)] {
+        let (p, n) = cuboid.sample(u1, u2);
+        let on_face = (p.x.abs() - 1.0).abs() < 1e-9 || (p.y.abs() - 2.0).abs() < 1e-9 ||
+                      (p.z.abs() - 3.0).abs() < 1e-9;
+        assert!(on_face);
+        assert_approx_eq!(n.norm(), 1.0);
+    }
+}
+
+#[test]
+fn test_sample_triangle_normal() {
+    let (_, n) = sample_triangle(&Point3::new(0.0, 0.0, 0.0),
+                                 &Point3::new(1.0, 0.0, 0.0),
+                                 &Point3::new(0.0, 1.0, 0.0),
+                                 0.5,
+                                 0.5);
+    assert_approx_eq!(n, Vector3::z());
+}
@@ -14,184 +14,153 @@ extern crate tobj;
 
 use std::collections::HashMap;
 use std::io::Read;
-use std::f64::consts;
 use std::fs::File;
 use std::path::Path;
-use std::sync::Arc;
-use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
 use std::thread;
 
-use na::{Isometry3, Point2, Point3, Vector3};
-use ncollide::shape::{Ball, Cuboid, TriMesh3};
-
 mod assets;
+mod bvh;
 mod bxdf;
 mod camera;
+mod film;
+mod filter;
 mod integrator;
 mod light;
 mod material;
 mod math;
+mod microfacet;
+mod mesh;
 mod montecarlo;
+mod output;
 mod parse;
 mod ray;
 mod renderer;
+mod sampler;
 mod scene;
 mod spectrum;
+mod surface;
 mod texture;
+mod tile;
+mod tonemap;
+mod transform;
 
-use camera::{Camera, PerspectiveCamera};
+use camera::Camera;
 use clap::{Arg, App};
-use integrator::{Integrator, Whitted};
-use light::{Light, PointLight};
-use material::{DiffuseMaterial, GlassMaterial, MirrorMaterial};
-use math::{Point, Scalar, Vector};
+use film::{Film, FilmTile};
+use math::Scalar;
+use output::ImageFormat;
 use parse::View;
-use rand::StdRng;
-use renderer::{Renderer, StandardRenderer};
-use scene::{Scene, SceneNode};
-use spectrum::Spectrum;
-use texture::{ConstantTexture, ImageTexture, Texture};
-
-fn load_obj(filename: &Path) -> Vec<TriMesh3<Scalar>> {
-    let obj = tobj::load_obj(filename);
-    let (models, _materials) = obj.expect("Could not load .obj");
-    let mut meshes = Vec::new();
-
-    for model in models {
-        let mesh = &model.mesh;
-
-        let indices: Vec<Point3<usize>> = (0..mesh.indices.len() / 3)
-            .map(|i| {
-                Point3::new(mesh.indices[i * 3] as usize,
-                            mesh.indices[i * 3 + 1] as usize,
-                            mesh.indices[i * 3 + 2] as usize)
-            })
-            .collect();
-
-        let vertices: Vec<Point3<Scalar>> = (0..mesh.positions.len() / 3)
-            .map(|v| {
-                Point3::new(mesh.positions[v * 3] as Scalar,
-                            mesh.positions[v * 3 + 1] as Scalar,
-                            mesh.positions[v * 3 + 2] as Scalar)
-            })
-            .collect();
+use renderer::Renderer;
+use sampler::Sampler;
+use scene::Scene;
+use tile::{TileOrder, TileQueue, generate_tiles};
+use tonemap::ToneMapOperator;
 
-        let uvs: Vec<Point2<Scalar>> = (0..mesh.texcoords.len() / 2)
-            .map(|t| {
-                Point2::new(mesh.texcoords[t * 2] as Scalar,
-                            mesh.texcoords[t * 2 + 1] as Scalar)
-            })
-            .collect();
-
-        let normals = if mesh.normals.is_empty() {
-            Some(Arc::new(indices.iter()
-                .map(|idx| {
-                    let v1 = vertices[idx.x];
-                    let v2 = vertices[idx.y];
-                    let v3 = vertices[idx.z];
-                    (v2 - v1).cross(&(v3 - v1))
-                })
-                .collect()))
-        } else {
-            Some(Arc::new((0..mesh.normals.len() / 3)
-                .map(|n| {
-                    Vector3::new(mesh.normals[n * 3] as Scalar,
-                                 mesh.normals[n * 3 + 1] as Scalar,
-                                 mesh.normals[n * 3 + 2] as Scalar)
-                })
-                .collect()))
-        };
-
-        let uvs = if uvs.is_empty() {
-            None
-        } else {
-            Some(Arc::new(uvs))
-        };
+/// The film shared by the render threads.
+///
+/// Tiles are merged in the order they were taken from the
+/// queue so that the sums for pixels shared by neighbouring
+/// tiles are the same whichever thread finished first. Tiles
+/// that finish ahead of an earlier one are held back until
+/// it has been merged.
+struct SharedFilm {
+    film: Film,
+    next: usize,
+    pending: HashMap<usize, FilmTile>,
+}
 
-        meshes.push(TriMesh3::new(Arc::new(vertices), Arc::new(indices), uvs, normals))
+impl SharedFilm {
+    fn merge_tile(&mut self, index: usize, tile: FilmTile) {
+        self.pending.insert(index, tile);
+        while let Some(tile) = self.pending.remove(&self.next) {
+            self.film.merge_tile(tile);
+            self.next += 1;
+        }
     }
-    meshes
 }
 
-fn render(width: u32,
-          height: u32,
-          nthreads: u32,
+/// How the work of rendering an image is divided up and
+/// where the samplers' streams of random values start.
+#[derive(Clone, Copy)]
+struct RenderOptions {
+    seed: u32,
+    tile_size: u32,
+    tile_order: TileOrder,
+}
+
+fn render(nthreads: u32,
           samples_per_pixel: u32,
+          options: &RenderOptions,
+          film: Film,
           camera: &Arc<Camera + Sync + Send>,
           scene: &Arc<Scene>,
-          renderer: &Arc<Renderer + Sync + Send>)
-          -> Vec<u8> {
-    let (tx, rx) = mpsc::channel();
-    // partition along the x dimension
-    let xchunk_size = width / nthreads;
-    for i in 0..nthreads {
-        let xstart = i * xchunk_size;
-        let xend = f32::min(width as f32, (xstart + xchunk_size) as f32) as u32;
+          renderer: &Arc<Renderer + Sync + Send>,
+          sampler: &(Sampler + Sync + Send))
+          -> Film {
+    let (width, height) = (film.width(), film.height());
+    let filter = film.filter().clone();
+    let tiles = generate_tiles(width, height, options.tile_size, options.tile_order);
+    let queue = Arc::new(TileQueue::new(tiles));
+    let film = Arc::new(Mutex::new(SharedFilm {
+        film: film,
+        next: 0,
+        pending: HashMap::new(),
+    }));
 
-        let tx = tx.clone();
-        let camera = camera.clone();
-        let scene = scene.clone();
-        let renderer = renderer.clone();
-        thread::spawn(move || {
-            let mut rng = StdRng::new().expect("Could not create random number generator");
-            // let rng = StdRng.from_seed();
-            for x in xstart..xend {
-                for y in 0..height {
-                    let mut c = if samples_per_pixel == 1 {
-                        let ray = camera.ray_from(x as Scalar, y as Scalar);
-                        renderer.render(&ray, &scene, &mut rng)
-                    } else {
-                        (0..samples_per_pixel)
-                            .map(|_| {
-                                // TODO: make the sampling methods into their
-                                // own trait/struct implementations for different
-                                // types of samplers to be used interchangeably
-                                let dx = rand::random::<Scalar>() - 0.5;
-                                let dy = rand::random::<Scalar>() - 0.5;
-                                let ray = camera.ray_from((x as Scalar) + dx, (y as Scalar) + dy);
-                                renderer.render(&ray, &scene, &mut rng)
-                            })
-                            .fold(na::zero(), |sum, c| sum + c)
-                    };
-                    c = c / (samples_per_pixel as Scalar);
-                    tx.send((x, y, c))
-                        .expect(&format!("Could not send Spectrum value for ({}, {})", x, y));
+    let workers: Vec<_> = (0..nthreads)
+        .map(|_| {
+            let queue = queue.clone();
+            let film = film.clone();
+            let filter = filter.clone();
+            let camera = camera.clone();
+            let scene = scene.clone();
+            let renderer = renderer.clone();
+            let mut sampler = sampler.clone_seeded(samples_per_pixel, options.seed);
+            thread::spawn(move || {
+                while let Some((index, region)) = queue.next() {
+                    let mut tile = FilmTile::new(&region, width, height, filter.clone());
+                    for y in region.y0..region.y1 {
+                        for x in region.x0..region.x1 {
+                            sampler.start_pixel(x, y);
+                            for i in 0..samples_per_pixel {
+                                sampler.start_sample(i);
+                                let (dx, dy) = sampler.get_2d();
+                                let (fx, fy) = (x as Scalar + dx, y as Scalar + dy);
+                                let l = match camera.generate_ray(fx, fy, &mut *sampler) {
+                                    Some(ray) => renderer.render(&ray, &scene, &mut *sampler),
+                                    None => na::zero(),
+                                };
+                                tile.add_sample(fx, fy, &l);
+                            }
+                        }
+                    }
+                    film.lock().expect("Film lock was poisoned").merge_tile(index, tile);
                 }
-            }
-        });
-    }
-    let mut pixel_map: HashMap<(u32, u32), Spectrum> = HashMap::with_capacity((width * height) as
-                                                                              usize);
-
-    // explicitly drop the transmission end
-    // otherwise the receiver will block indefinitely
-    drop(tx);
+            })
+        })
+        .collect();
 
-    for (x, y, c) in rx {
-        pixel_map.insert((x, y), c);
+    for worker in workers {
+        worker.join().expect("Render thread panicked");
     }
 
-    // reconstruct final image
-    let mut colours = Vec::with_capacity((width * height * 3) as usize);
-    for y in 0..height {
-        for x in 0..width {
-            let c = pixel_map.get(&(x, y)).expect(&format!("No pixel at ({}, {})", x, y));
-            // constrain rgb components to range [0, 255]
-            colours.push(na::clamp(c.x * 255.0, 0.0, 255.0) as u8);
-            colours.push(na::clamp(c.y * 255.0, 0.0, 255.0) as u8);
-            colours.push(na::clamp(c.z * 255.0, 0.0, 255.0) as u8);
-        }
-    }
-
-    colours
+    let film = match Arc::try_unwrap(film) {
+        Ok(film) => film.into_inner().expect("Film lock was poisoned"),
+        Err(_) => panic!("Film is still shared after rendering"),
+    };
+    assert!(film.pending.is_empty());
+    film.film
 }
 
-fn setup_scene<P: AsRef<Path>>(filename: P) -> (Scene, HashMap<String, View>) {
+fn setup_scene<P: AsRef<Path>>(filename: P,
+                               resolution: (Option<u32>, Option<u32>))
+                               -> (Scene, HashMap<String, View>) {
     let mut f = File::open(filename).expect("Could not open scene file.");
     let mut json_str = String::new();
     let _ = f.read_to_string(&mut json_str).expect("Failed to read file data.");
 
-    match parse::parse_scene(&json_str) {
+    match parse::parse_scene(&json_str, resolution) {
         Ok(res) => res,
         Err(err) => panic!("{}", err),
     }
@@ -225,21 +194,46 @@ fn main() {
             .short("t")
             .long("threads")
             .takes_value(true))
+        .arg(Arg::with_name("SEED")
+            .long("seed")
+            .takes_value(true))
+        .arg(Arg::with_name("TILE_SIZE")
+            .long("tile-size")
+            .takes_value(true))
+        .arg(Arg::with_name("TILE_ORDER")
+            .long("tile-order")
+            .takes_value(true)
+            .possible_values(&["spiral", "hilbert"]))
+        .arg(Arg::with_name("FORMAT")
+            .long("format")
+            .takes_value(true)
+            .possible_values(&["png", "exr", "exr-half", "exr-float", "hdr", "pfm"]))
+        .arg(Arg::with_name("EXPOSURE")
+            .long("exposure")
+            .takes_value(true)
+            .allow_hyphen_values(true))
+        .arg(Arg::with_name("TONEMAP")
+            .long("tonemap")
+            .takes_value(true)
+            .possible_values(&["clamp", "reinhard", "filmic", "aces"]))
         .get_matches();
 
-    let width = matches.value_of("WIDTH")
-        .unwrap_or("100")
-        .parse::<u32>()
-        .expect("Value for width is not a valid unsigned integer");
-    let height = matches.value_of("HEIGHT")
-        .unwrap_or("100")
-        .parse::<u32>()
-        .expect("Value for height is not a valid unsigned integer");
-    let samples = matches.value_of("SAMPLES")
-        .unwrap_or("3")
-        .parse::<u32>()
-        .expect("Value for samples is not a valid unsigned integer");
-    assert!(samples > 0);
+    // override the resolution given for each camera
+    let width = matches.value_of("WIDTH").map(|width| {
+        width.parse::<u32>()
+            .expect("Value for width is not a valid unsigned integer")
+    });
+    let height = matches.value_of("HEIGHT").map(|height| {
+        height.parse::<u32>()
+            .expect("Value for height is not a valid unsigned integer")
+    });
+    assert!(width != Some(0) && height != Some(0));
+
+    // overrides the number of samples given for each view
+    let samples = matches.value_of("SAMPLES").map(|samples| {
+        samples.parse::<u32>()
+            .expect("Value for samples is not a valid unsigned integer")
+    });
     let depth = matches.value_of("DEPTH")
         .unwrap_or("6")
         .parse::<i32>()
@@ -249,25 +243,64 @@ fn main() {
         .parse::<u32>()
         .expect("Value for threads is not a valid unsigned integer");
     assert!(nthreads > 0);
+    let seed = matches.value_of("SEED")
+        .unwrap_or("0")
+        .parse::<u32>()
+        .expect("Value for seed is not a valid unsigned integer");
+    let tile_size = matches.value_of("TILE_SIZE")
+        .unwrap_or("16")
+        .parse::<u32>()
+        .expect("Value for tile size is not a valid unsigned integer");
+    assert!(tile_size > 0);
+    let tile_order = TileOrder::from_name(matches.value_of("TILE_ORDER").unwrap_or("spiral"))
+        .expect("Value for tile order is not a valid tile order");
+    let options = RenderOptions {
+        seed: seed,
+        tile_size: tile_size,
+        tile_order: tile_order,
+    };
+
+    // override the tone mapping given for each view
+    let exposure = matches.value_of("EXPOSURE").map(|exposure| {
+        exposure.parse::<Scalar>()
+            .expect("Value for exposure is not a valid number")
+    });
+    let operator = matches.value_of("TONEMAP").map(|operator| {
+        ToneMapOperator::from_name(operator).expect("Value for tonemap is not a valid operator")
+    });
 
     let scene_filename = matches.value_of("SCENE").unwrap();
 
-    let (scene, views) = setup_scene(&scene_filename);
+    let (scene, views) = setup_scene(&scene_filename, (width, height));
     let scene = Arc::new(scene);
 
     for (name, view) in &views {
-        let colours = render(view.camera.width(),
-                             view.camera.height(),
-                             nthreads,
-                             samples,
-                             //  view.samples,
-                             &view.camera,
-                             &scene,
-                             &view.renderer);
-        let filename = matches.value_of("OUTPUT").unwrap_or(name);
-        let out = &mut File::create(&Path::new(filename)).expect("Could not create image file");
-        let img = image::ImageBuffer::from_raw(width, height, colours)
-            .expect("Could not create image buffer");
-        let _ = image::ImageRgb8(img).save(out, image::PNG);
+        let samples = samples.unwrap_or(view.samples);
+        assert!(samples > 0);
+        let film = Film::new(view.camera.width(), view.camera.height(), view.filter.clone());
+        let film = render(nthreads,
+                          samples,
+                          &options,
+                          film,
+                          &view.camera,
+                          &scene,
+                          &view.renderer,
+                          &*view.sampler);
+        let filename = Path::new(matches.value_of("OUTPUT").unwrap_or(name));
+        // an explicit format takes precedence over the file extension
+        let format = matches.value_of("FORMAT")
+            .and_then(ImageFormat::from_name)
+            .or_else(|| ImageFormat::from_path(filename))
+            .unwrap_or(ImageFormat::Png);
+        let mut tonemap = view.tonemap;
+        tonemap.exposure = exposure.unwrap_or(tonemap.exposure);
+        tonemap.operator = operator.unwrap_or(tonemap.operator);
+        output::write_image(filename,
+                            format,
+                            film.width(),
+                            film.height(),
+                            &film.image(1.0 / samples as Scalar),
+                            &tonemap)
+            .expect("Could not write image file");
     }
 }
@@ -1,55 +1,169 @@
 
+use std::sync::Arc;
+
 use na;
 use na::Point2;
 
-use bxdf::{BSDF, Lambertian, FresnelConductor, FresnelDielectric, SpecularReflection,
-           SpecularTransmission};
-use math::Normal;
-use spectrum::Spectrum;
+use bxdf::{BSDF, BxDF, Coated, Lambertian, FresnelConductor, FresnelDielectric,
+           FresnelThinDielectric, MicrofacetReflection, MicrofacetTransmission, OrenNayar,
+           SpecularReflection, SpecularTransmission, ThinSpecularTransmission};
+use math::{Scalar, Vector};
+use microfacet::{TrowbridgeReitz, roughness_to_alpha};
+use spectrum::{Spectrum, luminance};
+use surface::SurfaceGeometry;
 use texture::Texture;
 
+/// The roughness of a glossy surface in [0, 1] along dp/du
+/// and dp/dv, given by the luminance of the textures, which
+/// are shared when the roughness is the same along both.
+pub type Roughness = (Arc<Texture + Sync + Send>, Arc<Texture + Sync + Send>);
+
 pub trait Material {
-    fn get_bsdf(&self, normal: &Normal, uvs: &Option<Point2<f64>>) -> BSDF;
+    /// The geometry to shade the surface with, which materials
+    /// with normal or bump maps perturb. This is found before
+    /// the BSDF, which is given the geometry it returns.
+    fn shading_geometry(&self, geometry: SurfaceGeometry) -> SurfaceGeometry {
+        geometry
+    }
+
+    fn get_bsdf(&self, geometry: &SurfaceGeometry) -> BSDF;
+
+    /// The fraction of light left after travelling a distance
+    /// through the inside of the material, for materials
+    /// whose volume absorbs light.
+    fn transmittance(&self, _: Scalar) -> Spectrum {
+        Spectrum::new(1.0, 1.0, 1.0)
+    }
 }
 
 pub struct DiffuseMaterial {
     pub texture: Box<Texture + Sync + Send>,
+    /// The standard deviation of the angle of the surface's
+    /// facets in radians, given by the luminance of the
+    /// texture. Without it the surface is Lambertian.
+    pub roughness: Option<Box<Texture + Sync + Send>>,
 }
 
 impl DiffuseMaterial {
     pub fn new(texture: Box<Texture + Sync + Send>) -> DiffuseMaterial {
-        DiffuseMaterial { texture: texture }
+        DiffuseMaterial {
+            texture: texture,
+            roughness: None,
+        }
+    }
+
+    pub fn new_with_roughness(texture: Box<Texture + Sync + Send>,
+                              roughness: Box<Texture + Sync + Send>)
+                              -> DiffuseMaterial {
+        DiffuseMaterial {
+            texture: texture,
+            roughness: Some(roughness),
+        }
     }
 }
 
 impl Material for DiffuseMaterial {
-    fn get_bsdf(&self, normal: &Normal, uvs: &Option<Point2<f64>>) -> BSDF {
-        let mut bsdf = BSDF::new(*normal);
-        let f = self.texture.sample(uvs);
-        bsdf.add_bxdf(Box::new(Lambertian::new(f)));
+    fn get_bsdf(&self, geometry: &SurfaceGeometry) -> BSDF {
+        let mut bsdf = BSDF::new(geometry);
+        let f = self.texture.sample(&geometry.uv);
+        let sigma = match self.roughness {
+            Some(ref roughness) => luminance(&roughness.sample(&geometry.uv)),
+            None => 0.0,
+        };
+        if sigma > 0.0 {
+            bsdf.add_bxdf(Box::new(OrenNayar::new(f, sigma)));
+        } else {
+            bsdf.add_bxdf(Box::new(Lambertian::new(f)));
+        }
         bsdf
     }
 }
 
-pub struct GlassMaterial;
+/// A smooth or rough dielectric, such as glass or water, that
+/// both reflects and refracts light. The outside of its surface
+/// is assumed to be air with a refractive index of 1.
+pub struct GlassMaterial {
+    pub ior: Scalar,
+    /// Tints the light reflected from the surface.
+    pub reflectance: Spectrum,
+    /// Tints the light refracted through the surface.
+    pub transmittance: Spectrum,
+    /// Frosted glass is rough.
+    pub roughness: Option<Roughness>,
+    /// The absorption coefficient of the glass, the rate per
+    /// unit distance at which light is absorbed inside it, so
+    /// that exp(-absorption * distance) of it passes through
+    /// following the Beer-Lambert law.
+    pub absorption: Option<Spectrum>,
+    /// Thin glass, such as a window pane, is treated as a slab
+    /// with two surfaces so close together that light passes
+    /// straight through it. Thin glass is always smooth and
+    /// doesn't absorb light.
+    pub thin: bool,
+}
+
+impl GlassMaterial {
+    /// Create clear, smooth glass with the given refractive index.
+    pub fn new(ior: Scalar) -> GlassMaterial {
+        GlassMaterial {
+            ior: ior,
+            reflectance: Spectrum::new(1.0, 1.0, 1.0),
+            transmittance: Spectrum::new(1.0, 1.0, 1.0),
+            roughness: None,
+            absorption: None,
+            thin: false,
+        }
+    }
+}
 
 impl Material for GlassMaterial {
-    fn get_bsdf(&self, normal: &Normal, _: &Option<Point2<f64>>) -> BSDF {
-        let mut bsdf = BSDF::new(*normal);
-        // refractive index for glass is 1.5
-        bsdf.add_bxdf(Box::new(SpecularTransmission::new(Spectrum::new(1.0, 1.0, 1.0), 1.0, 1.5)));
-        bsdf.add_bxdf(Box::new(SpecularReflection::new(Spectrum::new(1.0, 1.0, 1.0),
-                                                       Box::new(FresnelDielectric::new(1.0,
-                                                                                       1.5)))));
+    fn get_bsdf(&self, geometry: &SurfaceGeometry) -> BSDF {
+        let mut bsdf = BSDF::new(geometry);
+        let (r, t) = (self.reflectance, self.transmittance);
+        if self.thin {
+            let fresnel = Box::new(FresnelThinDielectric::new(1.0, self.ior));
+            bsdf.add_bxdf(Box::new(ThinSpecularTransmission::new(t, 1.0, self.ior)));
+            bsdf.add_bxdf(Box::new(SpecularReflection::new(r, fresnel)));
+            return bsdf;
+        }
+
+        let fresnel = Box::new(FresnelDielectric::new(1.0, self.ior));
+        let roughness = sample_roughness(&self.roughness, geometry);
+        match roughness {
+            Some((u, v)) if u > 0.0 || v > 0.0 => {
+                let distribution = || {
+                    Box::new(TrowbridgeReitz::new(roughness_to_alpha(u), roughness_to_alpha(v)))
+                };
+                bsdf.add_bxdf(Box::new(MicrofacetTransmission::new(t,
+                                                                   distribution(),
+                                                                   1.0,
+                                                                   self.ior)));
+                bsdf.add_bxdf(Box::new(MicrofacetReflection::new(r, distribution(), fresnel)));
+            }
+            _ => {
+                bsdf.add_bxdf(Box::new(SpecularTransmission::new(t, 1.0, self.ior)));
+                bsdf.add_bxdf(Box::new(SpecularReflection::new(r, fresnel)));
+            }
+        }
         bsdf
     }
+
+    fn transmittance(&self, distance: Scalar) -> Spectrum {
+        match self.absorption {
+            Some(absorption) if !self.thin => {
+                let optical_depth = absorption * -distance;
+                Spectrum::new(optical_depth.x.exp(), optical_depth.y.exp(), optical_depth.z.exp())
+            }
+            _ => Spectrum::new(1.0, 1.0, 1.0),
+        }
+    }
 }
 
 pub struct MirrorMaterial;
 
 impl Material for MirrorMaterial {
-    fn get_bsdf(&self, normal: &Normal, _: &Option<Point2<f64>>) -> BSDF {
-        let mut bsdf = BSDF::new(*normal);
+    fn get_bsdf(&self, geometry: &SurfaceGeometry) -> BSDF {
+        let mut bsdf = BSDF::new(geometry);
         bsdf.add_bxdf(Box::new(
             SpecularReflection::new(
                 Spectrum::new(1.0, 1.0, 1.0),
@@ -58,3 +172,363 @@ impl Material for MirrorMaterial {
         bsdf
     }
 }
+
+/// The complex refractive index (eta and k) of a named metal
+/// at the red, green and blue wavelengths, for use with
+/// `MetalMaterial`.
+pub fn conductor_preset(name: &str) -> Option<(Spectrum, Spectrum)> {
+    match name {
+        "gold" => {
+            Some((Spectrum::new(0.143119, 0.374957, 1.44248),
+                  Spectrum::new(3.98316, 2.38572, 1.60322)))
+        }
+        "silver" => {
+            Some((Spectrum::new(0.155265, 0.116723, 0.138342),
+                  Spectrum::new(4.82835, 3.12225, 2.14696)))
+        }
+        "copper" => {
+            Some((Spectrum::new(0.200438, 0.924033, 1.10221),
+                  Spectrum::new(3.91295, 2.45285, 2.14219)))
+        }
+        "aluminium" => {
+            Some((Spectrum::new(1.65746, 0.880369, 0.521229),
+                  Spectrum::new(9.22387, 6.26952, 4.837)))
+        }
+        "chrome" => {
+            Some((Spectrum::new(4.36968, 2.9167, 1.6547),
+                  Spectrum::new(5.20643, 4.23136, 3.75495)))
+        }
+        _ => None,
+    }
+}
+
+/// A conductor reflecting light by the Fresnel equations for
+/// its complex refractive index, either as a perfect mirror or
+/// glossily from microfacets when it has a roughness.
+pub struct MetalMaterial {
+    eta: Spectrum,
+    k: Spectrum,
+    /// Without a roughness the metal is a perfect mirror.
+    roughness: Option<Roughness>,
+}
+
+impl MetalMaterial {
+    pub fn new(eta: Spectrum, k: Spectrum) -> MetalMaterial {
+        MetalMaterial {
+            eta: eta,
+            k: k,
+            roughness: None,
+        }
+    }
+
+    pub fn new_with_roughness(eta: Spectrum,
+                              k: Spectrum,
+                              roughness_u: Arc<Texture + Sync + Send>,
+                              roughness_v: Arc<Texture + Sync + Send>)
+                              -> MetalMaterial {
+        MetalMaterial {
+            eta: eta,
+            k: k,
+            roughness: Some((roughness_u, roughness_v)),
+        }
+    }
+}
+
+impl Material for MetalMaterial {
+    fn get_bsdf(&self, geometry: &SurfaceGeometry) -> BSDF {
+        let mut bsdf = BSDF::new(geometry);
+        let white = Spectrum::new(1.0, 1.0, 1.0);
+        let fresnel = Box::new(FresnelConductor::new(self.eta, self.k));
+        let roughness = sample_roughness(&self.roughness, geometry);
+        match roughness {
+            Some((u, v)) if u > 0.0 || v > 0.0 => {
+                let distribution = TrowbridgeReitz::new(roughness_to_alpha(u),
+                                                        roughness_to_alpha(v));
+                bsdf.add_bxdf(Box::new(MicrofacetReflection::new(white,
+                                                                 Box::new(distribution),
+                                                                 fresnel)));
+            }
+            _ => bsdf.add_bxdf(Box::new(SpecularReflection::new(white, fresnel))),
+        }
+        bsdf
+    }
+}
+
+/// Coat the surface a BSDF describes with a smooth or rough
+/// dielectric layer, which reflects light tinted by `specular`
+/// and passes the rest on to the BxDFs already in the BSDF.
+fn coat(bsdf: &mut BSDF, specular: Spectrum, ior: Scalar, roughness: Option<(Scalar, Scalar)>) {
+    bsdf.map_bxdfs(|bxdf| Box::new(Coated::new(bxdf, 1.0, ior)) as Box<BxDF>);
+    let fresnel = Box::new(FresnelDielectric::new(1.0, ior));
+    match roughness {
+        Some((u, v)) if u > 0.0 || v > 0.0 => {
+            let distribution = TrowbridgeReitz::new(roughness_to_alpha(u), roughness_to_alpha(v));
+            bsdf.add_bxdf(Box::new(MicrofacetReflection::new(specular,
+                                                             Box::new(distribution),
+                                                             fresnel)));
+        }
+        _ => bsdf.add_bxdf(Box::new(SpecularReflection::new(specular, fresnel))),
+    }
+}
+
+/// Sample the roughness along dp/du and dp/dv of a glossy surface.
+fn sample_roughness(roughness: &Option<Roughness>,
+                    geometry: &SurfaceGeometry)
+                    -> Option<(Scalar, Scalar)> {
+    roughness.as_ref().map(|&(ref u, ref v)| {
+        (luminance(&u.sample(&geometry.uv)), luminance(&v.sample(&geometry.uv)))
+    })
+}
+
+/// Glossy plastic, a diffuse base under a clear dielectric coat.
+pub struct PlasticMaterial {
+    pub texture: Box<Texture + Sync + Send>,
+    /// Tints the light reflected from the coat.
+    pub specular: Spectrum,
+    pub ior: Scalar,
+    /// The coat is smooth without a roughness.
+    pub roughness: Option<Roughness>,
+}
+
+impl PlasticMaterial {
+    /// Create smooth plastic with a white coat
+    /// with a refractive index of 1.5.
+    pub fn new(texture: Box<Texture + Sync + Send>) -> PlasticMaterial {
+        PlasticMaterial {
+            texture: texture,
+            specular: Spectrum::new(1.0, 1.0, 1.0),
+            ior: 1.5,
+            roughness: None,
+        }
+    }
+}
+
+impl Material for PlasticMaterial {
+    fn get_bsdf(&self, geometry: &SurfaceGeometry) -> BSDF {
+        let mut bsdf = BSDF::new(geometry);
+        bsdf.add_bxdf(Box::new(Lambertian::new(self.texture.sample(&geometry.uv))));
+        coat(&mut bsdf,
+             self.specular,
+             self.ior,
+             sample_roughness(&self.roughness, geometry));
+        bsdf
+    }
+}
+
+/// Any material under a clear dielectric coat.
+pub struct CoatedMaterial {
+    pub base: Arc<Material + Sync + Send>,
+    /// Tints the light reflected from the coat.
+    pub specular: Spectrum,
+    pub ior: Scalar,
+    /// The coat is smooth without a roughness.
+    pub roughness: Option<Roughness>,
+}
+
+impl CoatedMaterial {
+    /// Create a smooth white coat with a
+    /// refractive index of 1.5.
+    pub fn new(base: Arc<Material + Sync + Send>) -> CoatedMaterial {
+        CoatedMaterial {
+            base: base,
+            specular: Spectrum::new(1.0, 1.0, 1.0),
+            ior: 1.5,
+            roughness: None,
+        }
+    }
+}
+
+impl Material for CoatedMaterial {
+    fn shading_geometry(&self, geometry: SurfaceGeometry) -> SurfaceGeometry {
+        self.base.shading_geometry(geometry)
+    }
+
+    fn get_bsdf(&self, geometry: &SurfaceGeometry) -> BSDF {
+        let mut bsdf = self.base.get_bsdf(geometry);
+        coat(&mut bsdf,
+             self.specular,
+             self.ior,
+             sample_roughness(&self.roughness, geometry));
+        bsdf
+    }
+
+    fn transmittance(&self, distance: Scalar) -> Spectrum {
+        self.base.transmittance(distance)
+    }
+}
+
+/// A texture that changes the shading normal of a surface.
+pub enum ShadingMap {
+    /// A tangent space normal map, with the red, green and blue
+    /// channels mapped from [0, 1] to [-1, 1] along dp/du, dp/dv
+    /// and the shading normal respectively.
+    Normal(Box<Texture + Sync + Send>),
+    /// A height map given by the luminance of the texture
+    /// multiplied by the scale, in the units of the surface's
+    /// texture coordinates.
+    Bump(Box<Texture + Sync + Send>, Scalar),
+}
+
+impl ShadingMap {
+    /// Perturb the shading normal of the geometry, leaving it
+    /// unchanged where the surface has no texture coordinates.
+    pub fn apply(&self, geometry: SurfaceGeometry) -> SurfaceGeometry {
+        let uv = match geometry.uv {
+            Some(uv) => uv,
+            None => return geometry,
+        };
+        let ns = geometry.shading_normal;
+        let (normal, dpdu, dpdv) = match *self {
+            ShadingMap::Normal(ref texture) => {
+                let m = texture.sample(&geometry.uv) * 2.0 - Vector::from_element(1.0);
+                let tangent = na::normalize(&(geometry.dpdu -
+                                              ns * na::dot(&geometry.dpdu, &ns)));
+                // follow dp/dv where the texture coordinates are mirrored
+                let bitangent = if na::dot(&ns.cross(&tangent), &geometry.dpdv) < 0.0 {
+                    tangent.cross(&ns)
+                } else {
+                    ns.cross(&tangent)
+                };
+                let normal = tangent * m.x + bitangent * m.y + ns * m.z;
+                if normal == na::zero() {
+                    return geometry;
+                }
+                (na::normalize(&normal), geometry.dpdu, geometry.dpdv)
+            }
+            ShadingMap::Bump(ref texture, scale) => {
+                let (du, dv) = texture.texel_size();
+                let height = |u: Scalar, v: Scalar| {
+                    luminance(&texture.sample(&Some(Point2::new(u, v)))) * scale
+                };
+                let displace = height(uv.x, uv.y);
+                let u_displace = height(uv.x + du, uv.y);
+                let v_displace = height(uv.x, uv.y + dv);
+                // move the surface along its normal by the height, ignoring
+                // the change in the normal itself which is usually small
+                let dpdu = geometry.dpdu + ns * ((u_displace - displace) / du);
+                let dpdv = geometry.dpdv + ns * ((v_displace - displace) / dv);
+                let normal = dpdu.cross(&dpdv);
+                if normal == na::zero() {
+                    return geometry;
+                }
+                let normal = na::normalize(&normal);
+                let normal = if na::dot(&normal, &ns) < 0.0 {
+                    -normal
+                } else {
+                    normal
+                };
+                (normal, dpdu, dpdv)
+            }
+        };
+        SurfaceGeometry {
+            shading_normal: normal,
+            dpdu: dpdu - normal * na::dot(&dpdu, &normal),
+            dpdv: dpdv - normal * na::dot(&dpdv, &normal),
+            ..geometry
+        }
+    }
+}
+
+/// A material whose shading normal is perturbed by a
+/// normal or bump map before it is shaded. Any maps of
+/// the wrapped material are applied before this one.
+pub struct MappedMaterial {
+    material: Arc<Material + Sync + Send>,
+    map: ShadingMap,
+}
+
+impl MappedMaterial {
+    pub fn new(material: Arc<Material + Sync + Send>, map: ShadingMap) -> MappedMaterial {
+        MappedMaterial {
+            material: material,
+            map: map,
+        }
+    }
+}
+
+impl Material for MappedMaterial {
+    fn shading_geometry(&self, geometry: SurfaceGeometry) -> SurfaceGeometry {
+        self.map.apply(self.material.shading_geometry(geometry))
+    }
+
+    fn get_bsdf(&self, geometry: &SurfaceGeometry) -> BSDF {
+        self.material.get_bsdf(geometry)
+    }
+
+    fn transmittance(&self, distance: Scalar) -> Spectrum {
+        self.material.transmittance(distance)
+    }
+}
+
+#[test]
+fn test_flat_shading_maps_keep_normal() {
+    use math::Point;
+    use texture::ConstantTexture;
+
+    let n = Vector::new(0.0, 0.0, 1.0);
+    let geometry = SurfaceGeometry::new(Point::new(0.0, 0.0, 0.0),
+                                        n,
+                                        Vector::new(1.0, 0.0, 0.0),
+                                        Vector::new(0.0, 1.0, 0.0),
+                                        Some(Point2::new(0.5, 0.5)));
+    let flat = Box::new(ConstantTexture::new(Spectrum::new(0.5, 0.5, 1.0)));
+    let mapped = ShadingMap::Normal(flat).apply(geometry.clone());
+    assert_approx_eq!(mapped.shading_normal, n);
+    let level = Box::new(ConstantTexture::new(Spectrum::new(0.2, 0.2, 0.2)));
+    let bumped = ShadingMap::Bump(level, 1.0).apply(geometry.clone());
+    assert_approx_eq!(bumped.shading_normal, n);
+
+    // tilting the normal map towards red tilts the normal along dp/du
+    let tilted = Box::new(ConstantTexture::new(Spectrum::new(1.0, 0.5, 1.0)));
+    let mapped = ShadingMap::Normal(tilted).apply(geometry);
+    assert!(mapped.shading_normal.x > 0.0);
+    assert_approx_eq!(mapped.shading_normal.y, 0.0);
+    assert_approx_eq!(mapped.normal, n);
+}
+
+#[test]
+fn test_inner_shading_map_applies_first() {
+    use std::f64::consts;
+    use math::Point;
+    use texture::ConstantTexture;
+
+    /// A height rising along v.
+    struct Ramp;
+
+    impl Texture for Ramp {
+        fn sample(&self, uv: &Option<Point2<Scalar>>) -> Spectrum {
+            Spectrum::from_element(uv.map_or(0.0, |uv| uv.y))
+        }
+    }
+
+    let geometry = SurfaceGeometry::new(Point::new(0.0, 0.0, 0.0),
+                                        Vector::new(0.0, 0.0, 1.0),
+                                        Vector::new(1.0, 0.0, 0.0),
+                                        Vector::new(0.0, 1.0, 0.0),
+                                        Some(Point2::new(0.5, 0.5)));
+    // wrapped as the parser does, the bump map tilts the normal
+    // towards -y and then the normal map tilts that towards +x
+    let white = Box::new(ConstantTexture::new(Spectrum::new(1.0, 1.0, 1.0)));
+    let base = Arc::new(DiffuseMaterial::new(white));
+    let bumped = Arc::new(MappedMaterial::new(base, ShadingMap::Bump(Box::new(Ramp), 1.0)));
+    let tilted = Box::new(ConstantTexture::new(Spectrum::new(1.0, 0.5, 1.0)));
+    let mapped = MappedMaterial::new(bumped, ShadingMap::Normal(tilted));
+    let n = mapped.shading_geometry(geometry).shading_normal;
+    assert_approx_eq!(n, Vector::new(consts::FRAC_1_SQRT_2, -0.5, 0.5));
+}
+
+#[test]
+fn test_conductor_presets() {
+    use bxdf::Fresnel;
+
+    let (eta, k) = conductor_preset("gold").unwrap();
+    let gold = FresnelConductor::new(eta, k).evaluate(1.0);
+    // gold reflects red light far more than blue
+    assert!(gold.x > 0.9 && gold.z < 0.5);
+    for name in &["silver", "copper", "aluminium", "chrome"] {
+        let (eta, k) = conductor_preset(name).unwrap();
+        let r = FresnelConductor::new(eta, k).evaluate(1.0);
+        assert!(r.x > 0.5 && r.x <= 1.0);
+    }
+    assert!(conductor_preset("unobtainium").is_none());
+}
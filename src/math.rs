@@ -3,7 +3,7 @@ use std::f64;
 use std::f64::consts;
 
 use na;
-use na::{Point3, Vector3};
+use na::{Isometry3, Point3, Vector3};
 
 pub use na::dot;
 
@@ -13,6 +13,19 @@ pub type Point = Point3<Scalar>;
 pub type Vector = Vector3<Scalar>;
 pub type Normal = Vector;
 
+/// The transform that places an object at `eye` facing
+/// `target`, with its local -Z axis pointing at the target
+/// and its local +Y axis as close to `up` as possible.
+///
+/// This follows the right-handed convention used for all
+/// transforms, where cameras look down their local -Z axis
+/// with +X to the right of the image and +Y up.
+pub fn look_at(eye: &Point, target: &Point, up: &Vector) -> Isometry3<Scalar> {
+    // look_at_rh gives the view transform, which is the
+    // inverse of the transform of the object itself
+    Isometry3::look_at_rh(eye, target, up).inverse()
+}
+
 pub fn uniform_sample_sphere(u1: Scalar, u2: Scalar) -> Vector {
     let z = 1.0 - 2.0 * u1;
     let r = f64::max(0.0, 1.0 - z * z).sqrt();
@@ -48,6 +61,68 @@ pub fn reflect(v: &Vector, n: &Normal) -> Vector {
     reflected
 }
 
+/// Refract the vector `v` pointing away from the surface through
+/// the normal `n` on the same side, where `eta` is the ratio of
+/// the refractive index on `v`'s side to the index on the other.
+/// Returns None when the vector is totally internally reflected.
+pub fn refract(v: &Vector, n: &Normal, eta: Scalar) -> Option<Vector> {
+    let cos_i = na::dot(n, v);
+    let sin2_i = f64::max(0.0, 1.0 - cos_i * cos_i);
+    let sin2_t = eta * eta * sin2_i;
+    if sin2_t >= 1.0 {
+        return None;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    Some(-*v * eta + *n * (eta * cos_i - cos_t))
+}
+
+/// An approximation of the error function, with a
+/// maximum error of 1.5e-7.
+pub fn erf(x: Scalar) -> Scalar {
+    let (a1, a2, a3, a4, a5) = (0.254829592, -0.284496736, 1.421413741, -1.453152027, 1.061405429);
+    let p = 0.3275911;
+    let sign = x.signum();
+    let x = x.abs();
+    let t = 1.0 / (1.0 + p * x);
+    let y = 1.0 - (((((a5 * t + a4) * t) + a3) * t + a2) * t + a1) * t * (-x * x).exp();
+    sign * y
+}
+
+/// An approximation of the inverse of the error function
+/// using Giles' polynomial fit.
+pub fn erf_inv(x: Scalar) -> Scalar {
+    let x = x.clamp(-0.99999, 0.99999);
+    let w = -((1.0 - x) * (1.0 + x)).ln();
+    let p = if w < 5.0 {
+        let w = w - 2.5;
+        [2.81022636e-08,
+         3.43273939e-07,
+         -3.5233877e-06,
+         -4.39150654e-06,
+         0.00021858087,
+         -0.00125372503,
+         -0.00417768164,
+         0.246640727,
+         1.50140941]
+            .iter()
+            .fold(0.0, |p, c| c + p * w)
+    } else {
+        let w = w.sqrt() - 3.0;
+        [-0.000200214257,
+         0.000100950558,
+         0.00134934322,
+         -0.00367342844,
+         0.00573950773,
+         -0.0076224613,
+         0.00943887047,
+         1.00167406,
+         2.83297682]
+            .iter()
+            .fold(0.0, |p, c| c + p * w)
+    };
+    p * x
+}
+
 pub trait Clamp {
     fn clamp(&self, min: Self, max: Self) -> Self;
 }
@@ -73,6 +148,13 @@ fn test_unit_y() {
     assert_approx_eq!(vz, -Vector3::z());
 }
 
+#[test]
+fn test_erf_inv() {
+    for &x in &[-0.9, -0.5, 0.0, 0.3, 0.8] {
+        assert!((erf(erf_inv(x)) - x).abs() < 1e-5);
+    }
+}
+
 #[test]
 fn test_clamp_min_f64() {
     let x = -2.0f64.clamp(-1.0, 1.0);
@@ -0,0 +1,325 @@
+use na;
+use na::{Isometry3, Point2};
+use ncollide::bounding_volume;
+use ncollide::bounding_volume::AABB3;
+use ncollide::query::Ray3;
+
+use bvh::BVH;
+use math::{Normal, Point, Scalar, Vector};
+use montecarlo::Distribution1D;
+use surface::{Surface, SurfaceGeometry};
+
+/// A mesh of triangles with its own bounding volume
+/// hierarchy over them, so a ray only tests the few
+/// triangles near its path.
+///
+/// Vertex normals and texture coordinates are interpolated
+/// across each triangle when it is intersected. Triangles
+/// are front facing when their vertices are counter-clockwise.
+pub struct TriangleMesh {
+    vertices: Vec<Point>,
+    indices: Vec<[usize; 3]>,
+    normals: Option<Vec<Normal>>,
+    uvs: Option<Vec<Point2<Scalar>>>,
+    bvh: BVH<usize>,
+    aabb: AABB3<Scalar>,
+    /// Chooses triangles in proportion to their area.
+    areas: Distribution1D,
+}
+
+impl TriangleMesh {
+    /// Create a mesh from triangles given by the indices of
+    /// their vertices. The normals and texture coordinates,
+    /// if given, are per vertex.
+    pub fn new(vertices: Vec<Point>,
+               indices: Vec<[usize; 3]>,
+               normals: Option<Vec<Normal>>,
+               uvs: Option<Vec<Point2<Scalar>>>)
+               -> TriangleMesh {
+        assert!(!indices.is_empty(), "A mesh needs at least one triangle");
+        if let Some(ref normals) = normals {
+            assert_eq!(normals.len(), vertices.len());
+        }
+        if let Some(ref uvs) = uvs {
+            assert_eq!(uvs.len(), vertices.len());
+        }
+
+        let triangles = indices.iter()
+            .enumerate()
+            .map(|(i, idx)| {
+                let corners = [vertices[idx[0]], vertices[idx[1]], vertices[idx[2]]];
+                let (mins, maxs) = bounding_volume::point_cloud_aabb(&Isometry3::identity(),
+                                                                     &corners[..]);
+                (i, AABB3::new(mins, maxs))
+            })
+            .collect();
+        let (mins, maxs) = bounding_volume::point_cloud_aabb(&Isometry3::identity(),
+                                                             &vertices[..]);
+        let areas: Vec<Scalar> = indices.iter()
+            .map(|idx| {
+                let (a, b, c) = (&vertices[idx[0]], &vertices[idx[1]], &vertices[idx[2]]);
+                na::norm(&(*b - *a).cross(&(*c - *a))) * 0.5
+            })
+            .collect();
+
+        TriangleMesh {
+            bvh: BVH::new(triangles),
+            aabb: AABB3::new(mins, maxs),
+            areas: Distribution1D::new(&areas),
+            vertices: vertices,
+            indices: indices,
+            normals: normals,
+            uvs: uvs,
+        }
+    }
+
+    #[inline]
+    pub fn vertices(&self) -> &[Point] {
+        &self.vertices
+    }
+
+    #[inline]
+    pub fn indices(&self) -> &[[usize; 3]] {
+        &self.indices
+    }
+
+    #[inline]
+    pub fn normals(&self) -> Option<&[Normal]> {
+        self.normals.as_ref().map(|n| &n[..])
+    }
+
+    #[inline]
+    pub fn uvs(&self) -> Option<&[Point2<Scalar>]> {
+        self.uvs.as_ref().map(|uvs| &uvs[..])
+    }
+
+    #[inline]
+    pub fn aabb(&self) -> &AABB3<Scalar> {
+        &self.aabb
+    }
+
+    #[inline]
+    pub fn surface_area(&self) -> Scalar {
+        self.areas.integral() * self.areas.count() as Scalar
+    }
+
+    /// The corners of a triangle.
+    #[inline]
+    pub fn triangle(&self, i: usize) -> (&Point, &Point, &Point) {
+        let idx = &self.indices[i];
+        (&self.vertices[idx[0]], &self.vertices[idx[1]], &self.vertices[idx[2]])
+    }
+
+    /// Choose a triangle with probability proportional to its
+    /// area, returning its index and u remapped to [0, 1) so
+    /// that it can be used again.
+    pub fn choose_triangle(&self, u: Scalar) -> (usize, Scalar) {
+        let (x, _, i) = self.areas.sample_continuous(u);
+        let remapped = x * self.areas.count() as Scalar - i as Scalar;
+        (i, Scalar::min(remapped, 1.0))
+    }
+
+    /// Give a mesh without normals vertex normals averaged
+    /// from the faces around each vertex, weighted by area,
+    /// so that it is shaded smoothly.
+    pub fn smooth_normals(&mut self) {
+        if self.normals.is_some() {
+            return;
+        }
+        let mut normals = vec![na::zero::<Vector>(); self.vertices.len()];
+        for (i, idx) in self.indices.iter().enumerate() {
+            let (a, b, c) = self.triangle(i);
+            // the length of the cross product is twice the
+            // triangle's area which gives the weighting
+            let n = (*b - *a).cross(&(*c - *a));
+            for &v in idx {
+                normals[v] = normals[v] + n;
+            }
+        }
+        self.normals = Some(normals.into_iter()
+            .map(|n| if n == na::zero() { n } else { na::normalize(&n) })
+            .collect());
+    }
+
+    /// Find where the ray hits a triangle returning the distance
+    /// along the ray and the barycentric coordinates of the
+    /// second and third vertices.
+    fn intersect_triangle(&self, i: usize, ray: &Ray3<Scalar>) -> Option<(Scalar, Scalar, Scalar)> {
+        let (p0, p1, p2) = self.triangle(i);
+        let e1 = *p1 - *p0;
+        let e2 = *p2 - *p0;
+        let pvec = ray.dir.cross(&e2);
+        let det = na::dot(&e1, &pvec);
+        if det == 0.0 {
+            // the ray is parallel to the triangle
+            return None;
+        }
+        let inv_det = 1.0 / det;
+
+        let tvec = ray.origin - *p0;
+        let b1 = na::dot(&tvec, &pvec) * inv_det;
+        if b1 < 0.0 || b1 > 1.0 {
+            return None;
+        }
+        let qvec = tvec.cross(&e1);
+        let b2 = na::dot(&ray.dir, &qvec) * inv_det;
+        if b2 < 0.0 || b1 + b2 > 1.0 {
+            return None;
+        }
+        Some((na::dot(&e2, &qvec) * inv_det, b1, b2))
+    }
+
+    /// Find the closest triangle hit by a ray in the
+    /// mesh's space closer than t_max.
+    fn closest_hit(&self,
+                   ray: &Ray3<Scalar>,
+                   t_max: Scalar)
+                   -> Option<(Scalar, usize, Scalar, Scalar)> {
+        self.bvh.closest_hit(ray, t_max, |&i, t_max| {
+            match self.intersect_triangle(i, ray) {
+                Some((t, b1, b2)) if t > 0.0 && t < t_max => Some((t, (t, i, b1, b2))),
+                _ => None,
+            }
+        })
+    }
+
+    /// The geometry at a point on a triangle given by the
+    /// barycentric coordinates of its second and third vertices.
+    ///
+    /// The true normal follows the winding of the triangle, flipped
+    /// to the side of the interpolated vertex normal if there is one.
+    fn surface(&self, i: usize, b1: Scalar, b2: Scalar) -> SurfaceGeometry {
+        let idx = &self.indices[i];
+        let b0 = 1.0 - b1 - b2;
+        let (p0, p1, p2) = self.triangle(i);
+        let p = Point::from_coordinates(p0.coords * b0 + p1.coords * b1 + p2.coords * b2);
+        let normal = na::normalize(&(*p1 - *p0).cross(&(*p2 - *p0)));
+
+        // solve for the derivatives of the position with respect
+        // to the texture coordinates from the edges of the triangle
+        let (dpdu, dpdv) = match self.uvs {
+            Some(ref uvs) => {
+                let duv02 = uvs[idx[0]] - uvs[idx[2]];
+                let duv12 = uvs[idx[1]] - uvs[idx[2]];
+                let dp02 = *p0 - *p2;
+                let dp12 = *p1 - *p2;
+                let det = duv02.x * duv12.y - duv02.y * duv12.x;
+                if det.abs() < 1e-12 {
+                    (na::zero(), na::zero())
+                } else {
+                    let inv_det = 1.0 / det;
+                    ((dp02 * duv12.y - dp12 * duv02.y) * inv_det,
+                     (dp12 * duv02.x - dp02 * duv12.x) * inv_det)
+                }
+            }
+            None => (na::zero(), na::zero()),
+        };
+        let uv = self.uvs.as_ref().map(|uvs| {
+            Point2::from_coordinates(uvs[idx[0]].coords * b0 + uvs[idx[1]].coords * b1 +
+                                     uvs[idx[2]].coords * b2)
+        });
+
+        let shading_normal = match self.normals {
+            Some(ref normals) => {
+                let n = normals[idx[0]] * b0 + normals[idx[1]] * b1 + normals[idx[2]] * b2;
+                if n == na::zero() { None } else { Some(na::normalize(&n)) }
+            }
+            None => None,
+        };
+        match shading_normal {
+            Some(shading_normal) => {
+                let normal = if na::dot(&normal, &shading_normal) < 0.0 {
+                    -normal
+                } else {
+                    normal
+                };
+                let mut geometry = SurfaceGeometry::new(p, normal, dpdu, dpdv, uv);
+                geometry.shading_normal = shading_normal;
+                geometry
+            }
+            None => SurfaceGeometry::new(p, normal, dpdu, dpdv, uv),
+        }
+    }
+}
+
+/// Meshes are surfaces rather than solids so a ray
+/// starting inside a closed mesh isn't blocked by it.
+impl Surface for TriangleMesh {
+    fn intersect(&self, ray: &Ray3<Scalar>, t_max: Scalar) -> Option<(Scalar, SurfaceGeometry)> {
+        self.closest_hit(ray, t_max).map(|(t, i, b1, b2)| (t, self.surface(i, b1, b2)))
+    }
+
+    fn occludes(&self, ray: &Ray3<Scalar>, t_max: Scalar) -> bool {
+        self.bvh.any_hit(ray, t_max, |&i, t_max| {
+            match self.intersect_triangle(i, ray) {
+                Some((t, _, _)) => t > 0.0 && t < t_max,
+                None => false,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+fn test_quad(normals: Option<Vec<Normal>>) -> TriangleMesh {
+    // a unit square in the z = 0 plane facing +Z
+    let vertices = vec![Point::new(0.0, 0.0, 0.0),
+                        Point::new(1.0, 0.0, 0.0),
+                        Point::new(1.0, 1.0, 0.0),
+                        Point::new(0.0, 1.0, 0.0)];
+    let uvs = vec![Point2::new(0.0, 0.0),
+                   Point2::new(1.0, 0.0),
+                   Point2::new(1.0, 1.0),
+                   Point2::new(0.0, 1.0)];
+    TriangleMesh::new(vertices, vec![[0, 1, 2], [0, 2, 3]], normals, Some(uvs))
+}
+
+#[test]
+fn test_mesh_interpolates_normals_and_uvs() {
+    let flat = test_quad(None);
+    let ray = Ray3::new(Point::new(0.25, 0.5, 1.0), Vector::new(0.0, 0.0, -1.0));
+    let (t, geometry) = flat.intersect(&ray, ::std::f64::INFINITY).unwrap();
+    assert_approx_eq!(t, 1.0);
+    assert!(flat.intersect(&ray, 0.5).is_none());
+    assert_approx_eq!(geometry.shading_normal.z, 1.0);
+    let uv = geometry.uv.unwrap();
+    assert_approx_eq!(uv.x, 0.25);
+    assert_approx_eq!(uv.y, 0.5);
+    // the texture coordinates run along X and Y
+    assert_approx_eq!(geometry.dpdu.x, 1.0);
+    assert_approx_eq!(geometry.dpdv.y, 1.0);
+
+    // normals tilted towards -X along the left edge
+    // and +X along the right edge
+    let left = na::normalize(&Vector::new(-1.0, 0.0, 1.0));
+    let right = na::normalize(&Vector::new(1.0, 0.0, 1.0));
+    let curved = test_quad(Some(vec![left, right, right, left]));
+    let ray = Ray3::new(Point::new(0.5, 0.25, 1.0), Vector::new(0.0, 0.0, -1.0));
+    let (_, geometry) = curved.intersect(&ray, ::std::f64::INFINITY).unwrap();
+    assert_approx_eq!(geometry.shading_normal.x, 0.0);
+    assert_approx_eq!(geometry.shading_normal.z, 1.0);
+    let ray = Ray3::new(Point::new(0.25, 0.5, 1.0), Vector::new(0.0, 0.0, -1.0));
+    let (_, geometry) = curved.intersect(&ray, ::std::f64::INFINITY).unwrap();
+    assert!(geometry.shading_normal.x < 0.0);
+    assert_approx_eq!(geometry.normal.z, 1.0);
+
+    let miss = Ray3::new(Point::new(1.5, 0.5, 1.0), Vector::new(0.0, 0.0, -1.0));
+    assert!(!curved.occludes(&miss, ::std::f64::INFINITY));
+}
+
+#[test]
+fn test_smooth_normals() {
+    // two faces of a cube meeting along an edge
+    let vertices = vec![Point::new(0.0, 0.0, 0.0),
+                        Point::new(1.0, 0.0, 0.0),
+                        Point::new(0.0, 1.0, 0.0),
+                        Point::new(0.0, 0.0, 1.0)];
+    let mut mesh = TriangleMesh::new(vertices, vec![[0, 2, 1], [0, 1, 3]], None, None);
+    mesh.smooth_normals();
+    let normals = mesh.normals().unwrap();
+    // vertices on the shared edge average the two faces
+    let expected = na::normalize(&Vector::new(0.0, -1.0, -1.0));
+    assert_approx_eq!(normals[0].y, expected.y);
+    assert_approx_eq!(normals[0].z, expected.z);
+    assert_approx_eq!(normals[2].z, -1.0);
+    assert_approx_eq!(normals[3].y, -1.0);
+}
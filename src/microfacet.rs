@@ -0,0 +1,263 @@
+use std::f64::consts;
+
+use na;
+
+use bxdf::{cos_phi, cos_theta, cos_theta2, sin_phi, tan_theta, tan_theta2};
+use math::{Scalar, Vector, erf, erf_inv};
+
+/// Convert a perceptually linear roughness in [0, 1]
+/// to the alpha parameter of a microfacet distribution.
+#[inline]
+pub fn roughness_to_alpha(roughness: Scalar) -> Scalar {
+    Scalar::max(roughness * roughness, 0.0001)
+}
+
+/// A distribution of the normals of the microscopic facets
+/// making up a rough surface, with all directions given in
+/// the local shading frame.
+///
+/// Roughness can differ along the tangent (alpha_x) and the
+/// bitangent (alpha_y), giving anisotropic highlights such
+/// as those on brushed metal.
+pub trait MicrofacetDistribution {
+    /// The differential area of facets with normal wh.
+    fn d(&self, wh: &Vector) -> Scalar;
+
+    /// The area of facets hidden from direction w per
+    /// area of facets visible from it.
+    fn lambda(&self, w: &Vector) -> Scalar;
+
+    /// Sample a facet normal visible from wo, where wo
+    /// is in the upper hemisphere.
+    fn sample_visible(&self, wo: &Vector, u1: Scalar, u2: Scalar) -> Vector;
+
+    /// The fraction of facets visible from direction w.
+    #[inline]
+    fn g1(&self, w: &Vector) -> Scalar {
+        1.0 / (1.0 + self.lambda(w))
+    }
+
+    /// The fraction of facets visible from both directions.
+    #[inline]
+    fn g(&self, wo: &Vector, wi: &Vector) -> Scalar {
+        1.0 / (1.0 + self.lambda(wo) + self.lambda(wi))
+    }
+
+    /// Sample a facet normal from those visible from wo.
+    fn sample_wh(&self, wo: &Vector, u1: Scalar, u2: Scalar) -> Vector {
+        if wo.z < 0.0 {
+            -self.sample_visible(&-*wo, u1, u2)
+        } else {
+            self.sample_visible(wo, u1, u2)
+        }
+    }
+
+    /// The pdf of `sample_wh` choosing the facet normal wh.
+    fn pdf(&self, wo: &Vector, wh: &Vector) -> Scalar {
+        self.d(wh) * self.g1(wo) * na::dot(wo, wh).abs() / cos_theta(wo).abs()
+    }
+}
+
+/// The squared alpha of an anisotropic distribution
+/// in the azimuthal direction of w.
+#[inline]
+fn alpha2(w: &Vector, alpha_x: Scalar, alpha_y: Scalar) -> Scalar {
+    cos_phi(w) * cos_phi(w) * alpha_x * alpha_x + sin_phi(w) * sin_phi(w) * alpha_y * alpha_y
+}
+
+/// The distribution of Trowbridge and Reitz, also known as
+/// GGX, which has longer tails than Beckmann's giving
+/// highlights a soft glow.
+pub struct TrowbridgeReitz {
+    alpha_x: Scalar,
+    alpha_y: Scalar,
+}
+
+impl TrowbridgeReitz {
+    pub fn new(alpha_x: Scalar, alpha_y: Scalar) -> TrowbridgeReitz {
+        TrowbridgeReitz {
+            alpha_x: alpha_x,
+            alpha_y: alpha_y,
+        }
+    }
+}
+
+impl MicrofacetDistribution for TrowbridgeReitz {
+    fn d(&self, wh: &Vector) -> Scalar {
+        let tan2 = tan_theta2(wh);
+        if tan2.is_infinite() {
+            return 0.0;
+        }
+        let cos4 = cos_theta2(wh) * cos_theta2(wh);
+        let e = tan2 *
+                (cos_phi(wh) * cos_phi(wh) / (self.alpha_x * self.alpha_x) +
+                 sin_phi(wh) * sin_phi(wh) / (self.alpha_y * self.alpha_y));
+        1.0 / (consts::PI * self.alpha_x * self.alpha_y * cos4 * (1.0 + e) * (1.0 + e))
+    }
+
+    fn lambda(&self, w: &Vector) -> Scalar {
+        let tan2 = tan_theta2(w);
+        if tan2.is_infinite() {
+            return 0.0;
+        }
+        let alpha2_tan2 = alpha2(w, self.alpha_x, self.alpha_y) * tan2;
+        (-1.0 + (1.0 + alpha2_tan2).sqrt()) / 2.0
+    }
+
+    /// Heitz's sampling of the visible normals of the
+    /// distribution stretched to unit roughness, where
+    /// they are the projection of a hemisphere.
+    fn sample_visible(&self, wo: &Vector, u1: Scalar, u2: Scalar) -> Vector {
+        let vh = na::normalize(&Vector::new(self.alpha_x * wo.x, self.alpha_y * wo.y, wo.z));
+        let len2 = vh.x * vh.x + vh.y * vh.y;
+        let t1 = if len2 > 0.0 {
+            Vector::new(-vh.y, vh.x, 0.0) / len2.sqrt()
+        } else {
+            Vector::x()
+        };
+        let t2 = vh.cross(&t1);
+
+        // sample the projected half disc facing wo
+        let r = u1.sqrt();
+        let phi = 2.0 * consts::PI * u2;
+        let p1 = r * phi.cos();
+        let s = 0.5 * (1.0 + vh.z);
+        let p2 = (1.0 - s) * (1.0 - p1 * p1).sqrt() + s * r * phi.sin();
+        let nh = t1 * p1 + t2 * p2 + vh * Scalar::max(0.0, 1.0 - p1 * p1 - p2 * p2).sqrt();
+
+        na::normalize(&Vector::new(self.alpha_x * nh.x,
+                                   self.alpha_y * nh.y,
+                                   Scalar::max(1e-6, nh.z)))
+    }
+}
+
+/// The distribution of Beckmann and Spizzichino, a
+/// Gaussian distribution of facet slopes.
+pub struct Beckmann {
+    alpha_x: Scalar,
+    alpha_y: Scalar,
+}
+
+impl Beckmann {
+    pub fn new(alpha_x: Scalar, alpha_y: Scalar) -> Beckmann {
+        Beckmann {
+            alpha_x: alpha_x,
+            alpha_y: alpha_y,
+        }
+    }
+}
+
+impl MicrofacetDistribution for Beckmann {
+    fn d(&self, wh: &Vector) -> Scalar {
+        let tan2 = tan_theta2(wh);
+        if tan2.is_infinite() {
+            return 0.0;
+        }
+        let cos4 = cos_theta2(wh) * cos_theta2(wh);
+        (-tan2 *
+         (cos_phi(wh) * cos_phi(wh) / (self.alpha_x * self.alpha_x) +
+          sin_phi(wh) * sin_phi(wh) / (self.alpha_y * self.alpha_y)))
+            .exp() / (consts::PI * self.alpha_x * self.alpha_y * cos4)
+    }
+
+    /// A rational approximation of the Smith
+    /// shadowing function for Beckmann.
+    fn lambda(&self, w: &Vector) -> Scalar {
+        let tan = tan_theta(w).abs();
+        if tan.is_infinite() {
+            return 0.0;
+        }
+        let a = 1.0 / (alpha2(w, self.alpha_x, self.alpha_y).sqrt() * tan);
+        if a >= 1.6 {
+            0.0
+        } else {
+            (1.0 - 1.259 * a + 0.396 * a * a) / (3.535 * a + 2.181 * a * a)
+        }
+    }
+
+    /// Sample the slopes of visible facets of the distribution
+    /// stretched to unit roughness, following Jakob's method.
+    fn sample_visible(&self, wo: &Vector, u1: Scalar, u2: Scalar) -> Vector {
+        let stretched = na::normalize(&Vector::new(self.alpha_x * wo.x,
+                                                   self.alpha_y * wo.y,
+                                                   wo.z));
+        let (slope_x, slope_y) = beckmann_sample_slopes(cos_theta(&stretched), u1, u2);
+
+        // rotate to the azimuth of wo and unstretch
+        let (cos_phi, sin_phi) = (cos_phi(&stretched), sin_phi(&stretched));
+        let (slope_x, slope_y) = (self.alpha_x * (cos_phi * slope_x - sin_phi * slope_y),
+                                  self.alpha_y * (sin_phi * slope_x + cos_phi * slope_y));
+        na::normalize(&Vector::new(-slope_x, -slope_y, 1.0))
+    }
+}
+
+/// Sample the slopes of facets of the unit roughness Beckmann
+/// distribution visible from a direction with azimuth zero.
+fn beckmann_sample_slopes(cos_theta_i: Scalar, u1: Scalar, u2: Scalar) -> (Scalar, Scalar) {
+    if cos_theta_i > 0.9999 {
+        // at normal incidence every facet is visible
+        let r = (-(1.0 - u1).ln()).sqrt();
+        let phi = 2.0 * consts::PI * u2;
+        return (r * phi.cos(), r * phi.sin());
+    }
+
+    let sin_theta_i = Scalar::max(0.0, 1.0 - cos_theta_i * cos_theta_i).sqrt();
+    let tan_theta_i = sin_theta_i / cos_theta_i;
+    let cot_theta_i = 1.0 / tan_theta_i;
+    let sqrt_pi_inv = 1.0 / consts::PI.sqrt();
+
+    // invert the cdf of the slope along x with
+    // Newton's method, bracketed by bisection
+    let mut a = -1.0;
+    let mut c = erf(cot_theta_i);
+    let u1 = Scalar::max(u1, 1e-6);
+    let theta_i = cos_theta_i.acos();
+    let fit = 1.0 + theta_i * (-0.876 + theta_i * (0.4265 - 0.0594 * theta_i));
+    let mut b = c - (1.0 + c) * (1.0 - u1).powf(fit);
+    let normalization = 1.0 /
+                        (1.0 + c +
+                         sqrt_pi_inv * tan_theta_i * (-cot_theta_i * cot_theta_i).exp());
+    for _ in 0..9 {
+        if !(b >= a && b <= c) {
+            b = 0.5 * (a + c);
+        }
+        let inv_erf = erf_inv(b);
+        let value = normalization *
+                    (1.0 + b + sqrt_pi_inv * tan_theta_i * (-inv_erf * inv_erf).exp()) -
+                    u1;
+        if value.abs() < 1e-5 {
+            break;
+        }
+        if value > 0.0 {
+            c = b;
+        } else {
+            a = b;
+        }
+        let derivative = normalization * (1.0 - inv_erf * tan_theta_i);
+        b -= value / derivative;
+    }
+
+    (erf_inv(b), erf_inv(2.0 * Scalar::max(u2, 1e-6) - 1.0))
+}
+
+#[test]
+fn test_distributions_are_normalized() {
+    // the projected area of the facets is the area of the surface
+    let distributions: Vec<Box<MicrofacetDistribution>> =
+        vec![Box::new(TrowbridgeReitz::new(0.5, 0.3)), Box::new(Beckmann::new(0.4, 0.6))];
+    let n = 400;
+    let d_theta = consts::FRAC_PI_2 / n as Scalar;
+    let d_phi = 2.0 * consts::PI / n as Scalar;
+    for distribution in distributions {
+        let mut integral = 0.0;
+        for i in 0..n {
+            let theta = (i as Scalar + 0.5) * d_theta;
+            for j in 0..n {
+                let phi = (j as Scalar + 0.5) * d_phi;
+                let wh = Vector::new(theta.sin() * phi.cos(), theta.sin() * phi.sin(), theta.cos());
+                integral += distribution.d(&wh) * theta.cos() * theta.sin() * d_theta * d_phi;
+            }
+        }
+        assert!((integral - 1.0).abs() < 0.01);
+    }
+}
@@ -44,9 +44,183 @@ pub fn concentric_sample_disc(u1: f64, u2: f64) -> (f64, f64) {
     (dx, dy)
 }
 
+/// Weight a sample taken with one of two sampling
+/// strategies using the power heuristic (with an
+/// exponent of two) for multiple importance sampling.
+#[inline]
+pub fn power_heuristic(nf: u32, f_pdf: f64, ng: u32, g_pdf: f64) -> f64 {
+    let f = nf as f64 * f_pdf;
+    let g = ng as f64 * g_pdf;
+    if f == 0.0 && g == 0.0 {
+        0.0
+    } else {
+        (f * f) / (f * f + g * g)
+    }
+}
+
 #[inline]
 pub fn cosine_sample_hemisphere(u1: f64, u2: f64) -> Vector3<f64> {
     let (x, y) = concentric_sample_disc(u1, u2);
     let z = f64::max(0.0, 1.0 - x * x - y * y).sqrt();
     Vector3::new(x, y, z)
 }
+
+/// Uniformly sample barycentric coordinates
+/// over the area of a triangle.
+#[inline]
+pub fn uniform_sample_triangle(u1: f64, u2: f64) -> (f64, f64) {
+    let su1 = u1.sqrt();
+    (1.0 - su1, u2 * su1)
+}
+
+/// A piecewise-constant 1D distribution that can be sampled
+/// in proportion to the values of the function it was
+/// built from over [0, 1].
+pub struct Distribution1D {
+    func: Vec<f64>,
+    cdf: Vec<f64>,
+    func_int: f64,
+}
+
+impl Distribution1D {
+    pub fn new(func: &[f64]) -> Distribution1D {
+        let n = func.len();
+        let mut cdf = vec![0.0; n + 1];
+        for i in 1..n + 1 {
+            cdf[i] = cdf[i - 1] + func[i - 1].abs() / n as f64;
+        }
+
+        // fall back to a uniform distribution
+        // if the function is zero everywhere
+        let func_int = cdf[n];
+        for i in 1..n + 1 {
+            cdf[i] = if func_int == 0.0 {
+                i as f64 / n as f64
+            } else {
+                cdf[i] / func_int
+            };
+        }
+
+        Distribution1D {
+            func: func.iter().map(|f| f.abs()).collect(),
+            cdf: cdf,
+            func_int: func_int,
+        }
+    }
+
+    #[inline]
+    pub fn count(&self) -> usize {
+        self.func.len()
+    }
+
+    /// The integral of the function over [0, 1].
+    #[inline]
+    pub fn integral(&self) -> f64 {
+        self.func_int
+    }
+
+    /// Sample a value in [0, 1) returning it along with
+    /// its pdf and the index of the segment it falls in.
+    pub fn sample_continuous(&self, u: f64) -> (f64, f64, usize) {
+        // find the last cdf entry that is less than or equal to u
+        let mut lo = 0;
+        let mut hi = self.count();
+        while lo + 1 < hi {
+            let mid = (lo + hi) / 2;
+            if self.cdf[mid] <= u {
+                lo = mid;
+            } else {
+                hi = mid;
+            }
+        }
+        let offset = lo;
+
+        let mut du = u - self.cdf[offset];
+        let width = self.cdf[offset + 1] - self.cdf[offset];
+        if width > 0.0 {
+            du = du / width;
+        }
+        let pdf = if self.func_int > 0.0 {
+            self.func[offset] / self.func_int
+        } else {
+            0.0
+        };
+        ((offset as f64 + du) / self.count() as f64, pdf, offset)
+    }
+
+    /// The pdf of `sample_continuous` returning
+    /// a value in the segment at `offset`.
+    #[inline]
+    pub fn pdf(&self, offset: usize) -> f64 {
+        if self.func_int > 0.0 {
+            self.func[offset] / self.func_int
+        } else {
+            0.0
+        }
+    }
+}
+
+/// A piecewise-constant 2D distribution over [0, 1]^2 built
+/// from a grid of function values stored in row-major order.
+pub struct Distribution2D {
+    conditional: Vec<Distribution1D>,
+    marginal: Distribution1D,
+}
+
+impl Distribution2D {
+    pub fn new(func: &[f64], nu: usize, nv: usize) -> Distribution2D {
+        assert_eq!(func.len(), nu * nv);
+        let conditional: Vec<Distribution1D> = (0..nv)
+            .map(|v| Distribution1D::new(&func[v * nu..(v + 1) * nu]))
+            .collect();
+        let marginal: Vec<f64> = conditional.iter().map(|d| d.integral()).collect();
+        Distribution2D {
+            conditional: conditional,
+            marginal: Distribution1D::new(&marginal),
+        }
+    }
+
+    /// Sample a point (u, v) in [0, 1)^2 returning
+    /// it along with its pdf.
+    pub fn sample_continuous(&self, u1: f64, u2: f64) -> ((f64, f64), f64) {
+        let (v, pdf_v, offset) = self.marginal.sample_continuous(u2);
+        let (u, pdf_u, _) = self.conditional[offset].sample_continuous(u1);
+        ((u, v), pdf_u * pdf_v)
+    }
+
+    /// The pdf of `sample_continuous` returning the point (u, v).
+    pub fn pdf(&self, u: f64, v: f64) -> f64 {
+        let nv = self.marginal.count();
+        let iv = usize::min((v * nv as f64).max(0.0) as usize, nv - 1);
+        let conditional = &self.conditional[iv];
+        let nu = conditional.count();
+        let iu = usize::min((u * nu as f64).max(0.0) as usize, nu - 1);
+        if self.marginal.integral() > 0.0 {
+            conditional.func[iu] / self.marginal.integral()
+        } else {
+            0.0
+        }
+    }
+}
+
+#[test]
+fn test_distribution1d_sample() {
+    let d = Distribution1D::new(&[0.0, 1.0, 3.0, 0.0]);
+    let (x, pdf, offset) = d.sample_continuous(0.5);
+    assert_eq!(offset, 2);
+    assert_approx_eq!(x, 7.0 / 12.0);
+    assert_approx_eq!(pdf, 3.0);
+    assert_approx_eq!(d.pdf(1), 1.0);
+    assert_approx_eq!(d.pdf(0), 0.0);
+}
+
+#[test]
+fn test_distribution2d_pdf() {
+    let d = Distribution2D::new(&[1.0, 1.0, 2.0, 4.0], 2, 2);
+    // the pdf over the whole domain should integrate to one
+    let total = (d.pdf(0.25, 0.25) + d.pdf(0.75, 0.25) + d.pdf(0.25, 0.75) +
+                 d.pdf(0.75, 0.75)) / 4.0;
+    assert_approx_eq!(total, 1.0);
+    let ((_, _), pdf) = d.sample_continuous(0.9, 0.9);
+    assert_approx_eq!(pdf, d.pdf(0.9, 0.9));
+}
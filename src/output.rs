@@ -0,0 +1,305 @@
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use image;
+use image::hdr::HDREncoder;
+
+use spectrum::Spectrum;
+use tonemap::ToneMap;
+
+/// The file formats a rendered image can be written in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImageFormat {
+    /// 8 bit PNG, radiance is tone mapped to [0, 1].
+    Png,
+    /// OpenEXR with 16 bit half float channels.
+    ExrHalf,
+    /// OpenEXR with 32 bit float channels.
+    ExrFloat,
+    /// Radiance RGBE.
+    Hdr,
+    /// Portable float map.
+    Pfm,
+}
+
+impl ImageFormat {
+    pub fn from_name(name: &str) -> Option<ImageFormat> {
+        match name {
+            "png" => Some(ImageFormat::Png),
+            "exr" | "exr-half" => Some(ImageFormat::ExrHalf),
+            "exr-float" => Some(ImageFormat::ExrFloat),
+            "hdr" => Some(ImageFormat::Hdr),
+            "pfm" => Some(ImageFormat::Pfm),
+            _ => None,
+        }
+    }
+
+    /// Find the format from a file's extension.
+    pub fn from_path(path: &Path) -> Option<ImageFormat> {
+        path.extension()
+            .and_then(|ext| ext.to_str())
+            .and_then(|ext| ImageFormat::from_name(&ext.to_lowercase()))
+    }
+}
+
+/// Write an image of linear radiance values given
+/// in row-major order to a file in the given format.
+///
+/// The tone map is only applied to formats that store
+/// 8 bits per channel, the others keep the linear values.
+pub fn write_image(path: &Path,
+                   format: ImageFormat,
+                   width: u32,
+                   height: u32,
+                   pixels: &[Spectrum],
+                   tonemap: &ToneMap)
+                   -> io::Result<()> {
+    assert_eq!(pixels.len(), (width * height) as usize);
+    let f = try!(File::create(path));
+    let mut w = BufWriter::new(f);
+    match format {
+        ImageFormat::Png => write_png(&mut w, width, height, pixels, tonemap),
+        ImageFormat::ExrHalf => write_exr(&mut w, width, height, pixels, ExrPixelType::Half),
+        ImageFormat::ExrFloat => write_exr(&mut w, width, height, pixels, ExrPixelType::Float),
+        ImageFormat::Hdr => write_hdr(&mut w, width, height, pixels),
+        ImageFormat::Pfm => write_pfm(&mut w, width, height, pixels),
+    }
+}
+
+fn write_png<W: Write>(w: &mut W,
+                       width: u32,
+                       height: u32,
+                       pixels: &[Spectrum],
+                       tonemap: &ToneMap)
+                       -> io::Result<()> {
+    let mut colours = Vec::with_capacity(pixels.len() * 3);
+    for c in pixels {
+        let c = tonemap.apply(c);
+        // round to the nearest of the 256 levels
+        colours.push((c.x * 255.0 + 0.5) as u8);
+        colours.push((c.y * 255.0 + 0.5) as u8);
+        colours.push((c.z * 255.0 + 0.5) as u8);
+    }
+    let img = try!(image::ImageBuffer::from_raw(width, height, colours).ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "Could not create image buffer")
+    }));
+    image::ImageRgb8(img)
+        .save(w, image::PNG)
+        .map_err(|err| io::Error::new(io::ErrorKind::Other, err))
+}
+
+fn write_hdr<W: Write>(w: &mut W, width: u32, height: u32, pixels: &[Spectrum]) -> io::Result<()> {
+    let data: Vec<image::Rgb<f32>> = pixels.iter()
+        .map(|c| image::Rgb { data: [c.x as f32, c.y as f32, c.z as f32] })
+        .collect();
+    HDREncoder::new(w).encode(&data, width as usize, height as usize)
+}
+
+/// Write a little-endian portable float map, the
+/// rows of which are stored from bottom to top.
+fn write_pfm<W: Write>(w: &mut W, width: u32, height: u32, pixels: &[Spectrum]) -> io::Result<()> {
+    try!(write!(w, "PF\n{} {}\n-1.0\n", width, height));
+    for y in (0..height).rev() {
+        let row = &pixels[(y * width) as usize..((y + 1) * width) as usize];
+        for c in row {
+            try!(write_f32(w, c.x as f32));
+            try!(write_f32(w, c.y as f32));
+            try!(write_f32(w, c.z as f32));
+        }
+    }
+    Ok(())
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ExrPixelType {
+    Half,
+    Float,
+}
+
+impl ExrPixelType {
+    /// The value used for the pixel type in the channel list.
+    fn id(&self) -> i32 {
+        match *self {
+            ExrPixelType::Half => 1,
+            ExrPixelType::Float => 2,
+        }
+    }
+
+    fn size(&self) -> usize {
+        match *self {
+            ExrPixelType::Half => 2,
+            ExrPixelType::Float => 4,
+        }
+    }
+}
+
+/// Write an uncompressed single part scanline OpenEXR file.
+fn write_exr<W: Write>(w: &mut W,
+                       width: u32,
+                       height: u32,
+                       pixels: &[Spectrum],
+                       pixel_type: ExrPixelType)
+                       -> io::Result<()> {
+    let mut header = Vec::new();
+    // magic number followed by version 2 with no flags set
+    header.extend_from_slice(&[0x76, 0x2f, 0x31, 0x01, 2, 0, 0, 0]);
+
+    // channels are stored in alphabetical order
+    let channels = ["B", "G", "R"];
+    let mut chlist = Vec::new();
+    for name in &channels {
+        chlist.extend_from_slice(name.as_bytes());
+        chlist.push(0);
+        chlist.extend_from_slice(&i32_bytes(pixel_type.id()));
+        // pLinear and reserved bytes
+        chlist.extend_from_slice(&[0, 0, 0, 0]);
+        // x and y sampling
+        chlist.extend_from_slice(&i32_bytes(1));
+        chlist.extend_from_slice(&i32_bytes(1));
+    }
+    chlist.push(0);
+    write_attribute(&mut header, "channels", "chlist", &chlist);
+
+    // no compression
+    write_attribute(&mut header, "compression", "compression", &[0]);
+
+    let mut window = Vec::new();
+    window.extend_from_slice(&i32_bytes(0));
+    window.extend_from_slice(&i32_bytes(0));
+    window.extend_from_slice(&i32_bytes(width as i32 - 1));
+    window.extend_from_slice(&i32_bytes(height as i32 - 1));
+    write_attribute(&mut header, "dataWindow", "box2i", &window);
+    write_attribute(&mut header, "displayWindow", "box2i", &window);
+
+    // increasing y
+    write_attribute(&mut header, "lineOrder", "lineOrder", &[0]);
+    write_attribute(&mut header, "pixelAspectRatio", "float", &f32_bytes(1.0));
+    let mut centre = Vec::new();
+    centre.extend_from_slice(&f32_bytes(0.0));
+    centre.extend_from_slice(&f32_bytes(0.0));
+    write_attribute(&mut header, "screenWindowCenter", "v2f", &centre);
+    write_attribute(&mut header, "screenWindowWidth", "float", &f32_bytes(1.0));
+    header.push(0);
+    try!(w.write_all(&header));
+
+    // each scanline is a chunk holding its y coordinate, the
+    // size of its data and then the values of each channel
+    let line_size = width as usize * channels.len() * pixel_type.size();
+    let chunk_size = 8 + line_size;
+    let table_size = 8 * height as usize;
+    for y in 0..height as usize {
+        let offset = (header.len() + table_size + y * chunk_size) as u64;
+        try!(w.write_all(&u64_bytes(offset)));
+    }
+
+    let mut line = Vec::with_capacity(line_size);
+    for y in 0..height {
+        let row = &pixels[(y * width) as usize..((y + 1) * width) as usize];
+        line.clear();
+        for channel in 0..channels.len() {
+            for c in row {
+                let value = match channel {
+                    0 => c.z,
+                    1 => c.y,
+                    _ => c.x,
+                } as f32;
+                match pixel_type {
+                    ExrPixelType::Half => line.extend_from_slice(&u16_bytes(f32_to_f16(value))),
+                    ExrPixelType::Float => line.extend_from_slice(&f32_bytes(value)),
+                }
+            }
+        }
+        try!(w.write_all(&i32_bytes(y as i32)));
+        try!(w.write_all(&i32_bytes(line_size as i32)));
+        try!(w.write_all(&line));
+    }
+    Ok(())
+}
+
+fn write_attribute(header: &mut Vec<u8>, name: &str, attr_type: &str, value: &[u8]) {
+    header.extend_from_slice(name.as_bytes());
+    header.push(0);
+    header.extend_from_slice(attr_type.as_bytes());
+    header.push(0);
+    header.extend_from_slice(&i32_bytes(value.len() as i32));
+    header.extend_from_slice(value);
+}
+
+fn write_f32<W: Write>(w: &mut W, value: f32) -> io::Result<()> {
+    w.write_all(&f32_bytes(value))
+}
+
+#[inline]
+fn u16_bytes(value: u16) -> [u8; 2] {
+    [value as u8, (value >> 8) as u8]
+}
+
+#[inline]
+fn u32_bytes(value: u32) -> [u8; 4] {
+    [value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8]
+}
+
+#[inline]
+fn i32_bytes(value: i32) -> [u8; 4] {
+    u32_bytes(value as u32)
+}
+
+#[inline]
+fn f32_bytes(value: f32) -> [u8; 4] {
+    u32_bytes(value.to_bits())
+}
+
+#[inline]
+fn u64_bytes(value: u64) -> [u8; 8] {
+    let lo = u32_bytes(value as u32);
+    let hi = u32_bytes((value >> 32) as u32);
+    [lo[0], lo[1], lo[2], lo[3], hi[0], hi[1], hi[2], hi[3]]
+}
+
+/// Convert a float to the bits of the nearest half precision float.
+fn f32_to_f16(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exponent = ((bits >> 23) & 0xff) as i32;
+    let mantissa = bits & 0x7fffff;
+
+    if exponent == 0xff {
+        // infinity stays infinity and NaN stays NaN
+        return sign | 0x7c00 | if mantissa != 0 { 0x200 } else { 0 };
+    }
+
+    let exponent = exponent - 127 + 15;
+    if exponent >= 0x1f {
+        // too large so round to infinity
+        sign | 0x7c00
+    } else if exponent <= 0 {
+        // too small for a normalised half so
+        // store as a denormal or round to zero
+        if exponent < -10 {
+            return sign;
+        }
+        let mantissa = mantissa | 0x800000;
+        let shift = (14 - exponent) as u32;
+        let round = (mantissa >> (shift - 1)) & 1;
+        sign | ((mantissa >> shift) + round) as u16
+    } else {
+        let half = sign | ((exponent as u16) << 10) | ((mantissa >> 13) as u16);
+        // rounding up may carry into the exponent
+        // which still gives the nearest value
+        if mantissa & 0x1000 != 0 { half + 1 } else { half }
+    }
+}
+
+#[test]
+fn test_f32_to_f16() {
+    assert_eq!(f32_to_f16(0.0), 0x0000);
+    assert_eq!(f32_to_f16(1.0), 0x3c00);
+    assert_eq!(f32_to_f16(0.5), 0x3800);
+    assert_eq!(f32_to_f16(-2.0), 0xc000);
+    assert_eq!(f32_to_f16(65504.0), 0x7bff);
+    assert_eq!(f32_to_f16(1e6), 0x7c00);
+    // the smallest denormal half
+    assert_eq!(f32_to_f16(5.96046448e-8), 0x0001);
+}
@@ -8,52 +8,71 @@ use std::sync::Arc;
 
 use image;
 use na;
-use na::Isometry3;
+use na::{Isometry3, Matrix3, Matrix4, Quaternion, Translation3, UnitQuaternion};
 use ncollide::bounding_volume::AABB3;
-use ncollide::query::RayCast;
-use ncollide::shape::{Ball, Cuboid, Shape, TriMesh3};
+use ncollide::shape::{Ball, Cuboid, Shape};
 use serde_json;
 use serde_json::{Map, Value};
 
-use camera::{Camera, PerspectiveCamera};
+use assets;
+use camera::{Camera, FisheyeCamera, OrthographicCamera, PerspectiveCamera, SphericalCamera};
+use filter::{BoxFilter, Filter, GaussianFilter, LanczosFilter, MitchellFilter, TriangleFilter};
 use integrator::{Integrator, PathTraced, Whitted};
-use light::{Light, PointLight};
-use material::{DiffuseMaterial, GlassMaterial, Material, MirrorMaterial};
-use math::{Point, Scalar, Vector};
+use light::{AreaLight, DiffuseLight, InfiniteAreaLight, Light, PointLight, ShapeEmitter};
+use material;
+use material::{CoatedMaterial, DiffuseMaterial, GlassMaterial, MappedMaterial, Material,
+               MetalMaterial, MirrorMaterial, PlasticMaterial, Roughness, ShadingMap};
+use math::{Point, Scalar, Vector, look_at};
 use renderer::{Renderer, StandardRenderer};
-use scene::{Scene, SceneNode};
+use sampler::{HaltonSampler, IndependentSampler, Sampler, SobolSampler, StratifiedSampler};
+use scene::{Geometry, Scene, SceneNode};
 use spectrum::Spectrum;
+use surface::Surface;
 use texture::{ConstantTexture, ImageTexture, Texture};
+use tonemap::{ToneMap, ToneMapOperator};
+use transform::{AnimatedTransform, Transform};
 
 // TODO: rewrite in order to use #[derive(Serialize, Deserialize)]
 
-pub type Intersectable = Box<RayCast<Point, Isometry3<Scalar>> + Sync + Send>;
+pub type Intersectable = Arc<Surface + Sync + Send>;
+pub type Emitter = Arc<ShapeEmitter + Sync + Send>;
 
 pub struct View {
     pub camera: Arc<Camera + Sync + Send>,
     pub samples: u32,
     pub depth: i32,
     pub renderer: Arc<Renderer + Sync + Send>,
+    pub sampler: Box<Sampler + Sync + Send>,
+    pub filter: Arc<Filter + Sync + Send>,
+    pub tonemap: ToneMap,
 }
 
 impl View {
     pub fn new(camera: Arc<Camera + Sync + Send>,
                samples: u32,
                depth: i32,
-               renderer: Arc<Renderer + Sync + Send>)
+               renderer: Arc<Renderer + Sync + Send>,
+               sampler: Box<Sampler + Sync + Send>,
+               filter: Arc<Filter + Sync + Send>,
+               tonemap: ToneMap)
                -> View {
         View {
             camera: camera,
             samples: samples,
             depth: depth,
             renderer: renderer,
+            sampler: sampler,
+            filter: filter,
+            tonemap: tonemap,
         }
     }
 }
 
 #[derive(Debug)]
 pub enum Error {
+    EmptyMesh(String),
     ExpectedArray(&'static str),
+    ExpectedBool(&'static str),
     ExpectedU64(&'static str),
     ExpectedF64(&'static str),
     ExpectedI64(&'static str),
@@ -61,20 +80,28 @@ pub enum Error {
     ExpectedString(&'static str),
     Json(::serde_json::error::Error),
     MalformedPoint(&'static str),
+    MalformedShutter(&'static str),
     MalformedSpectrum(&'static str),
+    MalformedTransform(&'static str),
     MalformedVector(&'static str),
     MissingKey(&'static str),
+    MissingGeometry(String),
+    MissingModel(String),
+    MissingPreset(String),
     MissingReference {
         typ: &'static str,
         name: &'static str,
     },
+    Obj(::tobj::LoadError),
     Texture(::image::ImageError),
 }
 
 impl error::Error for Error {
     fn description(&self) -> &str {
         match *self {
+            Error::EmptyMesh(ref filename) => filename,
             Error::ExpectedArray(err) => err,
+            Error::ExpectedBool(err) => err,
             Error::ExpectedU64(err) => err,
             Error::ExpectedF64(err) => err,
             Error::ExpectedI64(err) => err,
@@ -82,17 +109,25 @@ impl error::Error for Error {
             Error::ExpectedString(err) => err,
             Error::Json(ref err) => err.description(),
             Error::MalformedPoint(err) => err,
+            Error::MalformedShutter(err) => err,
             Error::MalformedSpectrum(err) => err,
+            Error::MalformedTransform(err) => err,
             Error::MalformedVector(err) => err,
             Error::MissingKey(err) => err,
+            Error::MissingGeometry(ref name) => name,
+            Error::MissingModel(ref name) => name,
+            Error::MissingPreset(ref name) => name,
             Error::MissingReference { name, .. } => name,
+            Error::Obj(_) => "Could not load .obj",
             Error::Texture(ref err) => err.description(),
         }
     }
 
     fn cause(&self) -> Option<&error::Error> {
         match *self {
+            Error::EmptyMesh(_) => None,
             Error::ExpectedArray(_) => None,
+            Error::ExpectedBool(_) => None,
             Error::ExpectedU64(_) => None,
             Error::ExpectedF64(_) => None,
             Error::ExpectedI64(_) => None,
@@ -100,10 +135,16 @@ impl error::Error for Error {
             Error::ExpectedString(_) => None,
             Error::Json(ref err) => Some(err),
             Error::MalformedPoint(_) => None,
+            Error::MalformedShutter(_) => None,
             Error::MalformedSpectrum(_) => None,
+            Error::MalformedTransform(_) => None,
             Error::MalformedVector(_) => None,
             Error::MissingKey(_) => None,
+            Error::MissingGeometry(_) => None,
+            Error::MissingModel(_) => None,
+            Error::MissingPreset(_) => None,
             Error::MissingReference { .. } => None,
+            Error::Obj(_) => None,
             Error::Texture(ref err) => Some(err),
         }
     }
@@ -115,6 +156,12 @@ impl From<::serde_json::error::Error> for Error {
     }
 }
 
+impl From<::tobj::LoadError> for Error {
+    fn from(err: ::tobj::LoadError) -> Error {
+        Error::Obj(err)
+    }
+}
+
 impl From<::image::ImageError> for Error {
     fn from(err: ::image::ImageError) -> Error {
         Error::Texture(err)
@@ -124,7 +171,9 @@ impl From<::image::ImageError> for Error {
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
+            Error::EmptyMesh(ref filename) => write!(f, "No triangles found in '{}'", filename),
             Error::ExpectedArray(err) => write!(f, "Expected JSON array: {}", err),
+            Error::ExpectedBool(err) => write!(f, "Expected JSON bool: {}", err),
             Error::ExpectedU64(err) => write!(f, "Expected JSON u64: {}", err),
             Error::ExpectedF64(err) => write!(f, "Expected JSON f64: {}", err),
             Error::ExpectedI64(err) => write!(f, "Expected JSON i64: {}", err),
@@ -132,12 +181,18 @@ impl fmt::Display for Error {
             Error::ExpectedString(err) => write!(f, "Expected JSON string: {}", err),
             Error::Json(ref err) => write!(f, "JSON parse error: {}", err),
             Error::MalformedPoint(err) => write!(f, "Malformed point: {}", err),
+            Error::MalformedShutter(err) => write!(f, "Malformed shutter: {}", err),
             Error::MalformedSpectrum(err) => write!(f, "Malformed spectrum: {}", err),
+            Error::MalformedTransform(err) => write!(f, "Malformed transform: {}", err),
             Error::MalformedVector(err) => write!(f, "Malformed vector: {}", err),
             Error::MissingKey(err) => write!(f, "Missing key: {}", err),
+            Error::MissingGeometry(ref name) => write!(f, "Geometry '{}' not found", name),
+            Error::MissingModel(ref name) => write!(f, "Model '{}' not found in .obj", name),
+            Error::MissingPreset(ref name) => write!(f, "Preset '{}' not found", name),
             Error::MissingReference { typ, name } => {
                 write!(f, "Referenced {} with name '{}' not found.", typ, name)
             }
+            Error::Obj(ref err) => write!(f, "Could not load .obj: {:?}", err),
             Error::Texture(ref err) => write!(f, "Texture error: {}", err),
         }
     }
@@ -148,7 +203,12 @@ pub type Result<T> = result::Result<T, Error>;
 // TODO: use proper error handling here, i.e. Result
 
 /// Parse the scene description from a JSON formatted string.
-pub fn parse_scene(json: &str) -> Result<(Scene, HashMap<String, View>)> {
+///
+/// The width and height of every camera are replaced by
+/// those in `resolution` where they are given.
+pub fn parse_scene(json: &str,
+                   resolution: (Option<u32>, Option<u32>))
+                   -> Result<(Scene, HashMap<String, View>)> {
     let data: Value = try!(serde_json::from_str(json));
 
     let cameras = try!(data.pointer("/cameras").ok_or(Error::MissingKey("cameras")));
@@ -157,14 +217,19 @@ pub fn parse_scene(json: &str) -> Result<(Scene, HashMap<String, View>)> {
     let materials = try!(data.pointer("/materials").ok_or(Error::MissingKey("materials")));
     let lights = try!(data.pointer("/lights").ok_or(Error::MissingKey("lights")));
 
-    let cameras = try!(parse_cameras(cameras));
+    let cameras = try!(parse_cameras(cameras, resolution));
     let views = try!(parse_views(views, &cameras));
     let materials = try!(parse_materials(materials));
-    let objects = try!(parse_objects(objects, &materials));
+    // shared geometry is optional
+    let geometries = match data.pointer("/geometry") {
+        Some(geometries) => try!(parse_geometries(geometries)),
+        None => HashMap::new(),
+    };
+    let (objects, area_lights) = try!(parse_objects(objects, &materials, &geometries));
     let lights = try!(parse_lights(lights));
 
     let mut scene = Scene::new(objects);
-    for light in lights {
+    for light in lights.into_iter().chain(area_lights.into_iter()) {
         scene.add_light(light);
     }
     Ok((scene, views))
@@ -180,52 +245,160 @@ pub fn parse_scene(json: &str) -> Result<(Scene, HashMap<String, View>)> {
 ///         }
 ///     }
 /// }
-fn parse_cameras(data: &Value) -> Result<HashMap<String, Arc<Camera + Sync + Send>>> {
+fn parse_cameras(data: &Value,
+                 resolution: (Option<u32>, Option<u32>))
+                 -> Result<HashMap<String, Arc<Camera + Sync + Send>>> {
     let data = try!(data.as_object().ok_or(Error::ExpectedObject("cameras")));
 
     let mut cameras = HashMap::new();
     for (name, value) in data.iter() {
-        let camera = try!(parse_camera(value));
+        let camera = try!(parse_camera(value, resolution));
         cameras.insert(name.clone(), camera);
     }
     Ok(cameras)
 }
 
-fn parse_camera(data: &Value) -> Result<Arc<Camera + Sync + Send>> {
-    let transform = try!(data.pointer("/transform").ok_or(Error::MissingKey("transform")));
-    let transform = try!(parse_transform(transform));
-
-    let width = try!(data.pointer("/width").ok_or(Error::MissingKey("width")));
-    let width = try!(try_get_u64(width, "width"));
-
-    let height = try!(data.pointer("/height").ok_or(Error::MissingKey("height")));
-    let height = try!(try_get_u64(height, "height"));
-
-    let fov = try!(data.pointer("/fov").ok_or(Error::MissingKey("fov")));
-    let fov = try!(try_get_f64(fov, "fov"));
-
-    let near = try!(data.pointer("/near").ok_or(Error::MissingKey("near")));
-    let near = try!(try_get_f64(near, "near"));
+/// Parse a camera.
+///
+/// Structure:
+/// {
+///     "type": "Perspective" | "Orthographic" | "Spherical" | "Fisheye",
+///     "transform": { ... },
+///     "shutter": [0.0, 1.0],
+///     "width": 512,
+///     "height": 512,
+///     ...
+/// }
+///
+/// The "transform" may be replaced by "keyframes" for a moving
+/// camera. Rays are sent at times between the shutter opening
+/// and closing, which defaults to [0.0, 0.0] for no motion blur.
+///
+/// "Perspective" cameras also take "fov", "near", "far" and
+/// optionally "lens_radius" and "focal_distance".
+/// "Orthographic" cameras take "size" (the height of the view
+/// in world units), "near" and "far".
+/// "Fisheye" cameras take "fov", which defaults to 180 degrees.
+/// "Spherical" cameras take nothing more.
+///
+/// The "width" and "height" may be left out when they
+/// are given by `resolution` instead.
+fn parse_camera(data: &Value,
+                resolution: (Option<u32>, Option<u32>))
+                -> Result<Arc<Camera + Sync + Send>> {
+    let transform = try!(parse_animated_transform(try!(try_get_object(data, "camera"))));
+    if !transform.is_rigid() {
+        return Err(Error::MalformedTransform("Camera transforms must be rigid."));
+    }
+    let shutter = match data.pointer("/shutter") {
+        Some(shutter) => try!(parse_shutter(shutter)),
+        None => (0.0, 0.0),
+    };
 
-    let far = try!(data.pointer("/far").ok_or(Error::MissingKey("far")));
-    let far = try!(try_get_f64(far, "far"));
+    let (width, height) = resolution;
+    let width = match width {
+        Some(width) => width,
+        None => {
+            let width = try!(data.pointer("/width").ok_or(Error::MissingKey("width")));
+            try!(try_get_u64(width, "width")) as u32
+        }
+    };
+    let height = match height {
+        Some(height) => height,
+        None => {
+            let height = try!(data.pointer("/height").ok_or(Error::MissingKey("height")));
+            try!(try_get_u64(height, "height")) as u32
+        }
+    };
 
     let camera_type = try!(data.pointer("/type").ok_or(Error::MissingKey("type")));
     let camera_type = try!(try_get_string(camera_type, "type"));
 
     match camera_type {
         "Perspective" => {
+            let fov = try!(data.pointer("/fov").ok_or(Error::MissingKey("fov")));
+            let fov = try!(try_get_f64(fov, "fov"));
+            let (near, far) = try!(parse_clip_planes(data));
+
+            // depth of field is optional, the default
+            // lens radius of zero gives a pinhole camera
+            let lens_radius = match data.pointer("/lens_radius") {
+                Some(lens_radius) => try!(try_get_f64(lens_radius, "lens_radius")),
+                None => 0.0,
+            };
+            let focal_distance = match data.pointer("/focal_distance") {
+                Some(focal_distance) => try!(try_get_f64(focal_distance, "focal_distance")),
+                None => 1.0,
+            };
             Ok(Arc::new(PerspectiveCamera::new(transform,
-                                               width as u32,
-                                               height as u32,
+                                               shutter,
+                                               width,
+                                               height,
                                                fov.to_radians(),
                                                near,
-                                               far)) as Arc<Camera + Sync + Send>)
+                                               far,
+                                               lens_radius,
+                                               focal_distance)) as Arc<Camera + Sync + Send>)
+        }
+        "Orthographic" => {
+            let size = try!(data.pointer("/size").ok_or(Error::MissingKey("size")));
+            let size = try!(try_get_f64(size, "size"));
+            let (near, far) = try!(parse_clip_planes(data));
+            Ok(Arc::new(OrthographicCamera::new(transform,
+                                                shutter,
+                                                width,
+                                                height,
+                                                size,
+                                                near,
+                                                far)) as Arc<Camera + Sync + Send>)
+        }
+        "Spherical" => {
+            Ok(Arc::new(SphericalCamera::new(transform, shutter, width, height)) as
+               Arc<Camera + Sync + Send>)
+        }
+        "Fisheye" => {
+            let fov = match data.pointer("/fov") {
+                Some(fov) => try!(try_get_f64(fov, "fov")),
+                None => 180.0,
+            };
+            Ok(Arc::new(FisheyeCamera::new(transform,
+                                           shutter,
+                                           width,
+                                           height,
+                                           fov.to_radians())) as Arc<Camera + Sync + Send>)
         }
         _ => panic!("Unrecognised camera type: {}", camera_type),
     }
 }
 
+fn parse_clip_planes(data: &Value) -> Result<(Scalar, Scalar)> {
+    let near = try!(data.pointer("/near").ok_or(Error::MissingKey("near")));
+    let near = try!(try_get_f64(near, "near"));
+
+    let far = try!(data.pointer("/far").ok_or(Error::MissingKey("far")));
+    let far = try!(try_get_f64(far, "far"));
+
+    Ok((near, far))
+}
+
+/// Parse the times the shutter opens and closes.
+///
+/// Structure: [0.0, 1.0]
+fn parse_shutter(data: &Value) -> Result<(Scalar, Scalar)> {
+    let data = try!(data.as_array().ok_or(Error::ExpectedArray("shutter")));
+    if data.len() != 2 {
+        return Err(Error::MalformedShutter("Array of two elements expected."));
+    }
+
+    let open = try!(try_get_f64(&data[0], "open"));
+    let close = try!(try_get_f64(&data[1], "close"));
+    if close < open {
+        return Err(Error::MalformedShutter("Shutter must not close before it opens."));
+    }
+
+    Ok((open, close))
+}
+
 fn parse_views(data: &Value,
                cameras: &HashMap<String, Arc<Camera + Sync + Send>>)
                -> Result<HashMap<String, View>> {
@@ -267,7 +440,166 @@ fn parse_view(data: &Value, cameras: &HashMap<String, Arc<Camera + Sync + Send>>
         _ => panic!("Unrecognised renderer: {}", renderer),
     };
 
-    Ok(View::new(camera.clone(), samples as u32, depth as i32, renderer))
+    let sampler = match data.pointer("/sampler") {
+        Some(sampler) => try!(parse_sampler(sampler, samples as u32)),
+        None => Box::new(IndependentSampler::new(samples as u32, 0)) as Box<Sampler + Sync + Send>,
+    };
+
+    let filter = match data.pointer("/filter") {
+        Some(filter) => try!(parse_filter(filter)),
+        None => Arc::new(BoxFilter::new((0.5, 0.5))) as Arc<Filter + Sync + Send>,
+    };
+
+    let tonemap = match data.pointer("/tonemap") {
+        Some(tonemap) => try!(parse_tonemap(tonemap)),
+        None => ToneMap::default(),
+    };
+
+    Ok(View::new(camera.clone(),
+                 samples as u32,
+                 depth as i32,
+                 renderer,
+                 sampler,
+                 filter,
+                 tonemap))
+}
+
+/// Parse the tone mapping applied when writing 8 bit images.
+///
+/// Structure:
+/// {
+///     "operator": "Clamp" | "Reinhard" | "Filmic" | "ACES",
+///     "exposure": 0.0,
+///     "white": 4.0,
+///     "srgb": true
+/// }
+///
+/// All of the fields are optional. "exposure" is given in stops
+/// and "white" is the radiance mapped to white by the "Reinhard"
+/// operator, by default nothing is mapped to white.
+fn parse_tonemap(data: &Value) -> Result<ToneMap> {
+    let data = try!(data.as_object().ok_or(Error::ExpectedObject("tonemap")));
+    let default = ToneMap::default();
+
+    let operator = match data.get("operator") {
+        Some(operator) => {
+            match try!(try_get_string(operator, "operator")) {
+                "Clamp" => ToneMapOperator::Clamp,
+                "Reinhard" => {
+                    let white = match data.get("white") {
+                        Some(white) => try!(try_get_f64(white, "white")),
+                        None => ::std::f64::INFINITY,
+                    };
+                    ToneMapOperator::Reinhard(white)
+                }
+                "Filmic" => ToneMapOperator::Filmic,
+                "ACES" => ToneMapOperator::Aces,
+                operator => panic!("Unrecognised tone map operator: {}", operator),
+            }
+        }
+        None => default.operator,
+    };
+
+    let exposure = match data.get("exposure") {
+        Some(exposure) => try!(try_get_f64(exposure, "exposure")),
+        None => default.exposure,
+    };
+
+    let srgb = match data.get("srgb") {
+        Some(srgb) => try!(srgb.as_bool().ok_or(Error::ExpectedBool("srgb"))),
+        None => default.srgb,
+    };
+
+    Ok(ToneMap::new(exposure, operator, srgb))
+}
+
+/// Parse the filter used to reconstruct pixels from their samples.
+///
+/// Structure:
+/// {
+///     "type": "Box" | "Triangle" | "Gaussian" | "Mitchell" | "Lanczos",
+///     "radius": 2.0,
+///     "alpha": 2.0,
+///     "b": 0.33,
+///     "c": 0.33,
+///     "tau": 3.0
+/// }
+///
+/// "radius" is optional and defaults to a typical width for
+/// the type of filter. "alpha" is only used by the "Gaussian"
+/// filter, "b" and "c" by the "Mitchell" filter and "tau" by
+/// the "Lanczos" filter, all of them are optional.
+fn parse_filter(data: &Value) -> Result<Arc<Filter + Sync + Send>> {
+    let data = try!(data.as_object().ok_or(Error::ExpectedObject("filter")));
+
+    let get_or = |key: &'static str, default: Scalar| -> Result<Scalar> {
+        match data.get(key) {
+            Some(value) => try_get_f64(value, key),
+            None => Ok(default),
+        }
+    };
+
+    let filter_type = try!(data.get("type").ok_or(Error::MissingKey("type")));
+    let filter_type = try!(try_get_string(filter_type, "type"));
+    match filter_type {
+        "Box" => {
+            let r = try!(get_or("radius", 0.5));
+            Ok(Arc::new(BoxFilter::new((r, r))) as Arc<Filter + Sync + Send>)
+        }
+        "Triangle" => {
+            let r = try!(get_or("radius", 2.0));
+            Ok(Arc::new(TriangleFilter::new((r, r))) as Arc<Filter + Sync + Send>)
+        }
+        "Gaussian" => {
+            let r = try!(get_or("radius", 1.5));
+            let alpha = try!(get_or("alpha", 2.0));
+            Ok(Arc::new(GaussianFilter::new((r, r), alpha)) as Arc<Filter + Sync + Send>)
+        }
+        "Mitchell" => {
+            let r = try!(get_or("radius", 2.0));
+            let b = try!(get_or("b", 1.0 / 3.0));
+            let c = try!(get_or("c", 1.0 / 3.0));
+            Ok(Arc::new(MitchellFilter::new((r, r), b, c)) as Arc<Filter + Sync + Send>)
+        }
+        "Lanczos" => {
+            let r = try!(get_or("radius", 4.0));
+            let tau = try!(get_or("tau", 3.0));
+            Ok(Arc::new(LanczosFilter::new((r, r), tau)) as Arc<Filter + Sync + Send>)
+        }
+        _ => panic!("Unrecognised filter type: {}", filter_type),
+    }
+}
+
+/// Parse the sampler used to generate the samples for each pixel.
+///
+/// Structure:
+/// {
+///     "type": "Independent" | "Stratified" | "Halton" | "Sobol",
+///     "dimensions": 8
+/// }
+///
+/// "dimensions" is the number of stratified dimensions
+/// and is only used by the "Stratified" sampler.
+fn parse_sampler(data: &Value, samples: u32) -> Result<Box<Sampler + Sync + Send>> {
+    let data = try!(data.as_object().ok_or(Error::ExpectedObject("sampler")));
+
+    let sampler_type = try!(data.get("type").ok_or(Error::MissingKey("type")));
+    let sampler_type = try!(try_get_string(sampler_type, "type"));
+    match sampler_type {
+        "Independent" => {
+            Ok(Box::new(IndependentSampler::new(samples, 0)) as Box<Sampler + Sync + Send>)
+        }
+        "Stratified" => {
+            let dimensions = match data.get("dimensions") {
+                Some(dimensions) => try!(try_get_u64(dimensions, "dimensions")) as usize,
+                None => 8,
+            };
+            Ok(Box::new(StratifiedSampler::new(samples, dimensions, 0)) as Box<Sampler + Sync + Send>)
+        }
+        "Halton" => Ok(Box::new(HaltonSampler::new(samples, 0)) as Box<Sampler + Sync + Send>),
+        "Sobol" => Ok(Box::new(SobolSampler::new(samples, 0)) as Box<Sampler + Sync + Send>),
+        _ => panic!("Unrecognised sampler type: {}", sampler_type),
+    }
 }
 
 fn parse_materials(data: &Value) -> Result<HashMap<String, Arc<Material + Sync + Send>>> {
@@ -280,25 +612,237 @@ fn parse_materials(data: &Value) -> Result<HashMap<String, Arc<Material + Sync +
     Ok(materials)
 }
 
+/// Parse a material, which may perturb its shading
+/// normal with a texture.
+///
+/// Structure:
+/// {
+///     "type": "Diffuse",
+///     "texture": { ... },
+///     "normal_map": { "type": "Image", "filename": "normals.png" },
+///     "bump_map": { "type": "Image", "filename": "height.png" },
+///     "bump_scale": 0.01
+/// }
+///
+/// "normal_map" is a tangent space normal map and "bump_map"
+/// a height map, scaled by "bump_scale" (default 1). If both
+/// are given the bump map is applied first and the normal map
+/// then perturbs the bumped normal.
 fn parse_material(data: &Value) -> Result<Arc<Material + Sync + Send>> {
     let data = try!(data.as_object().ok_or(Error::ExpectedObject("material")));
 
     let material_type = try!(data.get("type").ok_or(Error::MissingKey("type")));
     let material_type = try!(try_get_string(material_type, "type"));
-    match material_type {
-        "Glass" => Ok(Arc::new(GlassMaterial) as Arc<Material + Sync + Send>),
-        "Mirror" => Ok(Arc::new(MirrorMaterial) as Arc<Material + Sync + Send>),
-        "Diffuse" => {
-            Ok(Arc::new(try!(parse_diffuse_material(data))) as Arc<Material + Sync + Send>)
+    let mut material = match material_type {
+        "Glass" => Arc::new(try!(parse_glass_material(data))) as Arc<Material + Sync + Send>,
+        "Mirror" => Arc::new(MirrorMaterial) as Arc<Material + Sync + Send>,
+        "Metal" => Arc::new(try!(parse_metal_material(data))) as Arc<Material + Sync + Send>,
+        "Plastic" => {
+            Arc::new(try!(parse_plastic_material(data))) as Arc<Material + Sync + Send>
         }
+        "Coated" => {
+            Arc::new(try!(parse_coated_material(data))) as Arc<Material + Sync + Send>
+        }
+        "Diffuse" => Arc::new(try!(parse_diffuse_material(data))) as Arc<Material + Sync + Send>,
         _ => panic!("Unrecognised material type: {}", material_type),
+    };
+
+    if let Some(bump_map) = data.get("bump_map") {
+        let texture = try!(parse_texture(bump_map));
+        let scale = match data.get("bump_scale") {
+            Some(scale) => try!(try_get_f64(scale, "bump_scale")),
+            None => 1.0,
+        };
+        material = Arc::new(MappedMaterial::new(material, ShadingMap::Bump(texture, scale)));
+    }
+    if let Some(normal_map) = data.get("normal_map") {
+        let texture = try!(parse_texture(normal_map));
+        material = Arc::new(MappedMaterial::new(material, ShadingMap::Normal(texture)));
     }
+    Ok(material)
 }
 
+/// Parse a diffuse material, which is rough if given a
+/// "roughness", the standard deviation of the angle of its
+/// facets in radians as a number or a texture.
 fn parse_diffuse_material(data: &Map<String, Value>) -> Result<DiffuseMaterial> {
     let texture = try!(data.get("texture").ok_or(Error::MissingKey("texture")));
     let texture = try!(parse_texture(texture));
-    Ok(DiffuseMaterial::new(texture))
+    match data.get("roughness") {
+        Some(roughness) => {
+            let roughness = try!(parse_scalar_texture(roughness, "roughness"));
+            Ok(DiffuseMaterial::new_with_roughness(texture, roughness))
+        }
+        None => Ok(DiffuseMaterial::new(texture)),
+    }
+}
+
+/// Parse a dielectric such as glass.
+///
+/// Structure (all optional):
+/// {
+///     "type": "Glass",
+///     "ior": 1.5,
+///     "reflectance": [1.0, 1.0, 1.0],
+///     "transmittance": [1.0, 1.0, 1.0],
+///     "roughness": 0.1,
+///     "roughness_v": 0.2,
+///     "absorption": [0.5, 0.1, 0.1],
+///     "thin": false
+/// }
+///
+/// "absorption" is the Beer-Lambert absorption coefficient,
+/// the rate per unit distance at which light is absorbed
+/// inside the glass, so exp(-absorption * distance) of it
+/// is transmitted. "thin" glass, such as a window pane,
+/// lets light pass straight through it.
+fn parse_glass_material(data: &Map<String, Value>) -> Result<GlassMaterial> {
+    let ior = match data.get("ior") {
+        Some(ior) => try!(try_get_f64(ior, "ior")),
+        None => 1.5,
+    };
+    let mut glass = GlassMaterial::new(ior);
+    if let Some(reflectance) = data.get("reflectance") {
+        glass.reflectance = try!(parse_spectrum(reflectance));
+    }
+    if let Some(transmittance) = data.get("transmittance") {
+        glass.transmittance = try!(parse_spectrum(transmittance));
+    }
+    glass.roughness = try!(parse_roughness(data));
+    if let Some(absorption) = data.get("absorption") {
+        glass.absorption = Some(try!(parse_spectrum(absorption)));
+    }
+    if let Some(thin) = data.get("thin") {
+        glass.thin = try!(thin.as_bool().ok_or(Error::ExpectedBool("thin")));
+    }
+    Ok(glass)
+}
+
+/// Parse plastic, a diffuse "texture" under a dielectric coat.
+///
+/// Structure:
+/// {
+///     "type": "Plastic",
+///     "texture": { ... },
+///     "specular": [1.0, 1.0, 1.0],
+///     "ior": 1.5,
+///     "roughness": 0.2
+/// }
+///
+/// All but the texture are optional and describe the coat
+/// as for "Coated".
+fn parse_plastic_material(data: &Map<String, Value>) -> Result<PlasticMaterial> {
+    let texture = try!(data.get("texture").ok_or(Error::MissingKey("texture")));
+    let mut plastic = PlasticMaterial::new(try!(parse_texture(texture)));
+    let (specular, ior, roughness) = try!(parse_coat(data));
+    plastic.specular = specular;
+    plastic.ior = ior;
+    plastic.roughness = roughness;
+    Ok(plastic)
+}
+
+/// Parse any "base" material under a dielectric coat.
+///
+/// Structure:
+/// {
+///     "type": "Coated",
+///     "base": { "type": "Metal", "preset": "copper" },
+///     "specular": [1.0, 1.0, 1.0],
+///     "ior": 1.5,
+///     "roughness": 0.0
+/// }
+///
+/// The coat's "specular" tints the light it reflects and its
+/// "ior" sets how much it reflects. It is smooth unless given
+/// a "roughness", as for "Metal".
+fn parse_coated_material(data: &Map<String, Value>) -> Result<CoatedMaterial> {
+    let base = try!(data.get("base").ok_or(Error::MissingKey("base")));
+    let mut coated = CoatedMaterial::new(try!(parse_material(base)));
+    let (specular, ior, roughness) = try!(parse_coat(data));
+    coated.specular = specular;
+    coated.ior = ior;
+    coated.roughness = roughness;
+    Ok(coated)
+}
+
+/// Parse the tint, refractive index and roughness of a coat.
+fn parse_coat(data: &Map<String, Value>) -> Result<(Spectrum, Scalar, Option<Roughness>)> {
+    let specular = match data.get("specular") {
+        Some(specular) => try!(parse_spectrum(specular)),
+        None => Spectrum::new(1.0, 1.0, 1.0),
+    };
+    let ior = match data.get("ior") {
+        Some(ior) => try!(try_get_f64(ior, "ior")),
+        None => 1.5,
+    };
+    Ok((specular, ior, try!(parse_roughness(data))))
+}
+
+/// Parse the "roughness" of a glossy material along dp/du
+/// and dp/dv, which is the same along both unless a
+/// "roughness_v" is given.
+fn parse_roughness(data: &Map<String, Value>) -> Result<Option<Roughness>> {
+    let roughness = match data.get("roughness") {
+        Some(roughness) => roughness,
+        None if data.contains_key("roughness_v") => return Err(Error::MissingKey("roughness")),
+        None => return Ok(None),
+    };
+    let roughness_u: Arc<Texture + Sync + Send> =
+        Arc::from(try!(parse_scalar_texture(roughness, "roughness")));
+    let roughness_v: Arc<Texture + Sync + Send> = match data.get("roughness_v") {
+        Some(roughness_v) => Arc::from(try!(parse_scalar_texture(roughness_v, "roughness_v"))),
+        None => roughness_u.clone(),
+    };
+    Ok(Some((roughness_u, roughness_v)))
+}
+
+/// Parse a metal given by the complex refractive index of
+/// the conductor, either as "eta" and "k" spectra or a named
+/// "preset" (gold, silver, copper, aluminium or chrome) that
+/// they override.
+///
+/// Metals are smooth mirrors unless given a "roughness" in
+/// [0, 1], as a number or a texture, which is along dp/du if
+/// "roughness_v" is given for the roughness along dp/dv.
+fn parse_metal_material(data: &Map<String, Value>) -> Result<MetalMaterial> {
+    let preset = match data.get("preset") {
+        Some(preset) => {
+            let preset = try!(try_get_string(preset, "preset"));
+            let conductor = material::conductor_preset(preset);
+            Some(try!(conductor.ok_or_else(|| Error::MissingPreset(preset.to_string()))))
+        }
+        None => None,
+    };
+    let eta = match (data.get("eta"), preset) {
+        (Some(eta), _) => try!(parse_spectrum(eta)),
+        (None, Some((eta, _))) => eta,
+        (None, None) => return Err(Error::MissingKey("eta")),
+    };
+    let k = match (data.get("k"), preset) {
+        (Some(k), _) => try!(parse_spectrum(k)),
+        (None, Some((_, k))) => k,
+        (None, None) => return Err(Error::MissingKey("k")),
+    };
+
+    match try!(parse_roughness(data)) {
+        Some((roughness_u, roughness_v)) => {
+            Ok(MetalMaterial::new_with_roughness(eta, k, roughness_u, roughness_v))
+        }
+        None => Ok(MetalMaterial::new(eta, k)),
+    }
+}
+
+/// Parse a scalar that may vary over a surface, either
+/// a number or a texture whose luminance gives the value.
+fn parse_scalar_texture(data: &Value, name: &'static str) -> Result<Box<Texture + Sync + Send>> {
+    match *data {
+        Value::Object(_) => parse_texture(data),
+        _ => {
+            let value = try!(try_get_f64(data, name));
+            Ok(Box::new(ConstantTexture::new(Spectrum::new(value, value, value))) as
+               Box<Texture + Sync + Send>)
+        }
+    }
 }
 
 fn parse_texture(data: &Value) -> Result<Box<Texture + Sync + Send>> {
@@ -330,24 +874,74 @@ fn parse_image_texture(data: &Map<String, Value>) -> Result<ImageTexture> {
     Ok(ImageTexture::new(image.clone()))
 }
 
+/// Parse a map of names to geometry that can be
+/// shared between many objects.
+///
+/// Structure:
+/// {
+///     "geometry": {
+///         "tree": {
+///             "shape": "Mesh",
+///             "filename": "tree.obj"
+///         }
+///     }
+/// }
+fn parse_geometries(data: &Value) -> Result<HashMap<String, Geometry>> {
+    let data = try!(data.as_object().ok_or(Error::ExpectedObject("geometry")));
+
+    let mut geometries = HashMap::new();
+    for (name, value) in data.iter() {
+        let value = try!(try_get_object(value, "geometry"));
+        let geometry = try!(parse_shape(value));
+        geometries.insert(name.clone(), geometry);
+    }
+    Ok(geometries)
+}
+
+/// Parse the objects in the scene, returning them along
+/// with the area lights for any objects that are emitters.
 fn parse_objects(data: &Value,
-                 materials: &HashMap<String, Arc<Material + Sync + Send>>)
-                 -> Result<Vec<Arc<SceneNode>>> {
+                 materials: &HashMap<String, Arc<Material + Sync + Send>>,
+                 geometries: &HashMap<String, Geometry>)
+                 -> Result<(Vec<Arc<SceneNode>>, Vec<Arc<Light + Sync + Send>>)> {
     let data = try!(data.as_object().ok_or(Error::ExpectedObject("objects")));
 
     // let mut objects = HashMap::new();
     let mut objects = Vec::new();
+    let mut lights = Vec::new();
     for (_name, value) in data.iter() {
-        let object = Arc::new(try!(parse_object(value, materials)));
+        let (object, light) = try!(parse_object(value, materials, geometries));
         // objects.insert(*name, object);
-        objects.push(object);
+        objects.push(Arc::new(object));
+        if let Some(light) = light {
+            lights.push(light);
+        }
     }
-    Ok(objects)
+    Ok((objects, lights))
 }
 
+/// Parse an object placed in the scene.
+///
+/// Structure:
+/// {
+///     "material": "white",
+///     "transform": { ... },
+///     "shape": "Ball",
+///     ...
+/// }
+/// or
+/// {
+///     "material": "white",
+///     "transform": { ... },
+///     "geometry": "tree"
+/// }
+///
+/// An object either has its own "shape" or is an instance of
+/// shared "geometry", placed with its own transform and material.
 fn parse_object(data: &Value,
-                materials: &HashMap<String, Arc<Material + Sync + Send>>)
-                -> Result<SceneNode> {
+                materials: &HashMap<String, Arc<Material + Sync + Send>>,
+                geometries: &HashMap<String, Geometry>)
+                -> Result<(SceneNode, Option<Arc<Light + Sync + Send>>)> {
     let data = try!(data.as_object().ok_or(Error::ExpectedObject("object")));
 
     let material = try!(data.get("material").ok_or(Error::MissingKey("material")));
@@ -355,43 +949,117 @@ fn parse_object(data: &Value,
     let material = materials.get(material)
         .expect(&format!("No Material found with name: {}", material));
 
-    let transform = try!(data.get("transform").ok_or(Error::MissingKey("transform")));
-    let transform = try!(parse_transform(transform));
+    let transform = try!(parse_animated_transform(data));
+
+    let geometry = match data.get("geometry") {
+        Some(name) => {
+            let name = try!(try_get_string(name, "geometry"));
+            match geometries.get(name) {
+                Some(geometry) => geometry.clone(),
+                None => return Err(Error::MissingGeometry(name.to_string())),
+            }
+        }
+        None => try!(parse_shape(data)),
+    };
+
+    // any object can emit light from its surface
+    let light = match data.get("emission") {
+        Some(emission) => {
+            let emission = try!(parse_spectrum(emission));
+            Some(Arc::new(DiffuseLight::new(emission, transform.clone(), geometry.emitter.clone())))
+        }
+        None => None,
+    };
+
+    let node = SceneNode::new(transform,
+                              material.clone(),
+                              &geometry,
+                              light.clone().map(|l| l as Arc<AreaLight + Sync + Send>));
+    Ok((node, light.map(|l| l as Arc<Light + Sync + Send>)))
+}
 
+fn parse_shape(data: &Map<String, Value>) -> Result<Geometry> {
     let shape = try!(data.get("shape").ok_or(Error::MissingKey("shape")));
     let shape = try!(try_get_string(shape, "shape"));
-    let (shape, aabb) = match shape {
-        "Cuboid" => try!(parse_cuboid(data, &transform)),
-        "Ball" => try!(parse_ball(data, &transform)),
+    match shape {
+        "Cuboid" => parse_cuboid(data),
+        "Ball" => parse_ball(data),
+        "Mesh" => parse_mesh(data),
         _ => panic!("Unrecognised shape: {}", shape),
-    };
-
-    Ok(SceneNode::new(transform, material.clone(), shape, aabb))
+    }
 }
 
-fn parse_cuboid(data: &Map<String, Value>,
-                transform: &Isometry3<Scalar>)
-                -> Result<(Intersectable, AABB3<Scalar>)> {
+fn parse_cuboid(data: &Map<String, Value>) -> Result<Geometry> {
     let extents = try!(data.get("extents").ok_or(Error::MissingKey("extents")));
     let extents = try!(parse_vector(extents));
 
-    let cuboid = Cuboid::new(extents);
-    let aabb = cuboid.aabb(transform);
-    Ok((Box::new(cuboid) as Box<RayCast<Point, Isometry3<Scalar>> + Sync + Send>, aabb))
+    let cuboid = Arc::new(Cuboid::new(extents));
+    let aabb = cuboid.aabb(&Isometry3::identity());
+    Ok(Geometry::new(cuboid.clone() as Intersectable, cuboid as Emitter, aabb))
 }
 
-fn parse_ball(data: &Map<String, Value>,
-              transform: &Isometry3<Scalar>)
-              -> Result<(Intersectable, AABB3<Scalar>)> {
+fn parse_ball(data: &Map<String, Value>) -> Result<Geometry> {
     let radius = try!(data.get("radius").ok_or(Error::MissingKey("radius")));
     let radius = try!(try_get_f64(radius, "radius"));
 
-    let ball = Ball::new(radius);
-    let aabb = ball.aabb(transform);
-    Ok((Box::new(ball) as Box<RayCast<Point, Isometry3<Scalar>> + Sync + Send>, aabb))
+    let ball = Arc::new(Ball::new(radius));
+    let aabb = ball.aabb(&Isometry3::identity());
+    Ok(Geometry::new(ball.clone() as Intersectable, ball as Emitter, aabb))
 }
 
-fn parse_lights(data: &Value) -> Result<Vec<Box<Light + Sync + Send>>> {
+/// Parse a triangle mesh loaded from a Wavefront .obj file.
+///
+/// All models in the file are merged into a single mesh
+/// unless a "model" is given, either by name or by its
+/// index in the file. Models without any triangles are
+/// left out and not counted.
+///
+/// It is an error for the file to have no triangles.
+///
+/// Meshes without normals in the file are shaded flat
+/// unless "smooth" is true, in which case each vertex's
+/// normal is averaged from the faces around it.
+fn parse_mesh(data: &Map<String, Value>) -> Result<Geometry> {
+    let filename = try!(data.get("filename").ok_or(Error::MissingKey("filename")));
+    let filename = try!(try_get_string(filename, "filename"));
+    // TODO: use a centralised location for loading/storing assets
+    let meshes = try!(assets::load_obj(&Path::new(filename)));
+
+    let mut mesh = match data.get("model") {
+        Some(&Value::String(ref name)) => {
+            match meshes.into_iter().find(|&(ref n, _)| n == name) {
+                Some((_, mesh)) => mesh,
+                None => return Err(Error::MissingModel(name.clone())),
+            }
+        }
+        Some(model) => {
+            let index = try!(try_get_u64(model, "model")) as usize;
+            match meshes.into_iter().nth(index) {
+                Some((_, mesh)) => mesh,
+                None => return Err(Error::MissingModel(index.to_string())),
+            }
+        }
+        None if meshes.is_empty() => return Err(Error::EmptyMesh(filename.to_string())),
+        None => {
+            let meshes: Vec<_> = meshes.into_iter().map(|(_, mesh)| mesh).collect();
+            assets::merge_meshes(&meshes)
+        }
+    };
+
+    let smooth = match data.get("smooth") {
+        Some(smooth) => try!(smooth.as_bool().ok_or(Error::ExpectedBool("smooth"))),
+        None => false,
+    };
+    if smooth {
+        mesh.smooth_normals();
+    }
+
+    let mesh = Arc::new(mesh);
+    let aabb = mesh.aabb().clone();
+    Ok(Geometry::new(mesh.clone() as Intersectable, mesh as Emitter, aabb))
+}
+
+fn parse_lights(data: &Value) -> Result<Vec<Arc<Light + Sync + Send>>> {
     let data = try!(data.as_object().ok_or(Error::ExpectedObject("lights")));
 
     let mut lights = Vec::new();
@@ -402,7 +1070,7 @@ fn parse_lights(data: &Value) -> Result<Vec<Box<Light + Sync + Send>>> {
     Ok(lights)
 }
 
-fn parse_light(data: &Value) -> Result<Box<Light + Sync + Send>> {
+fn parse_light(data: &Value) -> Result<Arc<Light + Sync + Send>> {
     let data = try!(data.as_object().ok_or(Error::ExpectedObject("light")));
 
     let light_type = try!(data.get("type").ok_or(Error::MissingKey("type")));
@@ -412,7 +1080,10 @@ fn parse_light(data: &Value) -> Result<Box<Light + Sync + Send>> {
     let colour = try!(parse_spectrum(colour));
 
     match light_type {
-        "Point" => Ok(Box::new(try!(parse_point_light(data, colour))) as Box<Light + Sync + Send>),
+        "Point" => Ok(Arc::new(try!(parse_point_light(data, colour))) as Arc<Light + Sync + Send>),
+        "Infinite" => {
+            Ok(Arc::new(try!(parse_infinite_light(data, colour))) as Arc<Light + Sync + Send>)
+        }
         _ => panic!("Unrecognised light type: {}", light_type),
     }
 }
@@ -427,6 +1098,29 @@ fn parse_point_light(data: &Map<String, Value>, colour: Spectrum) -> Result<Poin
     Ok(PointLight::new(1.0, colour, position, radius))
 }
 
+/// Parse an environment light surrounding the scene.
+///
+/// The radiance is read from an equirectangular image
+/// if a "filename" is given, otherwise the light has
+/// the same colour in every direction.
+fn parse_infinite_light(data: &Map<String, Value>, colour: Spectrum) -> Result<InfiniteAreaLight> {
+    let transform = match data.get("transform") {
+        Some(transform) => try!(parse_rigid_transform(transform)),
+        None => Isometry3::identity(),
+    };
+
+    match data.get("filename") {
+        Some(filename) => {
+            let filename = try!(try_get_string(filename, "filename"));
+            // TODO: use a centralised location for loading/storing assets
+            let (width, height, texels) =
+                try!(assets::load_radiance_image(&Path::new(filename)));
+            Ok(InfiniteAreaLight::new(colour, transform, width, height, texels))
+        }
+        None => Ok(InfiniteAreaLight::constant(colour)),
+    }
+}
+
 fn parse_vector(data: &Value) -> Result<Vector> {
     let data = try!(data.as_array().ok_or(Error::ExpectedArray("vector")));
     if data.len() != 3 {
@@ -453,18 +1147,201 @@ fn parse_point(data: &Value) -> Result<Point> {
     Ok(Point::new(x, y, z))
 }
 
-fn parse_transform(data: &Value) -> Result<Isometry3<Scalar>> {
+/// Parse an affine transform.
+///
+/// Structure:
+/// {
+///     "position": [0.0, 0.0, 0.0],
+///     "rotation": [0.0, 0.0, 0.0],
+///     "quaternion": [0.0, 0.0, 0.0, 1.0],
+///     "euler": [0.0, 0.0, 0.0],
+///     "look_at": [0.0, 0.0, -1.0],
+///     "up": [0.0, 1.0, 0.0],
+///     "scale": [1.0, 1.0, 1.0]
+/// }
+/// or
+/// {
+///     "matrix": [[1.0, 0.0, 0.0, 0.0],
+///                [0.0, 1.0, 0.0, 0.0],
+///                [0.0, 0.0, 1.0, 0.0],
+///                [0.0, 0.0, 0.0, 1.0]]
+/// }
+///
+/// The "scale" is applied first, then the rotation and then
+/// the translation to "position". It may be a single number
+/// to scale uniformly. A "matrix" is given by rows and
+/// replaces all the other fields, its bottom row must be
+/// [0, 0, 0, 1].
+///
+/// At most one of "rotation", "quaternion", "euler" and "look_at"
+/// may be given, with no rotation if none of them are.
+///
+/// * "rotation" is an axis scaled by an angle in radians.
+/// * "quaternion" is given as [x, y, z, w] and is normalised.
+/// * "euler" is the angles in degrees to rotate about the X,
+///   then Y, then Z axes.
+/// * "look_at" turns the -Z axis towards a point, keeping +Y
+///   as close to "up" as possible, which defaults to +Y.
+///
+/// All transforms are right-handed, with +Y up in world space.
+/// Cameras look down their local -Z axis.
+fn parse_transform(data: &Value) -> Result<Transform> {
     let data = try!(data.as_object().ok_or(Error::ExpectedObject("transform")));
 
+    if let Some(matrix) = data.get("matrix") {
+        if data.len() > 1 {
+            return Err(Error::MalformedTransform("No other fields may be given with a matrix."));
+        }
+        let matrix = try!(parse_matrix(matrix));
+        return Transform::from_matrix(&matrix)
+            .ok_or(Error::MalformedTransform("Matrix must be affine and invertible."));
+    }
+
     let position = try!(data.get("position").ok_or(Error::MissingKey("position")));
     let position = try!(parse_vector(position));
 
-    let rotation = match data.get("rotation") {
-        Some(rot) => try!(parse_vector(rot)),
-        None => na::zero(),
+    let rotations = ["rotation", "quaternion", "euler", "look_at"];
+    if rotations.iter().filter(|key| data.contains_key(**key)).count() > 1 {
+        return Err(Error::MalformedTransform("Only one kind of rotation may be given."));
+    }
+
+    let rotation = if let Some(rot) = data.get("rotation") {
+        UnitQuaternion::new(try!(parse_vector(rot)))
+    } else if let Some(quat) = data.get("quaternion") {
+        let quat = try!(quat.as_array().ok_or(Error::ExpectedArray("quaternion")));
+        if quat.len() != 4 {
+            return Err(Error::MalformedTransform("Quaternion of four elements expected."));
+        }
+        let x = try!(try_get_f64(&quat[0], "x"));
+        let y = try!(try_get_f64(&quat[1], "y"));
+        let z = try!(try_get_f64(&quat[2], "z"));
+        let w = try!(try_get_f64(&quat[3], "w"));
+        UnitQuaternion::new_normalize(Quaternion::new(w, x, y, z))
+    } else if let Some(euler) = data.get("euler") {
+        let euler = try!(parse_vector(euler));
+        UnitQuaternion::from_euler_angles(euler.x.to_radians(),
+                                          euler.y.to_radians(),
+                                          euler.z.to_radians())
+    } else if let Some(target) = data.get("look_at") {
+        let target = try!(parse_point(target));
+        let up = match data.get("up") {
+            Some(up) => try!(parse_vector(up)),
+            None => Vector::new(0.0, 1.0, 0.0),
+        };
+        let eye = Point::from_coordinates(position);
+        if na::norm(&(target - eye)) == 0.0 || na::norm(&up.cross(&(target - eye))) == 0.0 {
+            return Err(Error::MalformedTransform("Look at target must not be at the position \
+                                                  or in the up direction."));
+        }
+        look_at(&eye, &target, &up).rotation
+    } else {
+        UnitQuaternion::identity()
     };
 
-    Ok(Isometry3::new(position, rotation))
+    let isometry = Isometry3::from_parts(Translation3::from_vector(position), rotation);
+    match data.get("scale") {
+        Some(scale) => {
+            let scale = match scale.as_f64() {
+                Some(scale) => Vector::new(scale, scale, scale),
+                None => try!(parse_vector(scale)),
+            };
+            let stretch = Matrix3::new(scale.x, 0.0, 0.0, 0.0, scale.y, 0.0, 0.0, 0.0, scale.z);
+            if scale.x >= 0.0 && scale.y >= 0.0 && scale.z >= 0.0 {
+                return Transform::new(isometry, stretch)
+                    .ok_or(Error::MalformedTransform("Scale must not be zero."));
+            }
+            // a mirroring scale is split up in the same way as a
+            // mirroring matrix, leaving a stretch that flips every
+            // axis, so any two mirrored keyframes can be interpolated
+            let linear = *rotation.to_rotation_matrix().matrix() * stretch;
+            Transform::from_linear(position, &linear)
+                .ok_or(Error::MalformedTransform("Scale must not be zero."))
+        }
+        None => Ok(Transform::from_isometry(isometry)),
+    }
+}
+
+/// Parse a transform that must be rigid, such as
+/// that of a light or camera.
+fn parse_rigid_transform(data: &Value) -> Result<Isometry3<Scalar>> {
+    let transform = try!(parse_transform(data));
+    if !transform.is_rigid() {
+        return Err(Error::MalformedTransform("Only a rigid transform may be given here."));
+    }
+    Ok(transform.isometry)
+}
+
+/// Parse a 4x4 matrix given as an array of rows.
+fn parse_matrix(data: &Value) -> Result<Matrix4<Scalar>> {
+    let rows = try!(data.as_array().ok_or(Error::ExpectedArray("matrix")));
+    if rows.len() != 4 {
+        return Err(Error::MalformedTransform("Matrix of four rows expected."));
+    }
+
+    let mut matrix = Matrix4::zeros();
+    for (i, row) in rows.iter().enumerate() {
+        let row = try!(row.as_array().ok_or(Error::ExpectedArray("matrix row")));
+        if row.len() != 4 {
+            return Err(Error::MalformedTransform("Matrix rows of four elements expected."));
+        }
+        for (j, value) in row.iter().enumerate() {
+            matrix[(i, j)] = try!(try_get_f64(value, "matrix element"));
+        }
+    }
+    Ok(matrix)
+}
+
+/// Parse the transform of an object or camera, which is
+/// either fixed or moves between keyframes.
+///
+/// Structure:
+/// {
+///     "transform": { ... }
+/// }
+/// or
+/// {
+///     "keyframes": [
+///         { "time": 0.0, "transform": { ... } },
+///         { "time": 1.0, "transform": { ... } }
+///     ]
+/// }
+///
+/// Between keyframes the position is interpolated linearly
+/// and the rotation spherically, before the first and after
+/// the last keyframe the transform is held. Either every
+/// keyframe or none of them may mirror the object.
+fn parse_animated_transform(data: &Map<String, Value>) -> Result<AnimatedTransform> {
+    let keyframes = match data.get("keyframes") {
+        Some(keyframes) => keyframes,
+        None => {
+            let transform = try!(data.get("transform").ok_or(Error::MissingKey("transform")));
+            return Ok(AnimatedTransform::fixed(try!(parse_transform(transform))));
+        }
+    };
+    if data.contains_key("transform") {
+        return Err(Error::MalformedTransform("Only one of transform and keyframes may be given."));
+    }
+
+    let keyframes = try!(keyframes.as_array().ok_or(Error::ExpectedArray("keyframes")));
+    if keyframes.is_empty() {
+        return Err(Error::MalformedTransform("At least one keyframe expected."));
+    }
+    let mut parsed: Vec<(Scalar, Transform)> = Vec::with_capacity(keyframes.len());
+    for keyframe in keyframes {
+        let keyframe = try!(try_get_object(keyframe, "keyframe"));
+        let time = try!(keyframe.get("time").ok_or(Error::MissingKey("time")));
+        let time = try!(try_get_f64(time, "time"));
+        let transform = try!(keyframe.get("transform").ok_or(Error::MissingKey("transform")));
+        let transform = try!(parse_transform(transform));
+        // the object would be flattened somewhere between
+        // a mirrored keyframe and one that isn't mirrored
+        if parsed.iter().any(|&(_, ref t)| t.is_mirrored() != transform.is_mirrored()) {
+            return Err(Error::MalformedTransform("Keyframes must all be mirrored or all not be \
+                                                  mirrored."));
+        }
+        parsed.push((time, transform));
+    }
+    Ok(AnimatedTransform::new(parsed))
 }
 
 fn parse_spectrum(data: &Value) -> Result<Spectrum> {
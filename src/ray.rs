@@ -6,6 +6,9 @@ use ncollide::query::Ray3;
 pub struct Ray {
     pub ray: Ray3<f64>,
     pub depth: i32,
+    /// The time within the camera's shutter interval that the
+    /// ray was sent at, used to place moving objects.
+    pub time: f64,
 }
 
 impl Ray {
@@ -14,9 +17,14 @@ impl Ray {
     }
 
     pub fn new_with_depth(orig: Point3<f64>, dir: Vector3<f64>, depth: i32) -> Ray {
+        Self::new_with_time(orig, dir, depth, 0.0)
+    }
+
+    pub fn new_with_time(orig: Point3<f64>, dir: Vector3<f64>, depth: i32, time: f64) -> Ray {
         Ray {
             ray: Ray3::new(orig, dir),
             depth: depth,
+            time: time,
         }
     }
 
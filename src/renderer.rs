@@ -1,15 +1,11 @@
-
-use rand::StdRng;
-
-use na;
-
-use integrator::Integrator;
+use integrator::{Integrator, escaped_radiance};
 use ray::Ray;
+use sampler::Sampler;
 use scene::Scene;
 use spectrum::Spectrum;
 
 pub trait Renderer {
-    fn render(&self, ray: &Ray, scene: &Scene, rng: &mut StdRng) -> Spectrum;
+    fn render(&self, ray: &Ray, scene: &Scene, sampler: &mut Sampler) -> Spectrum;
 }
 
 pub struct StandardRenderer {
@@ -23,12 +19,12 @@ impl StandardRenderer {
 }
 
 impl Renderer for StandardRenderer {
-    fn render(&self, ray: &Ray, scene: &Scene, rng: &mut StdRng) -> Spectrum {
+    fn render(&self, ray: &Ray, scene: &Scene, sampler: &mut Sampler) -> Spectrum {
         let isect_opt = scene.trace(ray);
 
         match isect_opt {
-            Some(isect) => self.integrator.integrate(ray, &isect, scene, self, rng),
-            None => na::zero(),
+            Some(isect) => self.integrator.integrate(ray, &isect, scene, self, sampler),
+            None => escaped_radiance(ray, scene),
         }
     }
 }
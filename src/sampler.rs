@@ -0,0 +1,492 @@
+use rand::{Rng, SeedableRng, XorShiftRng};
+
+use math::Scalar;
+
+// the largest representable Scalar less than one
+const ONE_MINUS_EPSILON: Scalar = 0.99999999999999989;
+
+// bases for each dimension of the Halton sequence,
+// dimensions past these fall back to hashed values
+const PRIMES: [u32; 32] = [2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67,
+                           71, 73, 79, 83, 89, 97, 101, 103, 107, 109, 113, 127, 131];
+
+/// A source of sample values in [0, 1) for the different
+/// dimensions of the integral being estimated for a pixel,
+/// e.g. the position on the image plane, choosing a light
+/// and sampling a direction from a BSDF.
+///
+/// Samples are generated for one pixel at a time, each
+/// pixel is started with `start_pixel` and each sample
+/// within it with `start_sample`. After that successive
+/// calls to `get_1d` and `get_2d` each consume the next
+/// dimension of the sample.
+///
+/// The values generated depend only on the sampler's seed,
+/// the pixel and the sample index, so an image renders the
+/// same no matter how its pixels are split between threads.
+pub trait Sampler {
+    fn start_pixel(&mut self, x: u32, y: u32);
+    fn start_sample(&mut self, index: u32);
+
+    fn get_1d(&mut self) -> Scalar;
+    fn get_2d(&mut self) -> (Scalar, Scalar);
+
+    fn samples_per_pixel(&self) -> u32;
+
+    /// Create a new sampler of the same type for use on another
+    /// thread that generates the given number of samples per
+    /// pixel from the given seed.
+    fn clone_seeded(&self, samples_per_pixel: u32, seed: u32) -> Box<Sampler + Send>;
+}
+
+/// Generates a uniformly distributed random
+/// value for every dimension of every sample.
+pub struct IndependentSampler {
+    samples_per_pixel: u32,
+    seed: u32,
+    pixel_seed: u32,
+    rng: XorShiftRng,
+}
+
+impl IndependentSampler {
+    pub fn new(samples_per_pixel: u32, seed: u32) -> IndependentSampler {
+        IndependentSampler {
+            samples_per_pixel: samples_per_pixel,
+            seed: seed,
+            pixel_seed: 0,
+            rng: seeded_rng(seed),
+        }
+    }
+}
+
+impl Sampler for IndependentSampler {
+    #[inline]
+    fn start_pixel(&mut self, x: u32, y: u32) {
+        self.pixel_seed = pixel_seed(self.seed, x, y);
+        self.start_sample(0);
+    }
+
+    #[inline]
+    fn start_sample(&mut self, index: u32) {
+        // every sample has its own stream of random numbers
+        self.rng = seeded_rng(hash_combine(self.pixel_seed, index));
+    }
+
+    #[inline]
+    fn get_1d(&mut self) -> Scalar {
+        self.rng.next_f64()
+    }
+
+    #[inline]
+    fn get_2d(&mut self) -> (Scalar, Scalar) {
+        (self.rng.next_f64(), self.rng.next_f64())
+    }
+
+    #[inline]
+    fn samples_per_pixel(&self) -> u32 {
+        self.samples_per_pixel
+    }
+
+    fn clone_seeded(&self, samples_per_pixel: u32, seed: u32) -> Box<Sampler + Send> {
+        Box::new(IndependentSampler::new(samples_per_pixel, seed))
+    }
+}
+
+/// Divides each of the first few dimensions into strata,
+/// one per sample in the pixel, and jitters each sample
+/// within its stratum. 2D dimensions use a jittered grid
+/// when the number of samples is square and a Latin
+/// hypercube otherwise.
+pub struct StratifiedSampler {
+    samples_per_pixel: u32,
+    dimensions: usize,
+    seed: u32,
+    pixel_seed: u32,
+    rng: XorShiftRng,
+    samples_1d: Vec<Vec<Scalar>>,
+    samples_2d: Vec<Vec<(Scalar, Scalar)>>,
+    index: usize,
+    dim_1d: usize,
+    dim_2d: usize,
+}
+
+impl StratifiedSampler {
+    /// Create a sampler that stratifies the given number of
+    /// 1D and 2D dimensions, past those samples are random.
+    pub fn new(samples_per_pixel: u32, dimensions: usize, seed: u32) -> StratifiedSampler {
+        StratifiedSampler {
+            samples_per_pixel: samples_per_pixel,
+            dimensions: dimensions,
+            seed: seed,
+            pixel_seed: 0,
+            rng: seeded_rng(seed),
+            samples_1d: vec![Vec::new(); dimensions],
+            samples_2d: vec![Vec::new(); dimensions],
+            index: 0,
+            dim_1d: 0,
+            dim_2d: 0,
+        }
+    }
+}
+
+fn stratified_1d<R: Rng>(rng: &mut R, n: u32) -> Vec<Scalar> {
+    let inv_n = 1.0 / n as Scalar;
+    let mut samples: Vec<Scalar> = (0..n)
+        .map(|i| ((i as Scalar + rng.next_f64()) * inv_n).min(ONE_MINUS_EPSILON))
+        .collect();
+    rng.shuffle(&mut samples);
+    samples
+}
+
+fn stratified_2d<R: Rng>(rng: &mut R, n: u32) -> Vec<(Scalar, Scalar)> {
+    let side = (n as Scalar).sqrt() as u32;
+    let mut samples: Vec<(Scalar, Scalar)> = if side * side == n {
+        let inv_side = 1.0 / side as Scalar;
+        (0..n)
+            .map(|i| {
+                let x = ((i % side) as Scalar + rng.next_f64()) * inv_side;
+                let y = ((i / side) as Scalar + rng.next_f64()) * inv_side;
+                (x.min(ONE_MINUS_EPSILON), y.min(ONE_MINUS_EPSILON))
+            })
+            .collect()
+    } else {
+        // latin hypercube: stratify each axis on its own
+        // and pair them up at random
+        let xs = stratified_1d(rng, n);
+        let ys = stratified_1d(rng, n);
+        xs.into_iter().zip(ys.into_iter()).collect()
+    };
+    rng.shuffle(&mut samples);
+    samples
+}
+
+impl Sampler for StratifiedSampler {
+    fn start_pixel(&mut self, x: u32, y: u32) {
+        self.pixel_seed = pixel_seed(self.seed, x, y);
+        self.rng = seeded_rng(self.pixel_seed);
+        for dim in 0..self.dimensions {
+            self.samples_1d[dim] = stratified_1d(&mut self.rng, self.samples_per_pixel);
+            self.samples_2d[dim] = stratified_2d(&mut self.rng, self.samples_per_pixel);
+        }
+        self.start_sample(0);
+    }
+
+    #[inline]
+    fn start_sample(&mut self, index: u32) {
+        self.index = index as usize;
+        self.dim_1d = 0;
+        self.dim_2d = 0;
+        // dimensions past the stratified ones use
+        // a stream of random numbers for each sample
+        self.rng = seeded_rng(hash_combine(self.pixel_seed, index));
+    }
+
+    fn get_1d(&mut self) -> Scalar {
+        let dim = self.dim_1d;
+        self.dim_1d += 1;
+        if dim < self.dimensions && self.index < self.samples_1d[dim].len() {
+            self.samples_1d[dim][self.index]
+        } else {
+            self.rng.next_f64()
+        }
+    }
+
+    fn get_2d(&mut self) -> (Scalar, Scalar) {
+        let dim = self.dim_2d;
+        self.dim_2d += 1;
+        if dim < self.dimensions && self.index < self.samples_2d[dim].len() {
+            self.samples_2d[dim][self.index]
+        } else {
+            (self.rng.next_f64(), self.rng.next_f64())
+        }
+    }
+
+    #[inline]
+    fn samples_per_pixel(&self) -> u32 {
+        self.samples_per_pixel
+    }
+
+    fn clone_seeded(&self, samples_per_pixel: u32, seed: u32) -> Box<Sampler + Send> {
+        Box::new(StratifiedSampler::new(samples_per_pixel, self.dimensions, seed))
+    }
+}
+
+/// Uses the Halton sequence with a different prime base for
+/// each dimension. The sequence is decorrelated between
+/// pixels by a random toroidal shift (a Cranley-Patterson
+/// rotation) of every dimension.
+pub struct HaltonSampler {
+    samples_per_pixel: u32,
+    seed: u32,
+    pixel_seed: u32,
+    index: u32,
+    dim: u32,
+}
+
+impl HaltonSampler {
+    pub fn new(samples_per_pixel: u32, seed: u32) -> HaltonSampler {
+        HaltonSampler {
+            samples_per_pixel: samples_per_pixel,
+            seed: seed,
+            pixel_seed: 0,
+            index: 0,
+            dim: 0,
+        }
+    }
+}
+
+impl Sampler for HaltonSampler {
+    #[inline]
+    fn start_pixel(&mut self, x: u32, y: u32) {
+        self.pixel_seed = pixel_seed(self.seed, x, y);
+        self.start_sample(0);
+    }
+
+    #[inline]
+    fn start_sample(&mut self, index: u32) {
+        self.index = index;
+        self.dim = 0;
+    }
+
+    fn get_1d(&mut self) -> Scalar {
+        let dim = self.dim;
+        self.dim += 1;
+        let shift = to_unit(hash_combine(self.pixel_seed, dim));
+        match PRIMES.get(dim as usize) {
+            Some(&base) => {
+                let u = radical_inverse(base, self.index) + shift;
+                let u = if u >= 1.0 { u - 1.0 } else { u };
+                u.min(ONE_MINUS_EPSILON)
+            }
+            None => to_unit(hash_combine(hash_combine(self.pixel_seed, dim), self.index)),
+        }
+    }
+
+    #[inline]
+    fn get_2d(&mut self) -> (Scalar, Scalar) {
+        let u1 = self.get_1d();
+        let u2 = self.get_1d();
+        (u1, u2)
+    }
+
+    #[inline]
+    fn samples_per_pixel(&self) -> u32 {
+        self.samples_per_pixel
+    }
+
+    fn clone_seeded(&self, samples_per_pixel: u32, seed: u32) -> Box<Sampler + Send> {
+        Box::new(HaltonSampler::new(samples_per_pixel, seed))
+    }
+}
+
+/// Uses the first two dimensions of the Sobol sequence,
+/// which form a (0, 2)-sequence, for every 2D dimension
+/// with Owen scrambling. The order of the samples is
+/// shuffled differently for each dimension and pixel to
+/// decorrelate the dimensions from each other.
+///
+/// See "Practical Hash-based Owen Scrambling" (Burley 2020).
+pub struct SobolSampler {
+    samples_per_pixel: u32,
+    seed: u32,
+    pixel_seed: u32,
+    index: u32,
+    dim: u32,
+}
+
+impl SobolSampler {
+    pub fn new(samples_per_pixel: u32, seed: u32) -> SobolSampler {
+        SobolSampler {
+            samples_per_pixel: samples_per_pixel,
+            seed: seed,
+            pixel_seed: 0,
+            index: 0,
+            dim: 0,
+        }
+    }
+
+    /// The scrambled, shuffled index of the
+    /// current sample for the next dimension.
+    fn next_index(&mut self) -> (u32, u32) {
+        let seed = hash_combine(self.pixel_seed, self.dim);
+        self.dim += 1;
+        (nested_uniform_scramble(self.index, seed), seed)
+    }
+}
+
+impl Sampler for SobolSampler {
+    #[inline]
+    fn start_pixel(&mut self, x: u32, y: u32) {
+        self.pixel_seed = pixel_seed(self.seed, x, y);
+        self.start_sample(0);
+    }
+
+    #[inline]
+    fn start_sample(&mut self, index: u32) {
+        self.index = index;
+        self.dim = 0;
+    }
+
+    fn get_1d(&mut self) -> Scalar {
+        let (index, seed) = self.next_index();
+        to_unit(nested_uniform_scramble(sobol_0(index), hash_combine(seed, 0)))
+    }
+
+    fn get_2d(&mut self) -> (Scalar, Scalar) {
+        let (index, seed) = self.next_index();
+        (to_unit(nested_uniform_scramble(sobol_0(index), hash_combine(seed, 0))),
+         to_unit(nested_uniform_scramble(sobol_1(index), hash_combine(seed, 1))))
+    }
+
+    #[inline]
+    fn samples_per_pixel(&self) -> u32 {
+        self.samples_per_pixel
+    }
+
+    fn clone_seeded(&self, samples_per_pixel: u32, seed: u32) -> Box<Sampler + Send> {
+        Box::new(SobolSampler::new(samples_per_pixel, seed))
+    }
+}
+
+/// Reflect the digits of `index` written in the given
+/// base about the decimal point.
+pub fn radical_inverse(base: u32, mut index: u32) -> Scalar {
+    let inv_base = 1.0 / base as Scalar;
+    let mut f = inv_base;
+    let mut result = 0.0;
+    while index > 0 {
+        result = result + (index % base) as Scalar * f;
+        index = index / base;
+        f = f * inv_base;
+    }
+    result.min(ONE_MINUS_EPSILON)
+}
+
+#[inline]
+fn reverse_bits(mut x: u32) -> u32 {
+    x = (x << 16) | (x >> 16);
+    x = ((x & 0x00ff00ff) << 8) | ((x & 0xff00ff00) >> 8);
+    x = ((x & 0x0f0f0f0f) << 4) | ((x & 0xf0f0f0f0) >> 4);
+    x = ((x & 0x33333333) << 2) | ((x & 0xcccccccc) >> 2);
+    ((x & 0x55555555) << 1) | ((x & 0xaaaaaaaa) >> 1)
+}
+
+/// The first dimension of the Sobol sequence, which
+/// is the base 2 van der Corput sequence.
+#[inline]
+fn sobol_0(index: u32) -> u32 {
+    reverse_bits(index)
+}
+
+/// The second dimension of the Sobol sequence.
+#[inline]
+fn sobol_1(mut index: u32) -> u32 {
+    let mut v = 1 << 31;
+    let mut result = 0;
+    while index != 0 {
+        if index & 1 != 0 {
+            result = result ^ v;
+        }
+        index = index >> 1;
+        v = v ^ (v >> 1);
+    }
+    result
+}
+
+#[inline]
+fn laine_karras_permutation(mut x: u32, seed: u32) -> u32 {
+    x = x.wrapping_add(seed);
+    x = x ^ x.wrapping_mul(0x6c50b47c);
+    x = x ^ x.wrapping_mul(0xb82f1e52);
+    x = x ^ x.wrapping_mul(0xc7afe638);
+    x ^ x.wrapping_mul(0x8d22f6e6)
+}
+
+/// Owen scramble the bits of x, treated as a fixed point
+/// value in [0, 1), using the given seed.
+#[inline]
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    reverse_bits(laine_karras_permutation(reverse_bits(x), seed))
+}
+
+/// Mix the bits of a 32 bit value.
+#[inline]
+fn hash(mut x: u32) -> u32 {
+    x = x ^ (x >> 16);
+    x = x.wrapping_mul(0x7feb352d);
+    x = x ^ (x >> 15);
+    x = x.wrapping_mul(0x846ca68b);
+    x ^ (x >> 16)
+}
+
+#[inline]
+fn hash_combine(seed: u32, v: u32) -> u32 {
+    hash(seed ^ v.wrapping_add(0x9e3779b9).wrapping_add(seed << 6).wrapping_add(seed >> 2))
+}
+
+/// The seed for all of the samples in the pixel (x, y).
+#[inline]
+fn pixel_seed(seed: u32, x: u32, y: u32) -> u32 {
+    hash_combine(hash_combine(hash(seed), x), y)
+}
+
+/// Create a random number generator whose
+/// state is derived from the given seed.
+fn seeded_rng(seed: u32) -> XorShiftRng {
+    let mut state = [hash_combine(seed, 0),
+                     hash_combine(seed, 1),
+                     hash_combine(seed, 2),
+                     hash_combine(seed, 3)];
+    // the generator can't be seeded with all zeroes
+    if state.iter().all(|&s| s == 0) {
+        state[0] = 1;
+    }
+    XorShiftRng::from_seed(state)
+}
+
+/// Map a 32 bit value to a Scalar in [0, 1).
+#[inline]
+fn to_unit(x: u32) -> Scalar {
+    (x as Scalar / 4294967296.0).min(ONE_MINUS_EPSILON)
+}
+
+#[test]
+fn test_radical_inverse() {
+    assert_approx_eq!(radical_inverse(2, 1), 0.5);
+    assert_approx_eq!(radical_inverse(2, 3), 0.75);
+    assert_approx_eq!(radical_inverse(3, 1), 1.0 / 3.0);
+    assert_approx_eq!(radical_inverse(3, 4), 4.0 / 9.0);
+}
+
+#[test]
+fn test_samples_depend_on_pixel_and_index() {
+    let mut a = IndependentSampler::new(4, 7);
+    let mut b = IndependentSampler::new(4, 7);
+    // visit the pixels in a different order with each sampler
+    a.start_pixel(1, 2);
+    a.start_sample(3);
+    let expected = (a.get_1d(), a.get_2d());
+    b.start_pixel(5, 5);
+    b.start_sample(3);
+    let _ = b.get_1d();
+    b.start_pixel(1, 2);
+    b.start_sample(3);
+    assert_eq!((b.get_1d(), b.get_2d()), expected);
+}
+
+#[test]
+fn test_sobol_02_sequence() {
+    // every elementary interval of area 1/4 should contain
+    // exactly one of the first four points
+    let mut cells = [[0; 4]; 3];
+    for i in 0..4 {
+        let x = to_unit(sobol_0(i));
+        let y = to_unit(sobol_1(i));
+        cells[0][(x * 4.0) as usize] += 1;
+        cells[1][(y * 4.0) as usize] += 1;
+        cells[2][(x * 2.0) as usize * 2 + (y * 2.0) as usize] += 1;
+    }
+    for cell in cells.iter() {
+        assert_eq!(cell, &[1, 1, 1, 1]);
+    }
+}
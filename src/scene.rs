@@ -4,156 +4,202 @@ use std::sync::Arc;
 use uuid::Uuid;
 
 use na;
-use na::{Isometry3, Point2, Point3};
 use ncollide::bounding_volume::AABB3;
-use ncollide::partitioning::BVT;
-use ncollide::query::{RayCast, RayInterferencesCollector};
 
+use bvh::BVH;
 use bxdf::BSDF;
-use light::Light;
+use light::{AreaLight, Light, ShapeEmitter};
 use material::Material;
 use math::{Normal, Point, Scalar, Vector};
 use ray::Ray;
+use spectrum::Spectrum;
+use surface::{Surface, SurfaceGeometry};
+use transform::AnimatedTransform;
+
+/// Geometry that may be shared between many nodes
+/// in the scene, each placing it with its own
+/// transform and shading it with its own material.
+#[derive(Clone)]
+pub struct Geometry {
+    pub shape: Arc<Surface + Sync + Send>,
+    pub emitter: Arc<ShapeEmitter + Sync + Send>,
+    /// Bounds of the shape in its own space.
+    pub aabb: AABB3<Scalar>,
+}
+
+impl Geometry {
+    pub fn new(shape: Arc<Surface + Sync + Send>,
+               emitter: Arc<ShapeEmitter + Sync + Send>,
+               aabb: AABB3<Scalar>)
+               -> Geometry {
+        Geometry {
+            shape: shape,
+            emitter: emitter,
+            aabb: aabb,
+        }
+    }
+}
 
 /// Structure representing an object in the
 /// Scene that can be shaded.
 pub struct SceneNode {
     pub uuid: Uuid,
-    pub transform: Isometry3<Scalar>,
+    pub transform: AnimatedTransform,
     pub material: Arc<Material + Sync + Send>,
-    pub geom: Box<RayCast<Point, Isometry3<Scalar>> + Sync + Send>,
+    /// The shape of the node in its own space, rays
+    /// are moved into that space to be intersected.
+    pub geom: Arc<Surface + Sync + Send>,
+    /// Bounds of the node over its whole motion.
     pub aabb: AABB3<Scalar>,
+    /// The area light emitting from this node's
+    /// surface, if it is an emitter.
+    pub light: Option<Arc<AreaLight + Sync + Send>>,
 }
 
 /// Structure storing information about an
 /// object that was intersected in the Scene.
 pub struct Intersection {
     pub point: Point,
+    /// The true normal of the surface.
     pub normal: Normal,
+    /// The normal the surface is shaded with, after
+    /// any normal or bump map of its material.
+    pub shading_normal: Normal,
+    /// The partial derivatives of the position on the
+    /// surface with respect to the texture coordinates.
+    pub dpdu: Vector,
+    pub dpdv: Vector,
     pub bsdf: BSDF,
+    /// Radiance emitted from the surface back
+    /// along the intersecting ray (Le).
+    pub le: Spectrum,
+    /// The area light of the surface, if it is an emitter.
+    pub light: Option<Arc<AreaLight + Sync + Send>>,
+    /// The time of the ray that found the intersection.
+    pub time: Scalar,
 }
 
 impl Intersection {
-    pub fn new(p: Point, n: Normal, bsdf: BSDF) -> Intersection {
+    pub fn new(geometry: &SurfaceGeometry,
+               bsdf: BSDF,
+               le: Spectrum,
+               light: Option<Arc<AreaLight + Sync + Send>>,
+               time: Scalar)
+               -> Intersection {
         Intersection {
-            point: p,
-            normal: n,
+            point: geometry.point,
+            normal: geometry.normal,
+            shading_normal: geometry.shading_normal,
+            dpdu: geometry.dpdu,
+            dpdv: geometry.dpdv,
             bsdf: bsdf,
+            le: le,
+            light: light,
+            time: time,
         }
     }
 }
 
 impl SceneNode {
-    pub fn new(transform: Isometry3<Scalar>,
+    /// Create a node placing the geometry in
+    /// the scene, which is shared not copied.
+    pub fn new(transform: AnimatedTransform,
                material: Arc<Material + Sync + Send>,
-               geom: Box<RayCast<Point, Isometry3<Scalar>> + Sync + Send>,
-               aabb: AABB3<Scalar>)
+               geometry: &Geometry,
+               light: Option<Arc<AreaLight + Sync + Send>>)
                -> SceneNode {
+        let aabb = transform.motion_aabb(|t| t.transform_aabb(&geometry.aabb));
         SceneNode {
             uuid: Uuid::new_v4(),
             transform: transform,
             material: material,
             aabb: aabb,
-            geom: geom,
+            geom: geometry.shape.clone(),
+            light: light,
         }
     }
 }
 
+/// The objects and lights in a scene.
+///
+/// Nodes are found with a two level acceleration structure, the
+/// bounding volume hierarchy over the nodes in world space finds the
+/// nodes a ray may hit and the ray is then moved into each node's
+/// own space where meshes have their own hierarchy over their triangles,
+/// which is shared by every node that instances the mesh.
 pub struct Scene {
-    pub lights: Vec<Box<Light + Sync + Send>>,
-    world: BVT<Arc<SceneNode>, AABB3<Scalar>>,
-}
-
-/// Get the nearest node and surface info at the intersection
-/// point intersected by the given ray.
-fn get_nearest<'a>(ray: &Ray,
-                   nodes: &'a [Arc<SceneNode>])
-                   -> Option<(&'a SceneNode, f64, Vector, Option<Point2<f64>>)> {
-    let mut nearest_node: Option<&SceneNode> = None;
-    let mut nearest_toi = std::f64::MAX;
-    let mut nearest_normal = na::zero();
-    let mut nearest_uvs = None;
-    for node in nodes {
-        if let Some(isect) = node.geom
-            .toi_and_normal_and_uv_with_ray(&node.transform, &ray.ray, false) {
-            // check toi is greater than zero to rule out intersection
-            // with the node whose surface we're casting a ray from
-            // Note: this is not 100% reliable I don't think
-            // this in tandem with code in renderer for casting reflection
-            // and transmission rays slightly off the point on the surface
-            // they came from should hopefully prevent artifacts
-            if isect.toi > 0.0 && isect.toi < nearest_toi {
-                nearest_node = Some(node);
-                nearest_toi = isect.toi;
-                nearest_normal = isect.normal;
-                nearest_uvs = isect.uvs;
-            }
-        }
-    }
-    if nearest_node.is_some() {
-        Some((nearest_node.unwrap(), nearest_toi, nearest_normal, nearest_uvs))
-    } else {
-        None
-    }
+    pub lights: Vec<Arc<Light + Sync + Send>>,
+    world: BVH<Arc<SceneNode>>,
 }
 
 impl Scene {
     pub fn new(nodes: Vec<Arc<SceneNode>>) -> Scene {
-        let leaves = nodes.iter().map(|n| (n.clone(), n.aabb.clone())).collect();
+        let items = nodes.into_iter()
+            .map(|n| {
+                let aabb = n.aabb.clone();
+                (n, aabb)
+            })
+            .collect();
         Scene {
             lights: Vec::new(),
-            world: BVT::new_balanced(leaves),
+            world: BVH::new(items),
         }
     }
 
     #[inline]
-    pub fn add_light(&mut self, light: Box<Light + Sync + Send>) {
+    pub fn add_light(&mut self, light: Arc<Light + Sync + Send>) {
         self.lights.push(light);
     }
 
-    pub fn intersects(&self, ray: &Ray) -> bool {
-        let mut intersections = Vec::new();
-        {
-            // we define a scope here so that visitor (which takes a mutable
-            // borrow of the intersections vector) is dropped before we need
-            // to take another borrow of the intersections vector later on
-            let mut visitor = RayInterferencesCollector::new(&ray.ray, &mut intersections);
-            self.world.visit(&mut visitor);
-        }
-        intersections.iter().any(|n| n.geom.intersects_ray(&n.transform, &ray.ray))
-    }
-
-    pub fn intersections(&self, ray: &Ray) -> Vec<f64> {
-        let mut intersections = Vec::new();
-        {
-            // we define a scope here so that visitor (which takes a mutable
-            // borrow of the intersections vector) is dropped before we need
-            // to take another borrow of the intersections vector later on
-            let mut visitor = RayInterferencesCollector::new(&ray.ray, &mut intersections);
-            self.world.visit(&mut visitor);
-        }
-        intersections.iter()
-            .map(|n| n.geom.toi_with_ray(&n.transform, &ray.ray, true))
-            .filter(|x| x.is_some())
-            .map(|x| x.unwrap())
-            .filter(|&x| x > 0.0)
-            .collect()
+    /// Does the ray hit anything in the scene closer than t_max?
+    /// This stops at the first hit found rather than the nearest
+    /// so is cheaper than `trace` for shadow rays.
+    pub fn occluded(&self, ray: &Ray, t_max: Scalar) -> bool {
+        self.world.any_hit(&ray.ray, t_max, |node, t_max| {
+            // the local ray's direction isn't normalized
+            // so the time of impact is the same in both spaces
+            let local = node.transform.at(ray.time).inverse_transform_ray(ray);
+            node.geom.occludes(&local.ray, t_max)
+        })
     }
 
     pub fn trace(&self, ray: &Ray) -> Option<Intersection> {
-        let mut intersections = Vec::new();
-        {
-            // we define a scope here so that visitor (which takes a mutable
-            // borrow of the intersections vector) is dropped before we need
-            // to take another borrow of the intersections vector later on
-            let mut visitor = RayInterferencesCollector::new(&ray.ray, &mut intersections);
-            self.world.visit(&mut visitor);
-        }
+        let nearest = self.world.closest_hit(&ray.ray, std::f64::INFINITY, |node, t_max| {
+            let transform = node.transform.at(ray.time);
+            let local = transform.inverse_transform_ray(ray);
+            // the surface only reports hits with toi greater than zero to
+            // rule out intersection with the node whose surface we're
+            // casting a ray from
+            // Note: this is not 100% reliable I don't think
+            // this in tandem with code in renderer for casting reflection
+            // and transmission rays slightly off the point on the surface
+            // they came from should hopefully prevent artifacts
+            node.geom
+                .intersect(&local.ray, t_max)
+                .map(|(toi, geometry)| (toi, (node, geometry.transform(&transform))))
+        });
 
-        match get_nearest(ray, &intersections) {
-            Some((node, toi, normal, uvs)) => {
-                let p = *ray.orig() + *ray.dir() * toi;
-                Some(Intersection::new(p, normal, node.material.get_bsdf(&normal, &uvs)))
+        match nearest {
+            Some((node, geometry)) => {
+                let le = match node.light {
+                    Some(ref light) => {
+                        light.radiance(&geometry.point, &geometry.normal, &-(*ray.dir()))
+                    }
+                    None => na::zero(),
+                };
+                let geometry = node.material.shading_geometry(geometry);
+                let mut bsdf = node.material.get_bsdf(&geometry);
+                // a ray reaching the surface from inside has crossed
+                // the node's volume, which may have absorbed light
+                if na::dot(ray.dir(), &geometry.normal) > 0.0 {
+                    let distance = na::norm(&(geometry.point - *ray.orig()));
+                    bsdf.scale(&node.material.transmittance(distance));
+                }
+                Some(Intersection::new(&geometry,
+                                       bsdf,
+                                       le,
+                                       node.light.clone(),
+                                       ray.time))
             }
             None => None,
         }
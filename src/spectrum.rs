@@ -4,6 +4,12 @@ use na::Vector3;
 // TODO: make this a newtype.
 pub type Spectrum = Vector3<f64>;
 
+/// The luminance of a linear Rec. 709 colour.
+#[inline]
+pub fn luminance(c: &Spectrum) -> f64 {
+    c.x * 0.2126 + c.y * 0.7152 + c.z * 0.0722
+}
+
 // pub struct Spectrum(Vector3<f64>);
 
 // impl Spectrum {
@@ -0,0 +1,146 @@
+use std::f64::consts;
+
+use na;
+use na::{Isometry3, Point2};
+use ncollide::query::{Ray3, RayCast};
+use ncollide::shape::{Ball3, Cuboid3};
+
+use math;
+use math::{Normal, Point, Scalar, Vector};
+use transform::Transform;
+
+/// The local geometry of a surface around a point on it.
+#[derive(Clone, Debug)]
+pub struct SurfaceGeometry {
+    pub point: Point,
+    /// The true normal of the surface.
+    pub normal: Normal,
+    /// The normal used for shading, which differs from the
+    /// true normal where vertex normals are interpolated or
+    /// a normal or bump map perturbs it.
+    pub shading_normal: Normal,
+    /// The partial derivatives of the position on the
+    /// surface with respect to the texture coordinates.
+    pub dpdu: Vector,
+    pub dpdv: Vector,
+    pub uv: Option<Point2<Scalar>>,
+}
+
+impl SurfaceGeometry {
+    /// Create the geometry at a point where the shading normal
+    /// is the true normal. The derivatives are replaced with an
+    /// arbitrary frame around the normal if they are degenerate.
+    pub fn new(point: Point,
+               normal: Normal,
+               dpdu: Vector,
+               dpdv: Vector,
+               uv: Option<Point2<Scalar>>)
+               -> SurfaceGeometry {
+        let (dpdu, dpdv) = if na::norm_squared(&dpdu.cross(&dpdv)) == 0.0 {
+            math::coordinate_system(&normal)
+        } else {
+            (dpdu, dpdv)
+        };
+        SurfaceGeometry {
+            point: point,
+            normal: normal,
+            shading_normal: normal,
+            dpdu: dpdu,
+            dpdv: dpdv,
+            uv: uv,
+        }
+    }
+
+    /// Move the geometry out of a shape's own space.
+    pub fn transform(&self, transform: &Transform) -> SurfaceGeometry {
+        SurfaceGeometry {
+            point: transform.transform_point(&self.point),
+            normal: transform.transform_normal(&self.normal),
+            shading_normal: transform.transform_normal(&self.shading_normal),
+            dpdu: transform.transform_vector(&self.dpdu),
+            dpdv: transform.transform_vector(&self.dpdv),
+            uv: self.uv,
+        }
+    }
+}
+
+/// A shape that rays can be intersected with
+/// to find the geometry of its surface.
+pub trait Surface {
+    /// Find the closest point in front of the ray's origin
+    /// and closer than t_max where it hits the surface,
+    /// returning the distance along the ray and the geometry
+    /// there. The ray and the geometry are in the shape's
+    /// own space.
+    fn intersect(&self, ray: &Ray3<Scalar>, t_max: Scalar) -> Option<(Scalar, SurfaceGeometry)>;
+
+    /// Does the ray hit the surface in front of its
+    /// origin and closer than t_max?
+    fn occludes(&self, ray: &Ray3<Scalar>, t_max: Scalar) -> bool;
+}
+
+/// Balls are parameterised by longitude around the
+/// Y axis for u and latitude from the top for v.
+impl Surface for Ball3<Scalar> {
+    fn intersect(&self, ray: &Ray3<Scalar>, t_max: Scalar) -> Option<(Scalar, SurfaceGeometry)> {
+        let isect = match self.toi_and_normal_and_uv_with_ray(&Isometry3::identity(), ray, false) {
+            Some(isect) if isect.toi > 0.0 && isect.toi < t_max => isect,
+            _ => return None,
+        };
+        let p = ray.origin + ray.dir * isect.toi;
+        let rho = (p.x * p.x + p.z * p.z).sqrt();
+        let dpdu = Vector::new(-p.z, 0.0, p.x) * (2.0 * consts::PI);
+        let dpdv = if rho == 0.0 {
+            na::zero()
+        } else {
+            Vector::new(p.y * p.x / rho, -rho, p.y * p.z / rho) * consts::PI
+        };
+        Some((isect.toi, SurfaceGeometry::new(p, isect.normal, dpdu, dpdv, isect.uvs)))
+    }
+
+    fn occludes(&self, ray: &Ray3<Scalar>, t_max: Scalar) -> bool {
+        occludes(self, ray, t_max)
+    }
+}
+
+/// Each face of a cuboid is parameterised along the
+/// two axes following its normal's axis in turn.
+impl Surface for Cuboid3<Scalar> {
+    fn intersect(&self, ray: &Ray3<Scalar>, t_max: Scalar) -> Option<(Scalar, SurfaceGeometry)> {
+        let isect = match self.toi_and_normal_and_uv_with_ray(&Isometry3::identity(), ray, false) {
+            Some(isect) if isect.toi > 0.0 && isect.toi < t_max => isect,
+            _ => return None,
+        };
+        let p = ray.origin + ray.dir * isect.toi;
+        let n = isect.normal;
+        let axis = if n.x.abs() > n.y.abs() && n.x.abs() > n.z.abs() {
+            0
+        } else if n.y.abs() > n.z.abs() {
+            1
+        } else {
+            2
+        };
+        let extents = *self.half_extents() * 2.0;
+        let mut dpdu = na::zero::<Vector>();
+        let mut dpdv = na::zero::<Vector>();
+        dpdu[(axis + 1) % 3] = extents[(axis + 1) % 3];
+        dpdv[(axis + 2) % 3] = extents[(axis + 2) % 3];
+        Some((isect.toi, SurfaceGeometry::new(p, n, dpdu, dpdv, isect.uvs)))
+    }
+
+    fn occludes(&self, ray: &Ray3<Scalar>, t_max: Scalar) -> bool {
+        occludes(self, ray, t_max)
+    }
+}
+
+/// Shadow rays treat shapes as solid so that a ray
+/// starting inside one is blocked by it.
+fn occludes<S: RayCast<Point, Isometry3<Scalar>>>(shape: &S,
+                                                  ray: &Ray3<Scalar>,
+                                                  t_max: Scalar)
+                                                  -> bool {
+    match shape.toi_with_ray(&Isometry3::identity(), ray, true) {
+        Some(toi) => toi > 0.0 && toi < t_max,
+        None => false,
+    }
+}
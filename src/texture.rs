@@ -10,6 +10,13 @@ use spectrum::Spectrum;
 
 pub trait Texture {
     fn sample(&self, uv: &Option<Point2<f64>>) -> Spectrum;
+
+    /// The step in texture coordinates between neighbouring
+    /// values of the texture, used to take differences of
+    /// it such as the slope of a bump map.
+    fn texel_size(&self) -> (Scalar, Scalar) {
+        (0.0005, 0.0005)
+    }
 }
 
 /// A Texture that just has a single
@@ -58,4 +65,9 @@ impl Texture for ImageTexture {
             None => na::zero(),
         }
     }
+
+    fn texel_size(&self) -> (Scalar, Scalar) {
+        let (width, height) = self.data.dimensions();
+        (1.0 / width as Scalar, 1.0 / height as Scalar)
+    }
 }
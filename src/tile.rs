@@ -0,0 +1,169 @@
+use std::cmp::Ordering;
+use std::sync::atomic::{self, AtomicUsize};
+
+/// A rectangular region of the image covering the pixels
+/// from (x0, y0) up to but not including (x1, y1).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Tile {
+    pub x0: u32,
+    pub y0: u32,
+    pub x1: u32,
+    pub y1: u32,
+}
+
+impl Tile {
+    #[inline]
+    pub fn width(&self) -> u32 {
+        self.x1 - self.x0
+    }
+
+    #[inline]
+    pub fn height(&self) -> u32 {
+        self.y1 - self.y0
+    }
+}
+
+/// The order that tiles are handed out to render threads.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TileOrder {
+    /// Spiral outwards from the centre of the image.
+    Spiral,
+    /// Follow a Hilbert curve over the image so that tiles
+    /// rendered one after the other are close together.
+    Hilbert,
+}
+
+impl TileOrder {
+    pub fn from_name(name: &str) -> Option<TileOrder> {
+        match name {
+            "spiral" => Some(TileOrder::Spiral),
+            "hilbert" => Some(TileOrder::Hilbert),
+            _ => None,
+        }
+    }
+}
+
+/// Split an image into square tiles of the given size in
+/// the given order. Tiles along the right and bottom edges
+/// are cut short if the image size isn't a multiple of the
+/// tile size.
+pub fn generate_tiles(width: u32, height: u32, tile_size: u32, order: TileOrder) -> Vec<Tile> {
+    assert!(tile_size > 0);
+    let ntiles_x = (width + tile_size - 1) / tile_size;
+    let ntiles_y = (height + tile_size - 1) / tile_size;
+
+    let mut coords = Vec::with_capacity((ntiles_x * ntiles_y) as usize);
+    for ty in 0..ntiles_y {
+        for tx in 0..ntiles_x {
+            coords.push((tx, ty));
+        }
+    }
+
+    match order {
+        TileOrder::Spiral => {
+            let cx = (ntiles_x as i64 - 1) / 2;
+            let cy = (ntiles_y as i64 - 1) / 2;
+            // order tiles by the square ring around the centre
+            // tile they lie on and then by angle within the ring
+            let key = |&(tx, ty): &(u32, u32)| {
+                let dx = tx as i64 - cx;
+                let dy = ty as i64 - cy;
+                (i64::max(dx.abs(), dy.abs()), (dy as f64).atan2(dx as f64))
+            };
+            coords.sort_by(|a, b| {
+                let (ring_a, angle_a) = key(a);
+                let (ring_b, angle_b) = key(b);
+                ring_a.cmp(&ring_b)
+                    .then(angle_a.partial_cmp(&angle_b).unwrap_or(Ordering::Equal))
+            });
+        }
+        TileOrder::Hilbert => {
+            let n = u32::max(ntiles_x, ntiles_y).next_power_of_two();
+            coords.sort_by_key(|&(tx, ty)| hilbert_index(n, tx, ty));
+        }
+    }
+
+    coords.into_iter()
+        .map(|(tx, ty)| {
+            Tile {
+                x0: tx * tile_size,
+                y0: ty * tile_size,
+                x1: u32::min(width, (tx + 1) * tile_size),
+                y1: u32::min(height, (ty + 1) * tile_size),
+            }
+        })
+        .collect()
+}
+
+/// The distance along a Hilbert curve filling an
+/// n by n grid (n a power of two) of the cell (x, y).
+fn hilbert_index(n: u32, mut x: u32, mut y: u32) -> u32 {
+    let mut d = 0;
+    let mut s = n / 2;
+    while s > 0 {
+        let rx = if x & s > 0 { 1 } else { 0 };
+        let ry = if y & s > 0 { 1 } else { 0 };
+        d += s * s * ((3 * rx) ^ ry);
+        // rotate the quadrant so the curve
+        // inside it has the right orientation
+        if ry == 0 {
+            if rx == 1 {
+                x = n - 1 - x;
+                y = n - 1 - y;
+            }
+            ::std::mem::swap(&mut x, &mut y);
+        }
+        s /= 2;
+    }
+    d
+}
+
+/// A queue of tiles shared between render threads,
+/// each thread takes the next tile when it has
+/// finished the last so none are left idle.
+pub struct TileQueue {
+    tiles: Vec<Tile>,
+    next: AtomicUsize,
+}
+
+impl TileQueue {
+    pub fn new(tiles: Vec<Tile>) -> TileQueue {
+        TileQueue {
+            tiles: tiles,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Take the next tile to render along with its
+    /// position in the queue, if there are any left.
+    pub fn next(&self) -> Option<(usize, Tile)> {
+        let index = self.next.fetch_add(1, atomic::Ordering::Relaxed);
+        self.tiles.get(index).map(|&tile| (index, tile))
+    }
+}
+
+#[test]
+fn test_tiles_cover_image() {
+    for &order in &[TileOrder::Spiral, TileOrder::Hilbert] {
+        let tiles = generate_tiles(37, 21, 8, order);
+        assert_eq!(tiles.len(), 5 * 3);
+        let mut covered = vec![0; 37 * 21];
+        for tile in &tiles {
+            for y in tile.y0..tile.y1 {
+                for x in tile.x0..tile.x1 {
+                    covered[(y * 37 + x) as usize] += 1;
+                }
+            }
+        }
+        assert!(covered.iter().all(|&c| c == 1));
+    }
+}
+
+#[test]
+fn test_hilbert_index() {
+    // the curve over a 2x2 grid
+    assert_eq!(hilbert_index(2, 0, 0), 0);
+    assert_eq!(hilbert_index(2, 0, 1), 1);
+    assert_eq!(hilbert_index(2, 1, 1), 2);
+    assert_eq!(hilbert_index(2, 1, 0), 3);
+}
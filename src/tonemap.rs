@@ -0,0 +1,112 @@
+use math::Scalar;
+use spectrum::{Spectrum, luminance};
+
+/// The curve used to compress radiance into the
+/// range [0, 1] that can be displayed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ToneMapOperator {
+    /// Clip anything brighter than 1.
+    Clamp,
+    /// Reinhard's operator applied to luminance, radiance
+    /// at the white point (which may be infinite) maps to 1.
+    Reinhard(Scalar),
+    /// John Hable's filmic curve from Uncharted 2.
+    Filmic,
+    /// Krzysztof Narkowicz's fit of the ACES reference
+    /// rendering and output device transforms.
+    Aces,
+}
+
+impl ToneMapOperator {
+    pub fn from_name(name: &str) -> Option<ToneMapOperator> {
+        match name {
+            "clamp" => Some(ToneMapOperator::Clamp),
+            "reinhard" => Some(ToneMapOperator::Reinhard(::std::f64::INFINITY)),
+            "filmic" => Some(ToneMapOperator::Filmic),
+            "aces" => Some(ToneMapOperator::Aces),
+            _ => None,
+        }
+    }
+}
+
+/// Transforms linear radiance from the film
+/// to values ready to be stored in 8 bits.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ToneMap {
+    /// Exposure adjustment in stops.
+    pub exposure: Scalar,
+    pub operator: ToneMapOperator,
+    /// Whether to encode the result with the sRGB transfer function.
+    pub srgb: bool,
+}
+
+impl ToneMap {
+    pub fn new(exposure: Scalar, operator: ToneMapOperator, srgb: bool) -> ToneMap {
+        ToneMap {
+            exposure: exposure,
+            operator: operator,
+            srgb: srgb,
+        }
+    }
+
+    /// Map linear radiance to display values in [0, 1].
+    pub fn apply(&self, c: &Spectrum) -> Spectrum {
+        let c = *c * self.exposure.exp2();
+        let c = match self.operator {
+            ToneMapOperator::Clamp => c,
+            ToneMapOperator::Reinhard(white) => {
+                let l = luminance(&c);
+                if l <= 0.0 {
+                    c
+                } else {
+                    let mapped = l * (1.0 + l / (white * white)) / (1.0 + l);
+                    c * (mapped / l)
+                }
+            }
+            ToneMapOperator::Filmic => {
+                // the curve is applied with an exposure bias
+                // and scaled so that the white point maps to 1
+                let white_scale = 1.0 / hable(11.2);
+                c.map(|x| hable(2.0 * x) * white_scale)
+            }
+            ToneMapOperator::Aces => c.map(aces),
+        };
+        let c = c.map(|x| x.max(0.0).min(1.0));
+        if self.srgb { c.map(srgb_oetf) } else { c }
+    }
+}
+
+impl Default for ToneMap {
+    fn default() -> ToneMap {
+        ToneMap::new(0.0, ToneMapOperator::Clamp, true)
+    }
+}
+
+fn hable(x: Scalar) -> Scalar {
+    let (a, b, c, d, e, f) = (0.15, 0.50, 0.10, 0.20, 0.02, 0.30);
+    ((x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f)) - e / f
+}
+
+fn aces(x: Scalar) -> Scalar {
+    let (a, b, c, d, e) = (2.51, 0.03, 2.43, 0.59, 0.14);
+    (x * (a * x + b)) / (x * (c * x + d) + e)
+}
+
+/// The sRGB opto-electronic transfer function
+/// taking a linear value in [0, 1] to the
+/// non-linear value to be stored.
+pub fn srgb_oetf(x: Scalar) -> Scalar {
+    if x <= 0.0031308 {
+        12.92 * x
+    } else {
+        1.055 * x.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+#[test]
+fn test_srgb_oetf() {
+    assert_approx_eq!(srgb_oetf(0.0), 0.0);
+    assert_approx_eq!(srgb_oetf(1.0), 1.0);
+    // mid grey is stored at about half intensity
+    assert!((srgb_oetf(0.214) - 0.5).abs() < 1e-3);
+}
@@ -0,0 +1,471 @@
+use std::cmp::Ordering;
+
+use na;
+use na::{Isometry3, Matrix3, Matrix4, Quaternion, Rotation3, Translation3, UnitQuaternion};
+use ncollide::bounding_volume;
+use ncollide::bounding_volume::{AABB3, BoundingVolume};
+
+use math::{Normal, Point, Scalar, Vector};
+use ray::Ray;
+
+/// The number of transforms checked between
+/// each pair of keyframes when bounding an
+/// object over its whole motion.
+const MOTION_BOUND_STEPS: usize = 32;
+
+/// How far a stretch may be from the identity, by the
+/// Frobenius norm of the difference, for the transform
+/// to be taken as rigid.
+const RIGID_TOLERANCE: Scalar = 1e-6;
+
+/// An affine transform that places an object in the scene.
+///
+/// It is kept as a stretch (scale and shear) applied in the
+/// object's own space followed by a rotation and translation,
+/// so that transforms can be interpolated between keyframes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Transform {
+    pub isometry: Isometry3<Scalar>,
+    stretch: Matrix3<Scalar>,
+    inverse_stretch: Matrix3<Scalar>,
+}
+
+impl Transform {
+    /// Create a transform that stretches by the given matrix
+    /// before applying the isometry, returning None if the
+    /// stretch can't be inverted.
+    ///
+    /// A stretch within rounding error of the identity, such
+    /// as that left by decomposing a rotation matrix, is
+    /// replaced by the identity so the transform is rigid.
+    pub fn new(isometry: Isometry3<Scalar>, stretch: Matrix3<Scalar>) -> Option<Transform> {
+        if is_identity(&stretch) {
+            return Some(Self::from_isometry(isometry));
+        }
+        stretch.try_inverse().map(|inverse_stretch| {
+            Transform {
+                isometry: isometry,
+                stretch: stretch,
+                inverse_stretch: inverse_stretch,
+            }
+        })
+    }
+
+    /// A rigid transform.
+    pub fn from_isometry(isometry: Isometry3<Scalar>) -> Transform {
+        Transform {
+            isometry: isometry,
+            stretch: Matrix3::identity(),
+            inverse_stretch: Matrix3::identity(),
+        }
+    }
+
+    /// Create a transform from a 4x4 affine matrix, returning
+    /// None if the matrix isn't affine or can't be inverted.
+    pub fn from_matrix(m: &Matrix4<Scalar>) -> Option<Transform> {
+        if m[(3, 0)] != 0.0 || m[(3, 1)] != 0.0 || m[(3, 2)] != 0.0 || m[(3, 3)] != 1.0 {
+            return None;
+        }
+        let linear = Matrix3::new(m[(0, 0)], m[(0, 1)], m[(0, 2)],
+                                  m[(1, 0)], m[(1, 1)], m[(1, 2)],
+                                  m[(2, 0)], m[(2, 1)], m[(2, 2)]);
+        let translation = Vector::new(m[(0, 3)], m[(1, 3)], m[(2, 3)]);
+        Self::from_linear(translation, &linear)
+    }
+
+    /// Create a transform that applies the linear map
+    /// and then translates, returning None if the
+    /// linear map can't be inverted.
+    pub fn from_linear(translation: Vector, linear: &Matrix3<Scalar>) -> Option<Transform> {
+        polar_decompose(linear).and_then(|(rotation, stretch)| {
+            let isometry = Isometry3::from_parts(Translation3::from_vector(translation),
+                                                 rotation);
+            Self::new(isometry, stretch)
+        })
+    }
+
+    /// Does the transform keep lengths and angles the same?
+    pub fn is_rigid(&self) -> bool {
+        is_identity(&self.stretch)
+    }
+
+    /// Does the transform turn the object into its mirror image?
+    pub fn is_mirrored(&self) -> bool {
+        self.stretch.determinant() < 0.0
+    }
+
+    #[inline]
+    pub fn translation(&self) -> Vector {
+        self.isometry.translation.vector
+    }
+
+    #[inline]
+    pub fn transform_point(&self, p: &Point) -> Point {
+        self.isometry * Point::from_coordinates(self.stretch * p.coords)
+    }
+
+    /// Transform a vector, which will not keep its length
+    /// if the transform isn't rigid.
+    #[inline]
+    pub fn transform_vector(&self, v: &Vector) -> Vector {
+        self.isometry * (self.stretch * *v)
+    }
+
+    /// Transform a normal by the inverse transpose so that
+    /// it stays perpendicular to the surface, the result
+    /// is normalized.
+    #[inline]
+    pub fn transform_normal(&self, n: &Normal) -> Normal {
+        na::normalize(&(self.isometry * (self.inverse_stretch.transpose() * *n)))
+    }
+
+    #[inline]
+    pub fn inverse_transform_point(&self, p: &Point) -> Point {
+        let p = self.isometry.inverse() * *p;
+        Point::from_coordinates(self.inverse_stretch * p.coords)
+    }
+
+    #[inline]
+    pub fn inverse_transform_vector(&self, v: &Vector) -> Vector {
+        self.inverse_stretch * (self.isometry.inverse() * *v)
+    }
+
+    /// Take a normal in world space back to the
+    /// object's space, the result is normalized.
+    #[inline]
+    pub fn inverse_transform_normal(&self, n: &Normal) -> Normal {
+        na::normalize(&(self.stretch.transpose() * (self.isometry.inverse() * *n)))
+    }
+
+    /// Transform a ray, keeping its depth and time. The
+    /// direction isn't normalized so that distances along
+    /// the transformed ray are the same as along the ray.
+    pub fn transform_ray(&self, ray: &Ray) -> Ray {
+        Ray::new_with_time(self.transform_point(ray.orig()),
+                           self.transform_vector(ray.dir()),
+                           ray.depth,
+                           ray.time)
+    }
+
+    /// Take a ray in world space to the object's space, as
+    /// with `transform_ray` the direction isn't normalized.
+    pub fn inverse_transform_ray(&self, ray: &Ray) -> Ray {
+        Ray::new_with_time(self.inverse_transform_point(ray.orig()),
+                           self.inverse_transform_vector(ray.dir()),
+                           ray.depth,
+                           ray.time)
+    }
+
+    /// The factor by which a small area on a surface with
+    /// the normal n in the object's space is scaled.
+    pub fn area_scale(&self, n: &Normal) -> Scalar {
+        self.stretch.determinant().abs() * na::norm(&(self.inverse_stretch.transpose() * *n))
+    }
+
+    /// The bounding box of the transformed points.
+    pub fn point_cloud_aabb(&self, points: &[Point]) -> AABB3<Scalar> {
+        let points: Vec<Point> = points.iter().map(|p| self.transform_point(p)).collect();
+        let (mins, maxs) = bounding_volume::point_cloud_aabb(&Isometry3::identity(), &points[..]);
+        AABB3::new(mins, maxs)
+    }
+
+    /// A bounding box containing the transformed box.
+    pub fn transform_aabb(&self, aabb: &AABB3<Scalar>) -> AABB3<Scalar> {
+        let (mins, maxs) = (aabb.mins(), aabb.maxs());
+        let corners: Vec<Point> = (0..8)
+            .map(|i| {
+                Point::new(if i & 1 == 0 { mins.x } else { maxs.x },
+                           if i & 2 == 0 { mins.y } else { maxs.y },
+                           if i & 4 == 0 { mins.z } else { maxs.z })
+            })
+            .collect();
+        self.point_cloud_aabb(&corners)
+    }
+}
+
+/// Is the stretch the identity up to rounding error?
+fn is_identity(stretch: &Matrix3<Scalar>) -> bool {
+    (*stretch - Matrix3::identity()).norm() < RIGID_TOLERANCE
+}
+
+/// Split a linear map into a rotation followed by a stretch,
+/// such that m = rotation * stretch, returning None if the map
+/// can't be inverted. A reflection is left in the stretch.
+fn polar_decompose(m: &Matrix3<Scalar>) -> Option<(UnitQuaternion<Scalar>, Matrix3<Scalar>)> {
+    // average the matrix with its inverse transpose until
+    // it converges to the nearest orthogonal matrix
+    let mut r = *m;
+    for _ in 0..100 {
+        let next = match r.try_inverse() {
+            Some(inverse) => (r + inverse.transpose()) * 0.5,
+            None => return None,
+        };
+        let converged = (next - r).norm() < 1e-12;
+        r = next;
+        if converged {
+            break;
+        }
+    }
+    if r.determinant() < 0.0 {
+        r = -r;
+    }
+    let stretch = r.transpose() * *m;
+    let rotation = UnitQuaternion::from_rotation_matrix(&Rotation3::from_matrix_unchecked(r));
+    Some((rotation, stretch))
+}
+
+/// A transform that changes over time, given by keyframes at
+/// particular times. Between keyframes the translation and
+/// stretch are interpolated linearly and the rotation
+/// spherically, before the first and after the last keyframe
+/// the transform is held.
+///
+/// Keyframes must either all mirror the object or all not
+/// mirror it, otherwise the object would be flattened between
+/// them and the transform there couldn't be inverted.
+#[derive(Clone, Debug)]
+pub struct AnimatedTransform {
+    keyframes: Vec<(Scalar, Transform)>,
+}
+
+impl AnimatedTransform {
+    /// Create an animated transform from (time, transform) keyframes.
+    pub fn new(mut keyframes: Vec<(Scalar, Transform)>) -> AnimatedTransform {
+        assert!(!keyframes.is_empty(), "An animated transform needs at least one keyframe");
+        let mirrored = keyframes[0].1.is_mirrored();
+        assert!(keyframes.iter().all(|&(_, ref t)| t.is_mirrored() == mirrored),
+                "Keyframes must all mirror the object or all not mirror it");
+        keyframes.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(Ordering::Equal));
+        AnimatedTransform { keyframes: keyframes }
+    }
+
+    /// A transform that doesn't change.
+    pub fn fixed(transform: Transform) -> AnimatedTransform {
+        AnimatedTransform { keyframes: vec![(0.0, transform)] }
+    }
+
+    /// Change the transform at every keyframe, keeping their times.
+    pub fn map<F>(&self, f: F) -> AnimatedTransform
+        where F: Fn(&Transform) -> Transform
+    {
+        AnimatedTransform::new(self.keyframes.iter().map(|&(time, ref t)| (time, f(t))).collect())
+    }
+
+    #[inline]
+    pub fn is_animated(&self) -> bool {
+        self.keyframes.len() > 1
+    }
+
+    /// Is the transform at every keyframe rigid?
+    pub fn is_rigid(&self) -> bool {
+        self.keyframes.iter().all(|&(_, ref t)| t.is_rigid())
+    }
+
+    /// The transform at the first keyframe.
+    #[inline]
+    pub fn start(&self) -> &Transform {
+        &self.keyframes[0].1
+    }
+
+    /// The transform at the given time.
+    pub fn at(&self, time: Scalar) -> Transform {
+        let (first_time, ref first) = self.keyframes[0];
+        if !self.is_animated() || time <= first_time {
+            return *first;
+        }
+        let &(last_time, ref last) = self.keyframes.last().unwrap();
+        if time >= last_time {
+            return *last;
+        }
+
+        // find the keyframes either side of the time
+        let next = self.keyframes.iter().position(|&(t, _)| t > time).unwrap();
+        let (t0, ref a) = self.keyframes[next - 1];
+        let (t1, ref b) = self.keyframes[next];
+        interpolate(a, b, (time - t0) / (t1 - t0))
+    }
+
+    /// Find a bounding box that contains an object over its whole
+    /// motion, given a function bounding it under a single transform.
+    ///
+    /// The object is bounded at several times between each pair of
+    /// keyframes and the boxes are padded by the furthest the object
+    /// can stray from a straight line between two of those times as
+    /// it turns, so the box contains the object at every time.
+    pub fn motion_aabb<F>(&self, aabb: F) -> AABB3<Scalar>
+        where F: Fn(&Transform) -> AABB3<Scalar>
+    {
+        let mut bounds = aabb(self.start());
+        for pair in self.keyframes.windows(2) {
+            let (_, ref a) = pair[0];
+            let (_, ref b) = pair[1];
+            let steps = MOTION_BOUND_STEPS as Scalar;
+
+            // the angle turned through and the change in the
+            // stretched object relative to its size between steps
+            let cos_half_angle = na::dot(&a.isometry.rotation.quaternion().coords,
+                                         &b.isometry.rotation.quaternion().coords);
+            let angle = 2.0 * na::clamp(cos_half_angle.abs(), 0.0, 1.0).acos() / steps;
+            let stretching = ((b.stretch - a.stretch) * a.inverse_stretch).norm() / steps;
+
+            let mut pair_bounds = aabb(a);
+            let mut radius = furthest_corner(&pair_bounds, &a.translation());
+            for step in 1..MOTION_BOUND_STEPS + 1 {
+                let transform = interpolate(a, b, step as Scalar / steps);
+                let step_bounds = aabb(&transform);
+                radius = radius.max(furthest_corner(&step_bounds, &transform.translation()));
+                pair_bounds.merge(&step_bounds);
+            }
+
+            // between steps a point at distance r from the centre of
+            // rotation moves along a path whose second derivative is
+            // at most r * (angle^2 + 2 * angle * stretching), which
+            // keeps it within an eighth of that of the straight line
+            // joining where it is at each step
+            let pad = radius * (angle * angle + 2.0 * angle * stretching) / 8.0;
+            bounds.merge(&pair_bounds.loosened(pad));
+        }
+        bounds
+    }
+}
+
+/// The distance from a point to the
+/// furthest corner of a bounding box.
+fn furthest_corner(aabb: &AABB3<Scalar>, p: &Vector) -> Scalar {
+    let (mins, maxs) = (aabb.mins(), aabb.maxs());
+    let offset = Vector::new((mins.x - p.x).abs().max((maxs.x - p.x).abs()),
+                             (mins.y - p.y).abs().max((maxs.y - p.y).abs()),
+                             (mins.z - p.z).abs().max((maxs.z - p.z).abs()));
+    na::norm(&offset)
+}
+
+/// Interpolate between two transforms, lerping the
+/// translation and stretch and slerping the rotation.
+fn interpolate(a: &Transform, b: &Transform, t: Scalar) -> Transform {
+    let translation = a.translation() * (1.0 - t) + b.translation() * t;
+    let rotation = slerp(&a.isometry.rotation, &b.isometry.rotation, t);
+    let isometry = Isometry3::from_parts(Translation3::from_vector(translation), rotation);
+    if a.is_rigid() && b.is_rigid() {
+        return Transform::from_isometry(isometry);
+    }
+    let stretch = a.stretch * (1.0 - t) + b.stretch * t;
+    Transform::new(isometry, stretch).expect("Interpolated transform can't be inverted")
+}
+
+/// Spherical linear interpolation between two rotations
+/// taking the shortest path between them.
+fn slerp(a: &UnitQuaternion<Scalar>,
+         b: &UnitQuaternion<Scalar>,
+         t: Scalar)
+         -> UnitQuaternion<Scalar> {
+    let qa = a.quaternion().coords;
+    let mut qb = b.quaternion().coords;
+    let mut cos_theta = na::dot(&qa, &qb);
+    // q and -q are the same rotation so
+    // go the short way round the sphere
+    if cos_theta < 0.0 {
+        qb = -qb;
+        cos_theta = -cos_theta;
+    }
+
+    let q = if cos_theta > 0.9995 {
+        // the rotations are so close that
+        // linear interpolation is accurate
+        qa * (1.0 - t) + qb * t
+    } else {
+        let theta = cos_theta.acos();
+        let sin_theta = theta.sin();
+        qa * (((1.0 - t) * theta).sin() / sin_theta) + qb * ((t * theta).sin() / sin_theta)
+    };
+    UnitQuaternion::new_normalize(Quaternion::from_vector(q))
+}
+
+#[test]
+fn test_interpolate_keyframes() {
+    use na::Vector3;
+    use std::f64::consts;
+
+    let turn = Isometry3::new(Vector3::new(2.0, 0.0, 0.0), Vector3::new(0.0, consts::PI, 0.0));
+    let transform = AnimatedTransform::new(vec![
+        (1.0, Transform::from_isometry(turn)),
+        (0.0, Transform::from_isometry(Isometry3::identity())),
+    ]);
+    let half = transform.at(0.5);
+    assert_approx_eq!(half.translation().x, 1.0);
+    // half way through a half turn about y is a quarter turn
+    let x = half.isometry.rotation * Vector3::new(1.0, 0.0, 0.0);
+    assert_approx_eq!(x.x, 0.0);
+    assert_approx_eq!(x.z.abs(), 1.0);
+    // the transform is held outside the keyframes
+    assert_approx_eq!(transform.at(-1.0).translation().x, 0.0);
+    assert_approx_eq!(transform.at(2.0).translation().x, 2.0);
+}
+
+#[test]
+fn test_affine_transform() {
+    use std::f64::consts;
+
+    // scale x by 2 then turn a quarter about z
+    let linear = Matrix3::new(0.0, -1.0, 0.0, 2.0, 0.0, 0.0, 0.0, 0.0, 1.0);
+    let transform = Transform::from_linear(Vector::new(0.0, 0.0, 1.0), &linear).unwrap();
+    let angle = transform.isometry.rotation.angle();
+    assert_approx_eq!(angle, consts::FRAC_PI_2);
+
+    let p = transform.transform_point(&Point::new(1.0, 1.0, 0.0));
+    assert_approx_eq!(p.x, -1.0);
+    assert_approx_eq!(p.y, 2.0);
+    assert_approx_eq!(p.z, 1.0);
+    let q = transform.inverse_transform_point(&p);
+    assert_approx_eq!(q.x, 1.0);
+    assert_approx_eq!(q.y, 1.0);
+
+    // the normal of the plane x + y = 1 must stay
+    // perpendicular to the transformed plane
+    let n = transform.transform_normal(&Vector::new(1.0, 1.0, 0.0));
+    let along = transform.transform_vector(&Vector::new(1.0, -1.0, 0.0));
+    assert_approx_eq!(na::dot(&n, &along), 0.0);
+    assert_approx_eq!(na::norm(&n), 1.0);
+}
+
+#[test]
+fn test_motion_aabb_contains_turning_point() {
+    use na::Vector3;
+    use std::f64::consts;
+
+    // turn so that the point reaches its furthest along z
+    // half way between two of the sampled times, which
+    // bounding the samples alone would miss
+    let angle = consts::FRAC_PI_2 * MOTION_BOUND_STEPS as Scalar /
+                (MOTION_BOUND_STEPS as Scalar / 2.0 + 0.5);
+    let turn = Isometry3::new(Vector3::new(0.0, 0.0, 0.0), Vector3::new(0.0, angle, 0.0));
+    let transform = AnimatedTransform::new(vec![
+        (0.0, Transform::from_isometry(Isometry3::identity())),
+        (1.0, Transform::from_isometry(turn)),
+    ]);
+    let p = Point::new(1.0, 0.0, 0.0);
+    let bounds = transform.motion_aabb(|t| t.point_cloud_aabb(&[p]));
+    for i in 0..1001 {
+        let q = transform.at(i as Scalar / 1000.0).transform_point(&p);
+        for axis in 0..3 {
+            assert!(q[axis] >= bounds.mins()[axis] && q[axis] <= bounds.maxs()[axis]);
+        }
+    }
+}
+
+#[test]
+fn test_rotation_matrix_is_rigid() {
+    use std::f64::consts;
+
+    // a turn about z given as a matrix only comes
+    // back out of the decomposition up to rounding error
+    let (s, c) = (consts::FRAC_PI_3.sin(), consts::FRAC_PI_3.cos());
+    let linear = Matrix3::new(c, -s, 0.0, s, c, 0.0, 0.0, 0.0, 1.0);
+    let transform = Transform::from_linear(na::zero(), &linear).unwrap();
+    assert!(transform.is_rigid());
+    assert!(!transform.is_mirrored());
+
+    let mirror = Matrix3::new(-1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0);
+    let transform = Transform::from_linear(na::zero(), &(linear * mirror)).unwrap();
+    assert!(!transform.is_rigid());
+    assert!(transform.is_mirrored());
+}